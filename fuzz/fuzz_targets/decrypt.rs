@@ -0,0 +1,44 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use lib_sig::crypto::{KdfMode, KeyPair, State};
+use lib_sig::message::{EncryptedMessage, Message};
+use libfuzzer_sys::fuzz_target;
+
+// bob's side of a session and a message alice sent on it
+fn setup() -> &'static (State, String) {
+    static SETUP: OnceLock<(State, String)> = OnceLock::new();
+    SETUP.get_or_init(|| {
+        let (alice, bob) = (KeyPair::new(), KeyPair::new());
+        let state = State::new(alice.clone(), bob.public()).with_kdf(KdfMode::Spec);
+        let msg = Message::new(
+            "hi".to_owned(),
+            ("alice".to_owned(), 0),
+            ("bob".to_owned(), 0),
+            alice.public(),
+        );
+        let (encrypted, _) = msg.encrypt(&state).unwrap();
+        let line = serde_json::to_string(&encrypted).unwrap();
+        (State::new(bob, alice.public()).with_kdf(KdfMode::Spec), line)
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (state, line) = setup();
+
+    // whatever an attacker can put on the wire
+    if let Ok(msg) = serde_json::from_slice::<EncryptedMessage>(data) {
+        let _ = msg.decrypt(state);
+    }
+
+    // the genuine message with a forged header and ciphertext
+    if data.len() < 8 {
+        return;
+    }
+    let mut msg: EncryptedMessage = serde_json::from_str(line).unwrap();
+    msg.count = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    msg.previous_count = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    msg.encrypted_msg = data[8..].to_vec();
+    let _ = msg.decrypt(state);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use lib_sig::message::Msg;
+use libfuzzer_sys::fuzz_target;
+
+// every line the client and the server read is parsed like this
+fuzz_target!(|data: &[u8]| {
+    let line = match std::str::from_utf8(data) {
+        Ok(line) => line,
+        Err(_) => return,
+    };
+    if let Ok(msg) = serde_json::from_str::<Msg>(line) {
+        // the relay passes on what it parsed, the recipient has to read it just the same
+        let (_, msg) = msg.into_request();
+        let forwarded = serde_json::to_string(&msg).unwrap();
+        serde_json::from_str::<Msg>(&forwarded).unwrap();
+    }
+});
@@ -0,0 +1,132 @@
+//! Commands of the admin socket. Each line is one command, the server answers with any output
+//! followed by a line that is either `ok` or `error: <reason>`.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::message::DeviceId;
+
+pub const HELP: &str = "\
+list                      connected devices and their addresses
+kick <user> [<device>]    disconnects every device of a user, or only one
+ban <user>|<ip>           disconnects and refuses a user or an address
+unban <user>|<ip>
+bans                      banned users and addresses
+broadcast <text>          sends a notice to everyone connected
+motd [<text>]             shows or replaces the welcome message
+help";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl Target {
+    // anything that is not an address is a user name
+    fn parse(s: &str) -> Self {
+        match s.parse() {
+            Ok(ip) => Target::Ip(ip),
+            Err(_) => Target::User(s.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::User(user) => f.write_str(user),
+            Target::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    List,
+    Kick(String, Option<DeviceId>),
+    Ban(Target),
+    Unban(Target),
+    Bans,
+    Broadcast(String),
+    Motd(Option<String>),
+    Help,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        let args = rest.split_whitespace().collect::<Vec<_>>();
+        let command = match (name, args.as_slice()) {
+            ("list", []) => Command::List,
+            ("kick", [user]) => Command::Kick(user.to_string(), None),
+            ("kick", [user, device]) => {
+                let device = device
+                    .parse()
+                    .map_err(|_| format!("invalid device: {}", device))?;
+                Command::Kick(user.to_string(), Some(device))
+            }
+            ("ban", [target]) => Command::Ban(Target::parse(target)),
+            ("unban", [target]) => Command::Unban(Target::parse(target)),
+            ("bans", []) => Command::Bans,
+            ("broadcast", [_, ..]) => Command::Broadcast(rest.to_owned()),
+            ("motd", []) => Command::Motd(None),
+            ("motd", [_, ..]) => Command::Motd(Some(rest.to_owned())),
+            ("help", []) => Command::Help,
+            ("list" | "kick" | "ban" | "unban" | "bans" | "broadcast" | "help", _) => {
+                return Err(format!("wrong arguments for {}, see help", name))
+            }
+            _ => return Err(format!("unknown command: {}", name)),
+        };
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Command::parse("list"), Ok(Command::List));
+        assert_eq!(
+            Command::parse(" kick alice 2 "),
+            Ok(Command::Kick("alice".to_owned(), Some(2)))
+        );
+        assert_eq!(
+            Command::parse("ban 10.0.0.1"),
+            Ok(Command::Ban(Target::Ip("10.0.0.1".parse().unwrap())))
+        );
+        assert_eq!(
+            Command::parse("unban ::1"),
+            Ok(Command::Unban(Target::Ip("::1".parse().unwrap())))
+        );
+        assert_eq!(
+            Command::parse("ban alice"),
+            Ok(Command::Ban(Target::User("alice".to_owned())))
+        );
+        assert_eq!(
+            Command::parse("broadcast back  in 5 minutes"),
+            Ok(Command::Broadcast("back  in 5 minutes".to_owned()))
+        );
+        assert_eq!(Command::parse("motd"), Ok(Command::Motd(None)));
+        assert_eq!(
+            Command::parse("motd hello there"),
+            Ok(Command::Motd(Some("hello there".to_owned())))
+        );
+
+        for line in [
+            "",
+            "reboot",
+            "kick",
+            "kick alice phone",
+            "ban",
+            "broadcast",
+            "list all",
+        ] {
+            assert!(Command::parse(line).is_err(), "{}", line);
+        }
+    }
+}
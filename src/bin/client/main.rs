@@ -0,0 +1,1409 @@
+use clap::Parser;
+use lib_sig::config::{self, ClientConfig, ClientOptions};
+use lib_sig::connection::{Connection, Outgoing};
+use lib_sig::crypto::{
+    access_key_hash, pqxdh_initiate, pqxdh_respond, x3dh_initiate, x3dh_respond, KeyPair,
+    SigningKeyPair,
+};
+use lib_sig::message::{
+    AccessKey, Challenge, Device, DeviceId, EncryptedMessage, PreKeyBundle, PubKey, SealedMessage,
+    SenderCertificate, ServerInfo, SessionReset, UserQuery,
+};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use x25519_dalek::PublicKey;
+
+use lib_sig::contacts::{Contacts, Standing};
+use lib_sig::crypto::MlKemKeyPair;
+use lib_sig::crypto::{KdfMode, State, Suite};
+use lib_sig::history::{Entry, History};
+use lib_sig::message::{
+    unix_time, Message, Msg, NackReason, RegisterMessage, ERR_DECRYPT, ERR_DUPLICATE, ERR_SUITE,
+};
+use lib_sig::outbox::{self, Outbox, Status};
+use lib_sig::padding::Padding;
+use lib_sig::session::Session;
+use lib_sig::vault::Vault;
+
+mod tui;
+
+const HISTORY_FILE: &str = "history";
+const IDENTITY_FILE: &str = "identity";
+const ACCESS_KEY_FILE: &str = "access_key";
+const OUTBOX_FILE: &str = "outbox";
+const CONTACTS_FILE: &str = "contacts";
+const SIGNING_KEYS_FILE: &str = "signing_keys";
+
+// how often the announced key and signed prekey are replaced
+const KEY_ROTATION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// every session this client starts follows the Double Ratchet specification
+const KDF_MODE: KdfMode = KdfMode::Spec;
+
+// failed decryptions in a row after which the session is started again
+const RESET_AFTER_FAILURES: u32 = 3;
+
+// users shown per page of `!list`
+const LIST_PAGE: usize = 20;
+
+// keys this device announces to the server
+struct OwnKeys {
+    identity: SigningKeyPair,
+    // of the sessions this device starts
+    suite: Suite,
+    key: KeyPair,
+    prekey: KeyPair,
+    pq_prekey: MlKemKeyPair,
+    prekey_id: u32,
+}
+
+impl OwnKeys {
+    fn new(identity: SigningKeyPair, suite: Suite) -> Self {
+        OwnKeys {
+            identity,
+            suite,
+            key: KeyPair::new(),
+            prekey: KeyPair::new(),
+            pq_prekey: MlKemKeyPair::new(),
+            prekey_id: 0,
+        }
+    }
+
+    fn bundle(&self, username: &str, device: DeviceId) -> PreKeyBundle {
+        PreKeyBundle::new(
+            username.to_owned(),
+            device,
+            self.key.public(),
+            self.prekey.public(),
+            self.prekey_id,
+        )
+        .with_pq_prekey(self.pq_prekey.public())
+        .with_suite(self.suite)
+        .signed(&self.identity)
+    }
+
+    // replaces the announced key and signed prekeys, every session is restarted with the new key.
+    // Returns the devices whose prekeys are needed to do so.
+    fn rotate(
+        &mut self,
+        local: (&str, DeviceId),
+        keys: &HashMap<(String, DeviceId), PublicKey>,
+        suites: &HashMap<(String, DeviceId), Suite>,
+        states: &mut HashMap<(String, DeviceId), Session>,
+    ) -> Vec<(String, DeviceId)> {
+        self.key = KeyPair::new();
+        self.prekey = KeyPair::new();
+        self.pq_prekey = MlKemKeyPair::new();
+        self.prekey_id += 1;
+
+        let mut prekeys = Vec::new();
+        for (id, key) in keys.iter() {
+            let suite = suites.get(id).copied().unwrap_or(self.suite);
+            if suite.post_quantum() {
+                states.remove(id);
+                if starts_sessions(local, id) {
+                    prekeys.push(id.clone());
+                }
+                continue;
+            }
+            let state = State::new(self.key.clone(), *key)
+                .with_kdf(KDF_MODE)
+                .with_suite(suite);
+            states.insert(id.clone(), Session::new(state, local, (&id.0, id.1)));
+        }
+        prekeys
+    }
+}
+
+// post-quantum sessions are only set up from prekeys, by the side that would win a tie
+fn starts_sessions(local: (&str, DeviceId), remote: &(String, DeviceId)) -> bool {
+    local < (remote.0.as_str(), remote.1)
+}
+
+// the same side picks the cipher suite of a session, so both use the one it announced
+fn session_suite(
+    local: (&str, DeviceId),
+    remote: &(String, DeviceId),
+    ours: Suite,
+    theirs: Suite,
+) -> Suite {
+    if starts_sessions(local, remote) {
+        ours
+    } else {
+        theirs
+    }
+}
+
+// hides who sent a message from the server once the recipient handed out its access key
+struct SealedSender {
+    enabled: bool,
+    // our own access key, sent along with every message
+    access_key: [u8; 32],
+    certificate: Option<SenderCertificate>,
+    access_keys: HashMap<(String, DeviceId), [u8; 32]>,
+}
+
+impl SealedSender {
+    // copies to our own devices are never sealed, the server knows who they are from anyway
+    fn wrap(&self, msg: EncryptedMessage, keys: &HashMap<(String, DeviceId), PublicKey>) -> Msg {
+        let id = (msg.recv_name.clone(), msg.recv_device);
+        match (&self.certificate, self.access_keys.get(&id), keys.get(&id)) {
+            (Some(certificate), Some(token), Some(key))
+                if self.enabled && certificate.user != msg.recv_name =>
+            {
+                Msg::SealedMessage(msg.seal(certificate, key, *token))
+            }
+            _ => Msg::EncryptedMessage(msg),
+        }
+    }
+
+    // the certificate inside has to come from our server and match the key announced for the sender
+    fn unwrap(
+        &self,
+        sealed: &SealedMessage,
+        identity: &KeyPair,
+        keys: &HashMap<(String, DeviceId), PublicKey>,
+    ) -> Option<EncryptedMessage> {
+        let server_key = match &self.certificate {
+            Some(certificate) => certificate.server_key,
+            None => {
+                tracing::error!("received a sealed message before the server issued a certificate");
+                return None;
+            }
+        };
+        let (certificate, msg) = match sealed.unseal(identity, &server_key) {
+            Ok(unsealed) => unsealed,
+            Err(e) => {
+                tracing::error!("failed to unseal message; error = {}", e);
+                return None;
+            }
+        };
+        let id = (certificate.user.clone(), certificate.device_id);
+        if keys.get(&id) != Some(&certificate.identity_key) {
+            tracing::error!(
+                "sealed message from {} (device {}) has a certificate for another key",
+                id.0,
+                id.1
+            );
+            return None;
+        }
+        Some(msg)
+    }
+}
+
+// encrypts `msg` with the session, it stays in the outbox until the server acknowledges it.
+fn encrypt(
+    conn: &mut Connection<String>,
+    outbox: &mut Outbox,
+    session: &mut Session,
+    mut msg: Message,
+    padding: Padding,
+    sealed_sender: &SealedSender,
+    keys: &HashMap<(String, DeviceId), PublicKey>,
+) -> Outgoing {
+    session.set_padding(padding);
+    msg.public_key = session.state().key_pair().public();
+    let encrypted = session.encrypt(&msg).unwrap();
+    let request = conn.request(sealed_sender.wrap(encrypted, keys));
+    outbox.push(outbox::Entry::new(
+        request.id,
+        msg,
+        padding,
+        request.line.clone(),
+    ));
+    request
+}
+
+// messages that waited for the session with a device, encrypted now that it is set up
+fn encrypt_waiting(
+    waiting: &mut HashMap<(String, DeviceId), Vec<(Message, Padding)>>,
+    id: &(String, DeviceId),
+    session: &mut Session,
+    conn: &mut Connection<String>,
+    outbox: &mut Outbox,
+    sealed_sender: &SealedSender,
+    keys: &HashMap<(String, DeviceId), PublicKey>,
+) -> Vec<Outgoing> {
+    waiting
+        .remove(id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(msg, padding)| encrypt(conn, outbox, session, msg, padding, sealed_sender, keys))
+        .collect()
+}
+
+// what every connection starts with: registration, our keys and the hash of our access key.
+// Prekeys requested on a connection that was lost are requested again.
+fn handshake(
+    username: &str,
+    device: DeviceId,
+    own: &OwnKeys,
+    sealed_sender: &SealedSender,
+    pending_resets: &HashSet<(String, DeviceId)>,
+    challenge: &Challenge,
+) -> Vec<Msg> {
+    let mut pubkey =
+        PubKey::new(username.to_owned(), device, own.key.public()).signed(&own.identity);
+    pubkey.suite = own.suite;
+    let register =
+        RegisterMessage::new(username.to_owned(), device).signed(challenge, &own.identity);
+
+    let mut msgs = vec![
+        Msg::Register(register),
+        Msg::PubKey(pubkey),
+        Msg::PreKeyBundle(own.bundle(username, device)),
+        Msg::AccessKey(AccessKey::new(access_key_hash(&sealed_sender.access_key))),
+        Msg::ServerInfoRequest,
+    ];
+    for id in pending_resets {
+        msgs.push(Msg::PreKeyRequest(Device::new(id.0.clone(), id.1)));
+    }
+    msgs
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// the signing key of a device as `hex` prints it
+fn parse_signing_key(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, x) in key.iter_mut().enumerate() {
+        *x = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+fn chat_lines(history: &History, conversation: &str) -> Vec<tui::ChatLine> {
+    history
+        .conversation(conversation)
+        .iter()
+        .map(|x| tui::ChatLine {
+            from: x.from.clone(),
+            text: x.msg.clone(),
+            timestamp: x.timestamp,
+        })
+        .collect()
+}
+
+// every other user with what is known about the sessions with their devices
+fn roster(
+    local: &str,
+    keys: &HashMap<(String, DeviceId), PublicKey>,
+    states: &HashMap<(String, DeviceId), Session>,
+    waiting: &HashMap<(String, DeviceId), Vec<(Message, Padding)>>,
+    pending_resets: &HashSet<(String, DeviceId)>,
+    online: &HashSet<(String, DeviceId)>,
+    sealed_sender: &SealedSender,
+) -> Vec<tui::Contact> {
+    let mut ids = keys
+        .keys()
+        .filter(|(user, _)| user != local)
+        .collect::<Vec<_>>();
+    ids.sort();
+
+    let mut contacts: Vec<tui::Contact> = Vec::new();
+    for id in ids {
+        let session = if states.contains_key(id) {
+            tui::SessionState::Established
+        } else if waiting.contains_key(id) || pending_resets.contains(id) {
+            tui::SessionState::Pending
+        } else {
+            tui::SessionState::None
+        };
+        let device = tui::DeviceState {
+            device: id.1,
+            online: online.contains(id),
+            session,
+            sealed: sealed_sender.enabled
+                && sealed_sender.certificate.is_some()
+                && sealed_sender.access_keys.contains_key(id),
+            fingerprint: tui::fingerprint(&keys[id]),
+        };
+        match contacts.last_mut() {
+            Some(contact) if contact.user == id.0 => contact.devices.push(device),
+            _ => contacts.push(tui::Contact {
+                user: id.0.clone(),
+                devices: vec![device],
+            }),
+        }
+    }
+    contacts
+}
+
+// the user a key request with `id` was for and the lines waiting for their keys
+fn take_lookup(
+    lookups: &mut HashMap<String, (u64, Vec<String>)>,
+    id: u64,
+) -> Option<(String, Vec<String>)> {
+    let user = lookups.iter().find(|(_, x)| x.0 == id)?.0.clone();
+    lookups.remove(&user).map(|(_, lines)| (user, lines))
+}
+
+fn save_history(vault: Option<&Vault>, history: &History) {
+    if let Some(vault) = vault {
+        if let Err(e) = vault.save(HISTORY_FILE, history) {
+            tracing::error!("failed to save message history; error = {}", e);
+        }
+    }
+}
+
+fn save_outbox(vault: Option<&Vault>, outbox: &Outbox) {
+    if let Some(vault) = vault {
+        if let Err(e) = vault.save(OUTBOX_FILE, outbox) {
+            tracing::error!("failed to save outbox; error = {}", e);
+        }
+    }
+}
+
+// kept as a list, JSON has no maps keyed by anything but strings
+fn save_signing_keys(vault: Option<&Vault>, signing_keys: &HashMap<(String, DeviceId), [u8; 32]>) {
+    if let Some(vault) = vault {
+        let list = signing_keys.iter().collect::<Vec<_>>();
+        if let Err(e) = vault.save(SIGNING_KEYS_FILE, &list) {
+            tracing::error!("failed to save signing keys; error = {}", e);
+        }
+    }
+}
+
+fn save_contacts(vault: Option<&Vault>, contacts: &Contacts) {
+    if let Some(vault) = vault {
+        if let Err(e) = vault.save(CONTACTS_FILE, contacts) {
+            tracing::error!("failed to save contacts; error = {}", e);
+        }
+    }
+}
+
+// formats unix time as `YYYY-MM-DD HH:MM:SS` UTC
+fn format_time(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86400, timestamp % 86400);
+
+    // days since epoch to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Client for end-to-end encrypted messaging through the relay.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// TOML file with any of the options below, the command line takes precedence
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    options: ClientOptions,
+}
+
+fn load_config() -> config::Result<ClientConfig> {
+    let cli = Cli::parse();
+    let options = match &cli.config {
+        Some(path) => cli.options.or(config::load(path)?),
+        None => cli.options,
+    };
+    options.validate()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+    };
+
+    // the terminal interface takes over the screen, line mode stays the default for scripting
+    let mut ui = config.tui.then(tui::Ui::new);
+    match &ui {
+        Some(ui) => config.log_format.init(ui.log_writer(), false),
+        None => config.log_format.init(io::stdout, true),
+    }
+
+    let username = config.username;
+    let my_device = config.device;
+    // of the sessions this device starts, the other side announces the one it starts with
+    let suite = config.suite;
+    let padding = config.padding;
+
+    // message history is only kept in memory unless a data directory is supplied
+    let vault = match &config.data_dir {
+        Some(dir) => {
+            let passphrase = match env::var("LIB_SIG_PASSPHRASE") {
+                Ok(passphrase) => passphrase,
+                Err(_) => rpassword::prompt_password("passphrase: ")?,
+            };
+            Some(Vault::open(dir, &passphrase)?)
+        }
+        None => None,
+    };
+
+    // the signing key outlives the process if there is somewhere to keep it
+    let identity = match &vault {
+        Some(vault) => match vault.load(IDENTITY_FILE)? {
+            Some(identity) => identity,
+            None => {
+                let identity = SigningKeyPair::new();
+                vault.save(IDENTITY_FILE, &identity)?;
+                identity
+            }
+        },
+        None => SigningKeyPair::new(),
+    };
+    let mut own = OwnKeys::new(identity, suite);
+
+    // peers keep using the access key we handed out, so it is kept with the identity
+    let access_key = match &vault {
+        Some(vault) => match vault.load(ACCESS_KEY_FILE)? {
+            Some(access_key) => access_key,
+            None => {
+                let access_key: [u8; 32] = rand::random();
+                vault.save(ACCESS_KEY_FILE, &access_key)?;
+                access_key
+            }
+        },
+        None => rand::random(),
+    };
+    let mut sealed_sender = SealedSender {
+        // the sender of messages to other users is hidden from the server unless this is off
+        enabled: config.sealed_sender,
+        access_key,
+        certificate: None,
+        access_keys: HashMap::new(),
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    // messages to users that had to be looked up are read again once their keys arrived
+    let input = tx.clone();
+
+    // separate thread for getting input from stdio
+    // sends it through channel to main thread that asynchronously processes it,
+    // encrypts it and serialized sends it to server
+    match &mut ui {
+        Some(ui) => ui.start(username.clone(), tx)?,
+        None => {
+            // stops at the end of input, the client keeps receiving messages
+            thread::spawn(move || loop {
+                let mut buf = String::new();
+                if io::stdin().read_line(&mut buf).unwrap() == 0 {
+                    break;
+                }
+                tx.send(buf).unwrap();
+            });
+        }
+    }
+
+    // every device of every user has its own key and session
+    let mut keys: HashMap<(String, DeviceId), PublicKey> = HashMap::new();
+    // cipher suite of the session with each device, agreed on when it is set up
+    let mut suites: HashMap<(String, DeviceId), Suite> = HashMap::new();
+    // what the keys and prekeys of each device have to be signed with, a device never gets
+    // another one
+    let mut signing_keys: HashMap<(String, DeviceId), [u8; 32]> = match &vault {
+        Some(vault) => vault
+            .load::<Vec<((String, DeviceId), [u8; 32])>>(SIGNING_KEYS_FILE)?
+            .unwrap_or_default()
+            .into_iter()
+            .collect(),
+        None => HashMap::new(),
+    };
+    let mut states: HashMap<(String, DeviceId), Session> = HashMap::new();
+
+    let mut history = match &vault {
+        Some(vault) => vault.load(HISTORY_FILE)?.unwrap_or_default(),
+        None => History::new(),
+    };
+    if let Some(ui) = &ui {
+        for conversation in history.conversations() {
+            ui.conversation(conversation, chat_lines(&history, conversation));
+        }
+    }
+    let mut contacts = match &vault {
+        Some(vault) => vault.load(CONTACTS_FILE)?.unwrap_or_default(),
+        None => Contacts::new(),
+    };
+    // devices the server reported as connected
+    let mut online: HashSet<(String, DeviceId)> = HashSet::new();
+    // disappearing message timers in seconds, per conversation
+    let mut timers: HashMap<String, u64> = HashMap::new();
+    // padding of messages to a conversation if it differs from `padding`
+    let mut paddings: HashMap<String, Padding> = HashMap::new();
+
+    // decryption failures per device, and devices whose prekeys were requested to reset the session
+    let mut failures: HashMap<(String, DeviceId), u32> = HashMap::new();
+    let mut pending_resets: HashSet<(String, DeviceId)> = HashSet::new();
+    // devices a session reset was sent to, until they are heard from on the session it started
+    let mut sent_resets: HashSet<(String, DeviceId)> = HashSet::new();
+    // messages to devices without a post-quantum session yet, sent once it is set up
+    let mut waiting: HashMap<(String, DeviceId), Vec<(Message, Padding)>> = HashMap::new();
+    // users whose keys were requested, with the id of the request and the lines written to them
+    let mut lookups: HashMap<String, (u64, Vec<String>)> = HashMap::new();
+
+    // messages the server did not acknowledge before the last exit. Their sessions are gone,
+    // so they are encrypted again once there are new ones.
+    let mut outbox: Outbox = match &vault {
+        Some(vault) => vault.load(OUTBOX_FILE)?.unwrap_or_default(),
+        None => Outbox::new(),
+    };
+    let unsent = outbox.take();
+    if !unsent.is_empty() {
+        tracing::info!(
+            "{} messages from the last run are sent once there are sessions for them",
+            unsent.len()
+        );
+    }
+    // some may have reached the recipient already, their ids let it drop the copies
+    for (message, padding) in unsent {
+        let id = (message.recv_name.clone(), message.recv_device);
+        waiting.entry(id).or_default().push((message, padding));
+    }
+    let mut expiry = tokio::time::interval(Duration::from_secs(1));
+    let mut rotation = tokio::time::interval_at(
+        tokio::time::Instant::now() + KEY_ROTATION_INTERVAL,
+        KEY_ROTATION_INTERVAL,
+    );
+
+    let mut conn = Connection::new(config.addr, config.max_line_length);
+    conn.connect(|challenge| {
+        handshake(
+            &username,
+            my_device,
+            &own,
+            &sealed_sender,
+            &pending_resets,
+            challenge,
+        )
+    })
+    .await?;
+    // when to send the messages the server rate limited again
+    let mut resend_at: Option<Instant> = None;
+    let mut server_info: Option<ServerInfo> = None;
+
+    let refused = loop {
+        if let Some(ui) = &ui {
+            let mut users = roster(
+                &username,
+                &keys,
+                &states,
+                &waiting,
+                &pending_resets,
+                &online,
+                &sealed_sender,
+            );
+            users.retain(|x| !contacts.is_blocked(&x.user));
+            ui.roster(users);
+            ui.connection(conn.is_connected(), outbox.len());
+        }
+        let (connected, retry_at) = (conn.is_connected(), conn.retry_at());
+
+        tokio::select! {
+        Some(msg) = rx.recv() => {
+            if msg.starts_with('!') {
+                let mut args = msg.split_whitespace();
+                let cmd = args.next().unwrap_or("");
+                if cmd == "!help" {
+                    tracing::info!("to message someone type: username>message");
+                    tracing::info!("!list [prefix] [page], !devices, !link <device> <signing key>, !unlink <device>, !timer <user> <seconds|off>");
+                    tracing::info!("!history <user> [n], !search <text>, !rotate, !reset <user>");
+                    tracing::info!("!padding <user> <none|buckets|padme|random>, !outbox");
+                    tracing::info!("!contacts, !accept <user>, !block <user>, !unblock <user>");
+                }
+                else if cmd == "!list" {
+                    // pages count from 1, anything else is taken as the start of names
+                    let (mut prefix, mut page) = (None, 1);
+                    for arg in args {
+                        match arg.parse::<usize>() {
+                            Ok(n) if n > 0 => page = n,
+                            _ => prefix = Some(arg.to_owned()),
+                        }
+                    }
+                    let query = UserQuery::new(prefix, (page - 1) * LIST_PAGE, Some(LIST_PAGE));
+                    conn.send(Msg::ListUsers(query)).await;
+                }
+                else if cmd == "!devices" {
+                    let mut devices = keys.keys().filter(|(user, _)| *user == username).map(|(_, d)| d.to_string()).collect::<Vec<_>>();
+                    devices.sort();
+                    tracing::info!("this device: {}, other devices: {}", my_device, devices.join(", "));
+                }
+                else if cmd == "!reset" {
+                    let peer = match args.next() {
+                        Some(peer) => peer,
+                        None => {
+                            tracing::info!("usage: !reset <user>");
+                            continue;
+                        }
+                    };
+                    let devices = keys.keys().filter(|(user, _)| user == peer).cloned().collect::<Vec<_>>();
+                    if devices.is_empty() {
+                        tracing::info!("the user you tried to reset does not exist: {}", peer);
+                    }
+                    for id in devices {
+                        let msg = Msg::PreKeyRequest(Device::new(id.0.clone(), id.1));
+                        conn.send(msg).await;
+                        pending_resets.insert(id);
+                    }
+                }
+                else if cmd == "!rotate" {
+                    for id in own.rotate((&username, my_device), &keys, &suites, &mut states) {
+                        let msg = Msg::PreKeyRequest(Device::new(id.0.clone(), id.1));
+                        conn.send(msg).await;
+                        pending_resets.insert(id);
+                    }
+                    let msg = Msg::RotateKey(own.bundle(&username, my_device));
+                    conn.send(msg).await;
+                    tracing::info!("rotated keys, sessions restart with the new key");
+                }
+                else if cmd == "!history" {
+                    let peer = match args.next() {
+                        Some(peer) => peer,
+                        None => {
+                            tracing::info!("usage: !history <user> [n]");
+                            continue;
+                        }
+                    };
+                    let n = args.next().and_then(|x| x.parse().ok()).unwrap_or(20);
+                    for entry in history.last(peer, n) {
+                        tracing::info!("[{}] {}: {}", format_time(entry.timestamp), entry.from, entry.msg);
+                    }
+                }
+                else if cmd == "!search" {
+                    let text = msg.trim()["!search".len()..].trim();
+                    if text.is_empty() {
+                        tracing::info!("usage: !search <text>");
+                        continue;
+                    }
+                    for (conversation, entry) in history.search(text) {
+                        tracing::info!("[{}] ({}) {}: {}", format_time(entry.timestamp), conversation, entry.from, entry.msg);
+                    }
+                }
+                else if cmd == "!timer" {
+                    let (peer, timer) = match (args.next(), args.next()) {
+                        (Some(peer), Some("off")) => (peer, None),
+                        (Some(peer), Some(secs)) => match secs.parse::<u64>() {
+                            Ok(secs) if secs > 0 => (peer, Some(secs)),
+                            _ => {
+                                tracing::info!("usage: !timer <user> <seconds|off>");
+                                continue;
+                            }
+                        },
+                        _ => {
+                            tracing::info!("usage: !timer <user> <seconds|off>");
+                            continue;
+                        }
+                    };
+                    match timer {
+                        Some(secs) => {
+                            timers.insert(peer.to_owned(), secs);
+                            tracing::info!("messages to {} now disappear after {}s", peer, secs);
+                        }
+                        None => {
+                            timers.remove(peer);
+                            tracing::info!("disappearing messages to {} turned off", peer);
+                        }
+                    }
+                }
+                else if cmd == "!outbox" {
+                    if outbox.is_empty() && waiting.is_empty() {
+                        tracing::info!("every message reached the server");
+                    }
+                    for entry in outbox.entries() {
+                        let (user, device) = entry.recipient();
+                        let status = match entry.status {
+                            Status::Pending => "not sent",
+                            Status::Sent => "sent, not acknowledged",
+                        };
+                        match entry.message() {
+                            Some(msg) => tracing::info!("#{} to {} (device {}): {} [{}]", entry.id, user, device, msg.msg.trim(), status),
+                            None => tracing::info!("#{} session reset for {} (device {}) [{}]", entry.id, user, device, status),
+                        }
+                    }
+                    for ((user, device), msgs) in waiting.iter() {
+                        for (msg, _) in msgs {
+                            tracing::info!("to {} (device {}): {} [waiting for the session]", user, device, msg.msg.trim());
+                        }
+                    }
+                }
+                else if cmd == "!padding" {
+                    let (peer, scheme) = match (args.next(), args.next().map(|x| x.parse::<Padding>())) {
+                        (Some(peer), Some(Ok(scheme))) => (peer, scheme),
+                        _ => {
+                            tracing::info!("usage: !padding <user> <none|buckets|padme|random>");
+                            continue;
+                        }
+                    };
+                    paddings.insert(peer.to_owned(), scheme);
+                    tracing::info!("messages to {} are now padded with {}", peer, scheme);
+                }
+                else if cmd == "!contacts" {
+                    for (standing, title) in [(Standing::Accepted, "contacts"), (Standing::Requested, "message requests"), (Standing::Blocked, "blocked")] {
+                        let users = contacts.with_standing(standing);
+                        if !users.is_empty() {
+                            tracing::info!("{}: {}", title, users.join(", "));
+                        }
+                    }
+                }
+                else if cmd == "!accept" || cmd == "!block" || cmd == "!unblock" {
+                    let peer = match args.next() {
+                        Some(peer) if peer != username => peer,
+                        _ => {
+                            tracing::info!("usage: {} <user>", cmd);
+                            continue;
+                        }
+                    };
+                    let was_blocked = contacts.is_blocked(peer);
+                    if cmd == "!accept" {
+                        contacts.accept(peer);
+                        tracing::info!("{} is now a contact", peer);
+                    } else if cmd == "!block" {
+                        contacts.block(peer);
+                        tracing::info!("messages from {} are dropped from now on", peer);
+                        if config.report_blocks {
+                            conn.send(Msg::Block(peer.to_owned())).await;
+                        }
+                    } else if !contacts.unblock(peer) {
+                        tracing::info!("{} is not blocked", peer);
+                        continue;
+                    } else {
+                        tracing::info!("{} is no longer blocked, their next message is a request", peer);
+                    }
+                    if was_blocked && !contacts.is_blocked(peer) && config.report_blocks {
+                        conn.send(Msg::Unblock(peer.to_owned())).await;
+                    }
+                    save_contacts(vault.as_ref(), &contacts);
+                }
+                else if cmd == "!link" {
+                    // the new device prints its signing key when it is not linked yet
+                    let device = match (args.next().map(|x| x.parse::<DeviceId>()), args.next().and_then(parse_signing_key)) {
+                        (Some(Ok(device_id)), Some(signing_key)) => Device::new(username.clone(), device_id).with_signing_key(signing_key),
+                        _ => {
+                            tracing::info!("usage: !link <device> <signing key>");
+                            continue;
+                        }
+                    };
+                    conn.send(Msg::LinkDevice(device)).await;
+                }
+                else if cmd == "!unlink" {
+                    let device_id = match args.next().map(|x| x.parse::<DeviceId>()) {
+                        Some(Ok(device_id)) => device_id,
+                        _ => {
+                            tracing::info!("usage: !unlink <device>");
+                            continue;
+                        }
+                    };
+                    conn.send(Msg::UnlinkDevice(Device::new(username.clone(), device_id))).await;
+                }
+            }
+            else {
+                let mut spl = msg.split('>');
+                if let Some(peer) = spl.next() {
+                    if peer == username {
+                        tracing::info!("cannot message yourself");
+                        continue;
+                    }
+                    if contacts.is_blocked(peer) {
+                        tracing::info!("{} is blocked, unblock them with !unblock {} first", peer, peer);
+                        continue;
+                    }
+                    if !contacts.may_send(peer) {
+                        tracing::info!("{} sent you a message request, accept it with !accept {} to reply", peer, peer);
+                        continue;
+                    }
+                    // the server only hands out the keys of users we looked up
+                    if !keys.keys().any(|(user, _)| user == peer) {
+                        match lookups.get_mut(peer) {
+                            Some((_, lines)) => lines.push(msg.clone()),
+                            None => {
+                                let id = conn.send(Msg::KeyRequest(peer.to_owned())).await;
+                                lookups.insert(peer.to_owned(), (id, vec![msg.clone()]));
+                            }
+                        }
+                        tracing::info!("looking up {}, the message is sent once their keys arrive", peer);
+                        continue;
+                    }
+
+                    // writing to someone first makes them a contact
+                    if contacts.standing(peer).is_none() {
+                        contacts.accept(peer);
+                        save_contacts(vault.as_ref(), &contacts);
+                    }
+
+                    let msg = spl.next().unwrap_or("");
+                    let expires_in = timers.get(peer).copied();
+                    let padding = paddings.get(peer).copied().unwrap_or(padding);
+
+                    // every device of the recipient gets a copy, and so do our other devices
+                    let targets = keys.keys()
+                        .filter(|(user, device)| user == peer || (*user == username && *device != my_device))
+                        .cloned()
+                        .collect::<Vec<_>>();
+
+                    for target in targets {
+                        let mut msg = Message::new(msg.to_owned(), (username.clone(), my_device), target.clone(),
+                            own.key.public());
+                        if target.0 == username {
+                            msg.sent_to = Some(peer.to_string());
+                        } else {
+                            msg.access_key = Some(sealed_sender.access_key);
+                        }
+                        msg.expires_in = expires_in;
+
+                        let target_suite = suites.get(&target).copied().unwrap_or(suite);
+                        if target_suite.post_quantum() && !states.contains_key(&target) {
+                            tracing::info!("waiting for the session with {} (device {}) to be set up", target.0, target.1);
+                            waiting.entry(target).or_default().push((msg, padding));
+                            continue;
+                        }
+                        let st = states.entry(target.clone()).or_insert_with(|| {
+                            let state = State::new(own.key.clone(), *keys.get(&target).unwrap()).with_kdf(KDF_MODE).with_suite(target_suite);
+                            Session::new(state, (&username, my_device), (&target.0, target.1))
+                        });
+                        let sent = encrypt(&mut conn, &mut outbox, st, msg, padding, &sealed_sender, &keys);
+                        tracing::debug!("sending message to server: {}", sent.line);
+                        conn.send_tracked(&mut outbox, sent).await;
+                    }
+                    save_outbox(vault.as_ref(), &outbox);
+                    if !conn.is_connected() {
+                        tracing::info!("not connected to the server, the message is sent once the connection is back");
+                    }
+
+                    history.push(peer, Entry::new(username.clone(), msg.trim().to_owned(), unix_time(), expires_in));
+                    save_history(vault.as_ref(), &history);
+                    if let Some(ui) = &ui {
+                        ui.message(peer, &username, msg.trim(), unix_time());
+                    }
+                }
+                else {
+                    tracing::info!("wrong message format, to write message use \"<user> > <msg>\"");
+                    continue;
+                }
+            }
+        }
+
+        _ = expiry.tick() => {
+            let expired = history.remove_expired(unix_time());
+            for (conversation, entry) in expired.iter() {
+                tracing::info!("message from {} in conversation with {} has disappeared", entry.from, conversation);
+                if let Some(ui) = &ui {
+                    ui.conversation(conversation, chat_lines(&history, conversation));
+                }
+            }
+            if !expired.is_empty() {
+                save_history(vault.as_ref(), &history);
+            }
+        }
+
+        _ = rotation.tick() => {
+            for id in own.rotate((&username, my_device), &keys, &suites, &mut states) {
+                let msg = Msg::PreKeyRequest(Device::new(id.0.clone(), id.1));
+                conn.send(msg).await;
+                pending_resets.insert(id);
+            }
+            let msg = Msg::RotateKey(own.bundle(&username, my_device));
+            conn.send(msg).await;
+            tracing::debug!("rotated keys");
+        }
+
+        _ = tokio::time::sleep_until(resend_at.unwrap_or_else(Instant::now)), if connected && resend_at.is_some() => {
+            resend_at = None;
+            conn.resend_pending(&mut outbox).await;
+        }
+
+        _ = tokio::time::sleep_until(retry_at), if !connected => {
+            // the same keys are announced again, so peers keep their sessions with this device
+            let handshake = |challenge: &Challenge| handshake(&username, my_device, &own, &sealed_sender, &pending_resets, challenge);
+            match conn.connect(handshake).await {
+                Ok(()) => {
+                    tracing::info!("reconnected to the server");
+                    // lookups that were not answered on the lost connection never will be
+                    for (user, (id, _)) in lookups.iter_mut() {
+                        *id = conn.send(Msg::KeyRequest(user.clone())).await;
+                    }
+                    // the server may have taken what it did not acknowledge or not, it is sent again
+                    conn.replay(&mut outbox).await;
+                }
+                Err(e) => {
+                    tracing::error!("failed to reconnect; error = {}", e);
+                    conn.disconnected();
+                }
+            }
+        }
+
+        result = conn.next() => match result {
+            Some(Ok(message)) => {
+                tracing::debug!("received message: {}", message);
+                let msg: Msg = match serde_json::from_str(&message) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        tracing::error!("server sent a malformed message; error = {}", e);
+                        continue;
+                    }
+                };
+                // once the sender is unwrapped a sealed message is handled like any other
+                let msg = match msg {
+                    Msg::SealedMessage(sealed) => match sealed_sender.unwrap(&sealed, &own.key, &keys) {
+                        Some(msg) => Msg::EncryptedMessage(msg),
+                        None => continue,
+                    },
+                    msg => msg,
+                };
+                match msg {
+                    Msg::EncryptedMessage(msg) => {
+                        let id = (msg.sender_name.clone(), msg.sender_device);
+                        let st = match states.get_mut(&id) {
+                            Some(st) => st,
+                            None => {
+                                tracing::error!("received message from unknown device {} of {}", msg.sender_device, msg.sender_name);
+                                continue;
+                            }
+                        };
+                        let msg = match st.decrypt(&msg) {
+                            Ok(decrypted) => {
+                                failures.remove(&id);
+                                sent_resets.remove(&id);
+                                decrypted
+                            }
+                            // the sides disagree on the suite, a new session settles it
+                            Err(ERR_SUITE) => {
+                                tracing::error!("{} (device {}) uses cipher suite {}, the session uses {}", id.0, id.1, msg.suite, st.state().suite());
+                                if !pending_resets.contains(&id) {
+                                    conn.send(Msg::PreKeyRequest(Device::new(id.0.clone(), id.1))).await;
+                                    pending_resets.insert(id);
+                                }
+                                continue;
+                            }
+                            // sent again after an acknowledgement got lost, or replayed
+                            Err(ERR_DUPLICATE) => {
+                                tracing::debug!("dropped a message from {} (device {}) that arrived before", id.0, id.1);
+                                continue;
+                            }
+                            // the server only passes on messages from the device they name and sealed ones
+                            // carry its certificate, so only the sender itself gets to count here
+                            Err(ERR_DECRYPT) => {
+                                tracing::error!("failed to decrypt message from {} (device {}); error = {}", id.0, id.1, ERR_DECRYPT);
+
+                                // the sessions diverged, start a new one from the other side's prekeys
+                                let count = failures.entry(id.clone()).or_insert(0);
+                                *count += 1;
+                                if *count >= RESET_AFTER_FAILURES && !pending_resets.contains(&id) {
+                                    failures.remove(&id);
+                                    tracing::info!("resetting session with {} (device {})", id.0, id.1);
+                                    let msg = Msg::PreKeyRequest(Device::new(id.0.clone(), id.1));
+                                    conn.send(msg).await;
+                                    pending_resets.insert(id);
+                                }
+                                continue;
+                            }
+                            // the session is in step, the content is what is wrong
+                            Err(e) => {
+                                tracing::error!("failed to read message from {} (device {}); error = {}", id.0, id.1, e);
+                                continue;
+                            }
+                        };
+
+                        let conversation = msg.sent_to.clone().unwrap_or_else(|| msg.sender_name.clone());
+                        let from = if msg.sent_to.is_some() { username.clone() } else { msg.sender_name.clone() };
+                        // encrypted again after the sender restarted without hearing that it arrived
+                        if matches!(msg.id, Some(x) if history.contains(&conversation, &from, x)) {
+                            tracing::debug!("dropped a copy of a message from {} (device {}) that arrived before", id.0, id.1);
+                            continue;
+                        }
+
+                        let request = match &msg.sent_to {
+                            // what our other devices sent makes the recipient a contact here as well
+                            Some(to) => {
+                                if matches!(contacts.standing(to), None | Some(Standing::Requested)) {
+                                    contacts.accept(to);
+                                    save_contacts(vault.as_ref(), &contacts);
+                                }
+                                false
+                            }
+                            // decrypted all the same, so the session is still in step after unblocking
+                            None if contacts.is_blocked(&msg.sender_name) => {
+                                tracing::debug!("dropped message from blocked user {}", msg.sender_name);
+                                continue;
+                            }
+                            None => {
+                                if contacts.received(&msg.sender_name) {
+                                    save_contacts(vault.as_ref(), &contacts);
+                                    tracing::info!("{} sent you a message request, !accept {} to reply or !block {} to drop their messages", msg.sender_name, msg.sender_name, msg.sender_name);
+                                }
+                                contacts.standing(&msg.sender_name) == Some(Standing::Requested)
+                            }
+                        };
+
+                        if let Some(access_key) = msg.access_key {
+                            sealed_sender.access_keys.insert(id, access_key);
+                        }
+
+                        // the other side's timer applies to the whole conversation
+                        if timers.get(&conversation).copied() != msg.expires_in {
+                            match msg.expires_in {
+                                Some(secs) => {
+                                    timers.insert(conversation.clone(), secs);
+                                    tracing::info!("messages with {} now disappear after {}s", conversation, secs);
+                                }
+                                None => {
+                                    timers.remove(&conversation);
+                                    tracing::info!("disappearing messages with {} turned off", conversation);
+                                }
+                            }
+                        }
+                        let entry = Entry::new(from.clone(), msg.msg.trim().to_owned(), unix_time(), msg.expires_in);
+                        history.push(&conversation, entry.with_id(msg.id));
+                        save_history(vault.as_ref(), &history);
+
+                        match (&ui, &msg.sent_to) {
+                            (Some(ui), _) => ui.message(&conversation, &from, msg.msg.trim(), unix_time()),
+                            (None, Some(to)) => tracing::info!("you (device {}) > {}: {}", msg.sender_device, to, msg.msg.trim()),
+                            (None, None) if request => tracing::info!("{} (message request): {}", msg.sender_name, msg.msg.trim()),
+                            (None, None) => tracing::info!("{}: {}", msg.sender_name, msg.msg.trim()),
+                        }
+                    }
+                    Msg::PubKey(msg) if msg.user != username || msg.device_id != my_device => {
+                        let id = (msg.user.clone(), msg.device_id);
+                        // the key has to come from the device, not from the server
+                        match (signing_keys.get(&id), msg.signing_key) {
+                            (Some(known), Some(announced)) if *known != announced => {
+                                tracing::error!("{} (device {}) announced another signing key, its key is ignored", id.0, id.1);
+                                continue;
+                            }
+                            (Some(_), None) => {
+                                tracing::error!("the key of {} (device {}) is not signed, it is ignored", id.0, id.1);
+                                continue;
+                            }
+                            _ => (),
+                        }
+                        if let Some(signing_key) = msg.signing_key {
+                            if !msg.verify() {
+                                tracing::error!("the key of {} (device {}) is not signed with its signing key, it is ignored", id.0, id.1);
+                                continue;
+                            }
+                            if signing_keys.insert(id.clone(), signing_key).is_none() {
+                                save_signing_keys(vault.as_ref(), &signing_keys);
+                            }
+                        }
+                        // a device that reconnected announces a new key, the old session is useless
+                        if keys.insert(id.clone(), msg.public_key) == Some(msg.public_key) {
+                            continue;
+                        }
+                        // its access key may have changed as well, it comes along with its next message
+                        sealed_sender.access_keys.remove(&id);
+                        sent_resets.remove(&id);
+                        let session_suite = session_suite((&username, my_device), &id, suite, msg.suite);
+                        suites.insert(id.clone(), session_suite);
+                        if session_suite.post_quantum() {
+                            states.remove(&id);
+                            if starts_sessions((&username, my_device), &id) && !pending_resets.contains(&id) {
+                                let msg = Msg::PreKeyRequest(Device::new(id.0.clone(), id.1));
+                                conn.send(msg).await;
+                                pending_resets.insert(id);
+                            }
+                        } else {
+                            let state = State::new(own.key.clone(), msg.public_key).with_kdf(KDF_MODE).with_suite(session_suite);
+                            let mut session = Session::new(state, (&username, my_device), (&id.0, id.1));
+                            let waited = encrypt_waiting(&mut waiting, &id, &mut session, &mut conn, &mut outbox, &sealed_sender, &keys);
+                            states.insert(id, session);
+                            if !waited.is_empty() {
+                                for sent in waited {
+                                    conn.send_tracked(&mut outbox, sent).await;
+                                }
+                                save_outbox(vault.as_ref(), &outbox);
+                            }
+                        }
+                    }
+                    Msg::PreKeyBundle(bundle) => {
+                        let id = (bundle.user.clone(), bundle.device_id);
+                        if !pending_resets.remove(&id) {
+                            continue;
+                        }
+                        // the server does not get to hand out prekeys of its own, devices that never
+                        // announced a signing key cannot publish any
+                        if !signing_keys.get(&id).is_some_and(|key| bundle.verify(key)) {
+                            tracing::error!("prekeys of {} (device {}) are not signed with its signing key", id.0, id.1);
+                            continue;
+                        }
+                        if keys.get(&id) != Some(&bundle.identity_key) {
+                            tracing::error!("prekeys of {} (device {}) are not for the key it announced", id.0, id.1);
+                            continue;
+                        }
+
+                        let ephemeral = KeyPair::new();
+                        let session_suite = session_suite((&username, my_device), &id, suite, bundle.suite);
+                        let (root, pq_ciphertext) = if session_suite.post_quantum() {
+                            let initiated = bundle.pq_prekey.as_ref().and_then(|pq_prekey| {
+                                pqxdh_initiate(&own.key, &ephemeral, &bundle.identity_key, &bundle.signed_prekey, pq_prekey)
+                            });
+                            match initiated {
+                                Some((root, ciphertext)) => (root, Some(ciphertext)),
+                                None => {
+                                    tracing::error!("{} (device {}) has no valid post-quantum prekey", id.0, id.1);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            (x3dh_initiate(&own.key, &ephemeral, &bundle.identity_key, &bundle.signed_prekey), None)
+                        };
+                        keys.insert(id.clone(), bundle.identity_key);
+                        suites.insert(id.clone(), session_suite);
+
+                        // it goes through the outbox ahead of the messages on the session, so it is
+                        // sent again with them if the connection drops
+                        let reset = SessionReset {
+                            sender_name: username.clone(),
+                            sender_device: my_device,
+                            recv_name: id.0.clone(),
+                            recv_device: id.1,
+                            identity_key: own.key.public(),
+                            ephemeral_key: ephemeral.public(),
+                            prekey_id: bundle.prekey_id,
+                            pq_ciphertext,
+                            suite: session_suite,
+                        };
+                        let request = conn.request(Msg::SessionReset(reset.clone()));
+                        outbox.push(outbox::Entry::reset(request.id, reset, request.line.clone()));
+                        sent_resets.insert(id.clone());
+
+                        let state = State::from_root(own.key.clone(), bundle.identity_key, root).with_kdf(KDF_MODE).with_suite(session_suite);
+                        let mut session = Session::new(state, (&username, my_device), (&id.0, id.1));
+                        let waited = encrypt_waiting(&mut waiting, &id, &mut session, &mut conn, &mut outbox, &sealed_sender, &keys);
+                        let restarted = states.insert(id.clone(), session).is_some();
+
+                        conn.send_tracked(&mut outbox, request).await;
+                        for sent in waited {
+                            conn.send_tracked(&mut outbox, sent).await;
+                        }
+                        save_outbox(vault.as_ref(), &outbox);
+                        if restarted {
+                            tracing::info!("session with {} (device {}) was re-established", id.0, id.1);
+                        } else {
+                            tracing::debug!("session with {} (device {}) was set up", id.0, id.1);
+                        }
+                    }
+                    Msg::SessionReset(reset) => {
+                        let id = (reset.sender_name.clone(), reset.sender_device);
+                        if reset.prekey_id != own.prekey_id {
+                            tracing::error!("{} (device {}) tried to reset the session with an old prekey", id.0, id.1);
+                            continue;
+                        }
+                        // a reset with any other key than the one the device announced could come from anyone
+                        if keys.get(&id) != Some(&reset.identity_key) {
+                            tracing::error!("{} (device {}) tried to reset the session with a key it did not announce", id.0, id.1);
+                            continue;
+                        }
+                        // both sides reset the session at once, as when both start one the smaller
+                        // `(user, device)` keeps its own and the other side takes it
+                        if sent_resets.contains(&id) && starts_sessions((&username, my_device), &id) {
+                            tracing::debug!("{} (device {}) reset the session as well, it takes ours", id.0, id.1);
+                            continue;
+                        }
+
+                        let root = match &reset.pq_ciphertext {
+                            Some(ciphertext) => pqxdh_respond(&own.key, &own.prekey, &own.pq_prekey, &reset.identity_key, &reset.ephemeral_key, ciphertext),
+                            None if reset.suite.post_quantum() => None,
+                            None => Some(x3dh_respond(&own.key, &own.prekey, &reset.identity_key, &reset.ephemeral_key)),
+                        };
+                        let root = match root {
+                            Some(root) => root,
+                            None => {
+                                tracing::error!("{} (device {}) tried to start a session without a valid post-quantum key", id.0, id.1);
+                                continue;
+                            }
+                        };
+                        // a reset of our own would only replace the session again
+                        pending_resets.remove(&id);
+                        if sent_resets.remove(&id) {
+                            outbox.remove_resets((&id.0, id.1));
+                        }
+                        // the side that set up the session picked the suite
+                        suites.insert(id.clone(), reset.suite);
+                        let state = State::from_root(own.key.clone(), reset.identity_key, root).with_kdf(KDF_MODE).with_suite(reset.suite);
+                        let mut session = Session::new(state, (&username, my_device), (&id.0, id.1));
+                        for sent in encrypt_waiting(&mut waiting, &id, &mut session, &mut conn, &mut outbox, &sealed_sender, &keys) {
+                            conn.send_tracked(&mut outbox, sent).await;
+                        }
+                        save_outbox(vault.as_ref(), &outbox);
+                        let restarted = states.insert(id.clone(), session).is_some();
+                        failures.remove(&id);
+                        if restarted {
+                            tracing::info!("{} (device {}) re-established the session", id.0, id.1);
+                        } else {
+                            tracing::debug!("{} (device {}) set up the session", id.0, id.1);
+                        }
+                    }
+                    Msg::UnlinkDevice(device) => {
+                        let id = (device.user, device.device_id);
+                        // its signing key is kept, the server does not link it again with another
+                        keys.remove(&id);
+                        suites.remove(&id);
+                        states.remove(&id);
+                        waiting.remove(&id);
+                        sent_resets.remove(&id);
+                        online.remove(&id);
+                        sealed_sender.access_keys.remove(&id);
+                        tracing::info!("device {} of {} was unlinked", id.1, id.0);
+                    }
+                    Msg::Presence(presence) => {
+                        let id = (presence.user, presence.device_id);
+                        tracing::debug!("device {} of {} is {}", id.1, id.0, if presence.online { "online" } else { "offline" });
+                        if presence.online {
+                            online.insert(id);
+                        } else {
+                            online.remove(&id);
+                        }
+                    }
+                    Msg::SenderCertificate(certificate) => {
+                        if certificate.user != username || certificate.device_id != my_device || certificate.identity_key != own.key.public() {
+                            tracing::error!("server issued a sender certificate that is not ours");
+                            continue;
+                        }
+                        sealed_sender.certificate = Some(certificate);
+                        // the server accepted our keys, so the connection is fully set up again
+                        conn.registered();
+                    }
+                    Msg::Ack(ack) => {
+                        conn.answered(Some(ack.id));
+                        // the keys came before the answer
+                        if let Some((user, lines)) = take_lookup(&mut lookups, ack.id) {
+                            if !keys.keys().any(|(x, _)| *x == user) {
+                                tracing::info!("{} has no devices to message", user);
+                                continue;
+                            }
+                            for line in lines {
+                                let _ = input.send(line);
+                            }
+                        }
+                        if let Some(entry) = outbox.remove(ack.id) {
+                            tracing::debug!("{} reached the server", entry.describe());
+                            save_outbox(vault.as_ref(), &outbox);
+                        }
+                    }
+                    Msg::Nack(nack) => {
+                        let (id, what) = match conn.answered(nack.id) {
+                            Some(request) => request,
+                            None => {
+                                tracing::error!("server refused a request; reason = {}", nack.reason);
+                                continue;
+                            }
+                        };
+                        if let Some((user, _)) = take_lookup(&mut lookups, id) {
+                            tracing::info!("failed to look up {}; reason = {}", user, nack.reason);
+                            continue;
+                        }
+                        match nack.reason {
+                            NackReason::RateLimited { retry_after } if outbox.entries().iter().any(|x| x.id == id) => {
+                                tracing::info!("{} was rate limited, sending it again in {}s", what, retry_after);
+                                outbox.mark_pending(id);
+                                let at = Instant::now() + Duration::from_secs(retry_after);
+                                resend_at = Some(resend_at.map_or(at, |x| x.max(at)));
+                            }
+                            // nothing works without being registered, trying again would not help
+                            reason @ (NackReason::NotRegistered | NackReason::Banned | NackReason::InvalidSignature) => break reason,
+                            reason => {
+                                tracing::error!("server refused {}; reason = {}", what, reason);
+                                // the server would refuse it again
+                                let entry = match outbox.remove(id) {
+                                    Some(entry) => entry,
+                                    None => continue,
+                                };
+                                save_outbox(vault.as_ref(), &outbox);
+                                // the recipient's ratchet skips a lost message, but without the reset it
+                                // has no session with us at all. Refused for anything but a failure of
+                                // the server, a new one would be refused as well
+                                let (user, device) = entry.recipient();
+                                let peer = (user.to_owned(), device);
+                                if matches!(entry.item, outbox::Item::Reset(_)) {
+                                    sent_resets.remove(&peer);
+                                    if reason == NackReason::Internal && keys.contains_key(&peer) && !pending_resets.contains(&peer) {
+                                        conn.send(Msg::PreKeyRequest(Device::new(peer.0.clone(), peer.1))).await;
+                                        pending_resets.insert(peer);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Msg::Info(msg) => {
+                        tracing::info!("{}", msg.info);
+                    }
+                    // shown again only if it changed since the last connection
+                    Msg::ServerInfo(info) if server_info.as_ref() != Some(&info) => {
+                        tracing::info!("connected to {} (version {}): {}", info.name, info.version, info.motd);
+                        let supports = |feature: &str| info.features.iter().any(|x| x == feature);
+                        for feature in ["requests", "offline_queue", "prekeys"] {
+                            if !supports(feature) {
+                                tracing::warn!("the server does not support {}, messages may get lost", feature);
+                            }
+                        }
+                        // the server would refuse sealed messages
+                        sealed_sender.enabled = config.sealed_sender && supports("sealed_sender");
+                        if config.sealed_sender && !sealed_sender.enabled {
+                            tracing::warn!("the server does not support sealed sender, it sees who sends messages");
+                        }
+                        server_info = Some(info);
+                    }
+                    Msg::UserList(list) if list.users.is_empty() => tracing::info!("no users found"),
+                    Msg::UserList(list) => {
+                        tracing::info!("users {}-{} of {}: {}", list.offset + 1, list.offset + list.users.len(), list.total, list.users.join(", "));
+                    }
+                    Msg::Err(msg) => tracing::error!("server returned error; error = {:?}", msg),
+                    _ => (),
+                }
+            }
+            Some(Err(e)) => {
+                tracing::error!(
+                    "an error occurred while processing messages; error = {:?}",
+                    e
+                    );
+                }
+            None => {
+                // presence is sent again by the server once we are back
+                online.clear();
+                conn.disconnected();
+            }
+            }
+        }
+    };
+
+    // restores the terminal before printing
+    drop(ui);
+    match refused {
+        NackReason::Banned => eprintln!("error: {} is banned from this server", username),
+        NackReason::InvalidSignature => eprintln!(
+            "error: device {} of {} is registered with another signing key, link this one under a new device id with `!link <device> {}`",
+            my_device, username, hex(&own.identity.public())
+        ),
+        _ => eprintln!(
+            "error: device {} is not linked to {}, link it with `!link {} {}` from a linked device",
+            my_device, username, my_device, hex(&own.identity.public())
+        ),
+    }
+    process::exit(1);
+}
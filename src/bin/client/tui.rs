@@ -0,0 +1,519 @@
+// Full-screen interface of the client: a roster of users, the scrollback of the selected
+// conversation, the log, an input line and a status bar. It runs on its own thread, typed lines
+// go to the main loop exactly as they would in line mode and everything shown comes in as `Event`s.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers};
+use lib_sig::message::DeviceId;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use sha2::{Digest, Sha256};
+use x25519_dalek::PublicKey;
+
+// lines of the log kept and shown below the conversation
+const LOG_LINES: usize = 500;
+const LOG_HEIGHT: u16 = 7;
+
+pub struct ChatLine {
+    pub from: String,
+    pub text: String,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Established,
+    // waiting for prekeys or for the other side to set the session up
+    Pending,
+    None,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct DeviceState {
+    pub device: DeviceId,
+    pub online: bool,
+    pub session: SessionState,
+    pub sealed: bool,
+    pub fingerprint: String,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Contact {
+    pub user: String,
+    pub devices: Vec<DeviceState>,
+}
+
+impl Contact {
+    fn online(&self) -> bool {
+        self.devices.iter().any(|x| x.online)
+    }
+}
+
+pub enum Event {
+    Message(String, ChatLine),
+    // replaces the whole scrollback of a conversation, after messages disappeared
+    Conversation(String, Vec<ChatLine>),
+    Roster(Vec<Contact>),
+    // whether the client is connected to the server, and how many messages it did not acknowledge
+    Connection(bool, usize),
+    Log(String),
+    Close,
+}
+
+/// Short hash of a key for comparing out of band, in groups of four hex digits.
+pub fn fingerprint(key: &PublicKey) -> String {
+    Sha256::digest(key.as_bytes())[..8]
+        .chunks(2)
+        .map(|x| format!("{:02x}{:02x}", x[0], x[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub struct Ui {
+    tx: mpsc::Sender<Event>,
+    rx: Option<mpsc::Receiver<Event>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Ui {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Ui {
+            tx,
+            rx: Some(rx),
+            thread: None,
+        }
+    }
+
+    /// Writer for the log subscriber, its lines end up in the log pane.
+    pub fn log_writer(&self) -> impl Fn() -> LogWriter + Send + Sync + 'static {
+        let tx = self.tx.clone();
+        move || LogWriter(tx.clone())
+    }
+
+    /// Takes over the terminal. Lines typed are sent on `input`, prefixed with the selected
+    /// conversation as `user>` unless they are commands.
+    pub fn start(
+        &mut self,
+        username: String,
+        input: tokio::sync::mpsc::UnboundedSender<String>,
+    ) -> io::Result<()> {
+        let terminal = ratatui::try_init()?;
+        let rx = self.rx.take().expect("the interface is only started once");
+        self.thread = Some(thread::spawn(move || {
+            let result = App::new(username).run(terminal, rx, input);
+            ratatui::restore();
+            if let Err(e) = result {
+                eprintln!("terminal interface failed: {}", e);
+            }
+        }));
+        Ok(())
+    }
+
+    pub fn message(&self, conversation: &str, from: &str, text: &str, timestamp: u64) {
+        let line = ChatLine {
+            from: from.to_owned(),
+            text: text.to_owned(),
+            timestamp,
+        };
+        let _ = self.tx.send(Event::Message(conversation.to_owned(), line));
+    }
+
+    pub fn conversation(&self, conversation: &str, lines: Vec<ChatLine>) {
+        let _ = self
+            .tx
+            .send(Event::Conversation(conversation.to_owned(), lines));
+    }
+
+    pub fn roster(&self, roster: Vec<Contact>) {
+        let _ = self.tx.send(Event::Roster(roster));
+    }
+
+    pub fn connection(&self, connected: bool, unacknowledged: usize) {
+        let _ = self.tx.send(Event::Connection(connected, unacknowledged));
+    }
+}
+
+// gives the terminal back however the client exits
+impl Drop for Ui {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Event::Close);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub struct LogWriter(mpsc::Sender<Event>);
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf).trim_end().to_owned();
+        if !line.is_empty() {
+            let _ = self.0.send(Event::Log(line));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct App {
+    username: String,
+    conversations: HashMap<String, Vec<ChatLine>>,
+    unread: HashMap<String, usize>,
+    roster: Vec<Contact>,
+    connected: bool,
+    unacknowledged: usize,
+    // name of the selected conversation, kept when the list changes around it
+    selected: Option<String>,
+    input: String,
+    // lines scrolled up from the newest message
+    scroll: usize,
+    log: Vec<String>,
+}
+
+impl App {
+    fn new(username: String) -> Self {
+        App {
+            username,
+            conversations: HashMap::new(),
+            unread: HashMap::new(),
+            roster: Vec::new(),
+            connected: true,
+            unacknowledged: 0,
+            selected: None,
+            input: String::new(),
+            scroll: 0,
+            log: Vec::new(),
+        }
+    }
+
+    fn run(
+        mut self,
+        mut terminal: DefaultTerminal,
+        rx: mpsc::Receiver<Event>,
+        input: tokio::sync::mpsc::UnboundedSender<String>,
+    ) -> io::Result<()> {
+        loop {
+            loop {
+                match rx.try_recv() {
+                    Ok(Event::Close) | Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                    Ok(event) => self.apply(event),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                }
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(Duration::from_millis(50))? {
+                continue;
+            }
+            let key = match event::read()? {
+                TermEvent::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    ratatui::restore();
+                    std::process::exit(0);
+                }
+                KeyCode::Esc => {
+                    ratatui::restore();
+                    std::process::exit(0);
+                }
+                KeyCode::Enter => {
+                    if let Some(line) = self.submit() {
+                        if input.send(line).is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                KeyCode::Backspace => {
+                    self.input.pop();
+                }
+                KeyCode::Char(c) => self.input.push(c),
+                KeyCode::Up | KeyCode::BackTab => self.select(-1),
+                KeyCode::Down | KeyCode::Tab => self.select(1),
+                KeyCode::PageUp => self.scroll += 5,
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(5),
+                _ => (),
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Message(conversation, line) => {
+                if self.selected.as_deref() != Some(conversation.as_str()) {
+                    *self.unread.entry(conversation.clone()).or_insert(0) += 1;
+                }
+                if self.selected.is_none() {
+                    self.selected = Some(conversation.clone());
+                    self.unread.remove(&conversation);
+                }
+                self.conversations
+                    .entry(conversation)
+                    .or_default()
+                    .push(line);
+            }
+            Event::Conversation(conversation, lines) => {
+                self.conversations.insert(conversation, lines);
+            }
+            Event::Roster(roster) => {
+                self.roster = roster;
+                if self.selected.is_none() {
+                    self.selected = self.names().into_iter().next();
+                }
+            }
+            Event::Connection(connected, unacknowledged) => {
+                self.connected = connected;
+                self.unacknowledged = unacknowledged;
+            }
+            Event::Log(line) => {
+                self.log.push(line);
+                if self.log.len() > LOG_LINES {
+                    self.log.remove(0);
+                }
+            }
+            Event::Close => (),
+        }
+    }
+
+    // everyone in the roster and everyone there is a conversation with
+    fn names(&self) -> Vec<String> {
+        let mut names = self
+            .roster
+            .iter()
+            .map(|x| x.user.clone())
+            .chain(self.conversations.keys().cloned())
+            .filter(|x| *x != self.username)
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    fn select(&mut self, step: isize) {
+        let names = self.names();
+        if names.is_empty() {
+            return;
+        }
+        let current = self
+            .selected
+            .as_ref()
+            .and_then(|x| names.iter().position(|n| n == x))
+            .unwrap_or(0) as isize;
+        let next = (current + step).rem_euclid(names.len() as isize) as usize;
+        self.unread.remove(&names[next]);
+        self.selected = Some(names[next].clone());
+        self.scroll = 0;
+    }
+
+    fn submit(&mut self) -> Option<String> {
+        let text = std::mem::take(&mut self.input);
+        let text = text.trim();
+        if text.is_empty() {
+            return None;
+        }
+        if text.starts_with('!') {
+            return Some(text.to_owned());
+        }
+        match &self.selected {
+            Some(peer) => {
+                self.scroll = 0;
+                Some(format!("{}>{}", peer, text))
+            }
+            None => {
+                self.log
+                    .push("select a conversation with up/down first".to_owned());
+                None
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, input, status] = Layout::vertical([
+            Constraint::Min(5),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [roster, right] =
+            Layout::horizontal([Constraint::Length(26), Constraint::Min(20)]).areas(main);
+        let [chat, log] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(LOG_HEIGHT)]).areas(right);
+
+        self.draw_roster(frame, roster);
+        self.draw_chat(frame, chat);
+
+        let log_lines = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(LOG_HEIGHT as usize - 2))
+            .map(|x| Line::from(x.as_str()))
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(log_lines)
+                .block(Block::bordered().title("log"))
+                .dark_gray(),
+            log,
+        );
+
+        let prompt = match &self.selected {
+            Some(peer) => format!("to {}", peer),
+            None => "input".to_owned(),
+        };
+        frame.render_widget(
+            Paragraph::new(self.input.as_str()).block(Block::bordered().title(prompt)),
+            input,
+        );
+        let width = input.width.saturating_sub(2) as usize;
+        let cursor = self.input.chars().count().min(width.saturating_sub(1));
+        frame.set_cursor_position((input.x + 1 + cursor as u16, input.y + 1));
+
+        frame.render_widget(self.status_bar(), status);
+    }
+
+    fn draw_roster(&self, frame: &mut Frame, area: Rect) {
+        let names = self.names();
+        let items = names
+            .iter()
+            .map(|name| {
+                let online = self.roster.iter().any(|x| x.user == *name && x.online());
+                let mut spans = vec![
+                    if online {
+                        Span::from("● ").green()
+                    } else {
+                        Span::from("○ ").dark_gray()
+                    },
+                    Span::from(name.as_str()),
+                ];
+                if let Some(n) = self.unread.get(name) {
+                    spans.push(Span::from(format!(" ({})", n)).yellow().bold());
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect::<Vec<_>>();
+
+        let mut state = ListState::default().with_selected(
+            self.selected
+                .as_ref()
+                .and_then(|x| names.iter().position(|n| n == x)),
+        );
+        frame.render_stateful_widget(
+            List::new(items)
+                .block(Block::bordered().title(format!("{} · roster", self.username)))
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+            area,
+            &mut state,
+        );
+    }
+
+    fn draw_chat(&self, frame: &mut Frame, area: Rect) {
+        let title = self.selected.clone().unwrap_or_default();
+        let lines = self
+            .selected
+            .as_ref()
+            .and_then(|x| self.conversations.get(x))
+            .map(|x| x.as_slice())
+            .unwrap_or_default();
+
+        // pick the newest lines that fit once wrapped, skipping `scroll` of them
+        let (width, height) = (
+            area.width.saturating_sub(2).max(1) as usize,
+            area.height.saturating_sub(2) as usize,
+        );
+        let end = lines.len().saturating_sub(self.scroll);
+        let mut start = end;
+        let mut used = 0;
+        while start > 0 {
+            let line = &lines[start - 1];
+            let len = line.from.chars().count() + line.text.chars().count() + 9;
+            let rows = len.div_ceil(width).max(1);
+            if used + rows > height {
+                break;
+            }
+            used += rows;
+            start -= 1;
+        }
+
+        let text = lines[start..end]
+            .iter()
+            .map(|x| {
+                let from = if x.from == self.username {
+                    Span::from("you").cyan()
+                } else {
+                    Span::from(x.from.as_str()).magenta()
+                };
+                Line::from(vec![
+                    Span::from(format!("{} ", clock(x.timestamp))).dark_gray(),
+                    from,
+                    Span::from(": "),
+                    Span::from(x.text.as_str()),
+                ])
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(text)
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    // what is known about the sessions with every device of the selected user
+    fn status_bar(&self) -> Paragraph<'_> {
+        let contact = self
+            .selected
+            .as_ref()
+            .and_then(|x| self.roster.iter().find(|c| c.user == *x));
+        let mut spans = Vec::new();
+        if !self.connected {
+            spans.push(Span::from("offline, reconnecting").red());
+            spans.push(Span::from(" │ "));
+        }
+        if self.unacknowledged > 0 {
+            spans.push(Span::from(format!("{} unacknowledged", self.unacknowledged)).yellow());
+            spans.push(Span::from(" │ "));
+        }
+        match contact {
+            Some(contact) if !contact.devices.is_empty() => {
+                for (i, d) in contact.devices.iter().enumerate() {
+                    if i > 0 {
+                        spans.push(Span::from(" │ "));
+                    }
+                    spans.push(Span::from(format!("device {} ", d.device)));
+                    spans.push(match d.session {
+                        SessionState::Established => Span::from("encrypted").green(),
+                        SessionState::Pending => Span::from("setting up").yellow(),
+                        SessionState::None => Span::from("no session").red(),
+                    });
+                    if d.sealed {
+                        spans.push(Span::from(" sealed").green());
+                    }
+                    spans.push(Span::from(format!(" [{}]", d.fingerprint)));
+                }
+            }
+            _ => spans.push(Span::from("no known devices")),
+        }
+        spans.push(Span::from("  ·  tab/↑↓ switch, pgup/pgdn scroll, esc quit").dark_gray());
+        Paragraph::new(Line::from(spans)).style(Style::default().bg(Color::Black))
+    }
+}
+
+// hours and minutes of a unix time, in UTC
+fn clock(timestamp: u64) -> String {
+    let secs = timestamp % (24 * 60 * 60);
+    format!("{:02}:{:02}", secs / 3600, secs / 60 % 60)
+}
@@ -0,0 +1,341 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::builder::BoolishValueParser;
+use clap::{Args, ValueEnum};
+use serde::{de::DeserializeOwned, Deserialize};
+use tracing::metadata::LevelFilter;
+use tracing_subscriber::fmt::{format::FmtSpan, MakeWriter};
+use tracing_subscriber::EnvFilter;
+
+use crate::crypto::Suite;
+use crate::message::DeviceId;
+use crate::padding::Padding;
+
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::Invalid(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:6142";
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+// fits a bundle with a post-quantum prekey and messages padded to the largest bucket many times over
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 1 << 20;
+pub const DEFAULT_RATE_LIMIT: usize = 20;
+pub const DEFAULT_NAME: &str = "lib-sig";
+pub const DEFAULT_MOTD: &str = "Welcome to this simple server!";
+
+// hides the length of messages unless something else is configured
+pub const DEFAULT_PADDING: Padding = Padding::Padme;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, with span events.
+    #[default]
+    Full,
+    /// Human readable, one short line per event.
+    Compact,
+    /// One JSON object per event.
+    Json,
+}
+
+impl LogFormat {
+    /// Installs the global subscriber, writing to `writer`. The level is taken from `RUST_LOG`.
+    pub fn init<W>(self, writer: W, ansi: bool)
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(
+                EnvFilter::builder()
+                    .with_default_directive(LevelFilter::INFO.into())
+                    .from_env_lossy(),
+            )
+            .with_writer(writer)
+            .with_ansi(ansi);
+        match self {
+            LogFormat::Full => builder.with_span_events(FmtSpan::FULL).init(),
+            LogFormat::Compact => builder.compact().init(),
+            LogFormat::Json => builder.json().init(),
+        }
+    }
+}
+
+/// Reads a TOML config file, unknown keys are an error.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+}
+
+fn at_least_one(name: &str, value: Option<usize>, default: usize) -> Result<usize> {
+    match value.unwrap_or(default) {
+        0 => Err(ConfigError::Invalid(format!(
+            "{} has to be at least 1",
+            name
+        ))),
+        x => Ok(x),
+    }
+}
+
+/// Options of the relay, each can be given on the command line or in the config file.
+#[derive(Args, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerOptions {
+    /// Address to listen on [default: 127.0.0.1:6142]
+    #[arg(long)]
+    pub addr: Option<String>,
+    /// Database path, registrations are only kept in memory without one
+    #[arg(long)]
+    pub db: Option<PathBuf>,
+    /// How log lines are written [default: full]
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Connections beyond this many are turned away [default: 1024]
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    /// Longest line accepted from a client, in bytes [default: 1048576]
+    #[arg(long)]
+    pub max_line_length: Option<usize>,
+    /// Messages and lookups a device may send per second, more are refused until it slows down
+    /// [default: 20]
+    #[arg(long)]
+    pub rate_limit: Option<usize>,
+    /// Address to serve Prometheus metrics on at `/metrics`, disabled without one
+    #[arg(long)]
+    pub metrics_addr: Option<String>,
+    /// Unix socket to accept admin commands on, only its owner may connect
+    #[arg(long)]
+    pub admin_socket: Option<PathBuf>,
+    /// Name the server introduces itself with [default: lib-sig]
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Welcome message shown to clients when they connect
+    #[arg(long)]
+    pub motd: Option<String>,
+    /// Announce the keys and presence of every user to everyone, instead of only to the users
+    /// who looked them up [default: off]
+    #[arg(long, value_parser = BoolishValueParser::new(), num_args = 0..=1,
+        default_missing_value = "on")]
+    pub broadcast_keys: Option<bool>,
+    /// Let clients list registered users, without it they can only look up names they know
+    /// [default: on]
+    #[arg(long, value_parser = BoolishValueParser::new(), num_args = 0..=1,
+        default_missing_value = "on")]
+    pub list_users: Option<bool>,
+}
+
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    pub db: Option<PathBuf>,
+    pub log_format: LogFormat,
+    pub max_connections: usize,
+    pub max_line_length: usize,
+    pub rate_limit: usize,
+    pub metrics_addr: Option<SocketAddr>,
+    pub admin_socket: Option<PathBuf>,
+    pub name: String,
+    pub motd: String,
+    pub broadcast_keys: bool,
+    pub list_users: bool,
+}
+
+impl ServerOptions {
+    /// Takes what is not set here from `file`.
+    pub fn or(self, file: ServerOptions) -> Self {
+        ServerOptions {
+            addr: self.addr.or(file.addr),
+            db: self.db.or(file.db),
+            log_format: self.log_format.or(file.log_format),
+            max_connections: self.max_connections.or(file.max_connections),
+            max_line_length: self.max_line_length.or(file.max_line_length),
+            rate_limit: self.rate_limit.or(file.rate_limit),
+            metrics_addr: self.metrics_addr.or(file.metrics_addr),
+            admin_socket: self.admin_socket.or(file.admin_socket),
+            name: self.name.or(file.name),
+            motd: self.motd.or(file.motd),
+            broadcast_keys: self.broadcast_keys.or(file.broadcast_keys),
+            list_users: self.list_users.or(file.list_users),
+        }
+    }
+
+    pub fn validate(self) -> Result<ServerConfig> {
+        let addr = self.addr.unwrap_or_else(|| DEFAULT_ADDR.to_owned());
+        Ok(ServerConfig {
+            addr: addr.parse().map_err(|_| {
+                ConfigError::Invalid(format!("invalid address to listen on: {}", addr))
+            })?,
+            db: self.db,
+            log_format: self.log_format.unwrap_or_default(),
+            max_connections: at_least_one(
+                "max_connections",
+                self.max_connections,
+                DEFAULT_MAX_CONNECTIONS,
+            )?,
+            max_line_length: at_least_one(
+                "max_line_length",
+                self.max_line_length,
+                DEFAULT_MAX_LINE_LENGTH,
+            )?,
+            rate_limit: at_least_one("rate_limit", self.rate_limit, DEFAULT_RATE_LIMIT)?,
+            metrics_addr: match self.metrics_addr {
+                Some(addr) => Some(addr.parse().map_err(|_| {
+                    ConfigError::Invalid(format!("invalid metrics address: {}", addr))
+                })?),
+                None => None,
+            },
+            admin_socket: self.admin_socket,
+            name: self.name.unwrap_or_else(|| DEFAULT_NAME.to_owned()),
+            motd: self.motd.unwrap_or_else(|| DEFAULT_MOTD.to_owned()),
+            broadcast_keys: self.broadcast_keys.unwrap_or(false),
+            list_users: self.list_users.unwrap_or(true),
+        })
+    }
+}
+
+/// Options of the client, each can be given on the command line or in the config file.
+#[derive(Args, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientOptions {
+    /// Name to register with the server
+    #[arg(short, long)]
+    pub username: Option<String>,
+    /// Address of the server [default: 127.0.0.1:6142]
+    #[arg(long)]
+    pub addr: Option<String>,
+    /// Id of this device [default: 0]
+    #[arg(short, long)]
+    pub device: Option<DeviceId>,
+    /// Keeps identity and history encrypted in this directory, nothing is kept without one
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+    /// Cipher suite of new sessions [default: aes128-siv]
+    #[arg(long, env = "LIB_SIG_SUITE")]
+    pub suite: Option<String>,
+    /// Padding of sent messages [default: padme]
+    #[arg(long, env = "LIB_SIG_PADDING")]
+    pub padding: Option<String>,
+    /// Hide the sender of messages to other users from the server [default: on]
+    #[arg(long, env = "LIB_SIG_SEALED_SENDER", value_parser = BoolishValueParser::new(),
+        num_args = 0..=1, default_missing_value = "on")]
+    pub sealed_sender: Option<bool>,
+    /// Full-screen terminal interface instead of reading lines from stdin [default: off]
+    #[arg(long, env = "LIB_SIG_TUI", value_parser = BoolishValueParser::new(),
+        num_args = 0..=1, default_missing_value = "on")]
+    pub tui: Option<bool>,
+    /// How log lines are written [default: full]
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Longest line accepted from the server, in bytes [default: 1048576]
+    #[arg(long)]
+    pub max_line_length: Option<usize>,
+    /// Also have the server drop messages from blocked users, which tells it whom we block
+    /// [default: off]
+    #[arg(long, env = "LIB_SIG_REPORT_BLOCKS", value_parser = BoolishValueParser::new(),
+        num_args = 0..=1, default_missing_value = "on")]
+    pub report_blocks: Option<bool>,
+}
+
+pub struct ClientConfig {
+    pub username: String,
+    pub addr: String,
+    pub device: DeviceId,
+    pub data_dir: Option<PathBuf>,
+    pub suite: Suite,
+    pub padding: Padding,
+    pub sealed_sender: bool,
+    pub tui: bool,
+    pub log_format: LogFormat,
+    pub max_line_length: usize,
+    pub report_blocks: bool,
+}
+
+impl ClientOptions {
+    /// Takes what is not set here from `file`.
+    pub fn or(self, file: ClientOptions) -> Self {
+        ClientOptions {
+            username: self.username.or(file.username),
+            addr: self.addr.or(file.addr),
+            device: self.device.or(file.device),
+            data_dir: self.data_dir.or(file.data_dir),
+            suite: self.suite.or(file.suite),
+            padding: self.padding.or(file.padding),
+            sealed_sender: self.sealed_sender.or(file.sealed_sender),
+            tui: self.tui.or(file.tui),
+            log_format: self.log_format.or(file.log_format),
+            max_line_length: self.max_line_length.or(file.max_line_length),
+            report_blocks: self.report_blocks.or(file.report_blocks),
+        }
+    }
+
+    pub fn validate(self) -> Result<ClientConfig> {
+        let username = self
+            .username
+            .ok_or_else(|| ConfigError::Invalid("a username is required".to_owned()))?;
+        // `>` separates the recipient from the message, the relay keys records by `user \0 device`
+        if username.is_empty()
+            || username
+                .chars()
+                .any(|c| c == '>' || c == '\0' || c.is_whitespace())
+        {
+            return Err(ConfigError::Invalid(format!(
+                "invalid username `{}`, it must not be empty or contain whitespace or `>`",
+                username
+            )));
+        }
+
+        let addr = self.addr.unwrap_or_else(|| DEFAULT_ADDR.to_owned());
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => (),
+            _ => {
+                return Err(ConfigError::Invalid(format!(
+                    "invalid server address `{}`, expected host:port",
+                    addr
+                )))
+            }
+        }
+
+        Ok(ClientConfig {
+            username,
+            addr,
+            device: self.device.unwrap_or(0),
+            data_dir: self.data_dir,
+            suite: match self.suite {
+                Some(suite) => suite.parse().map_err(ConfigError::Invalid)?,
+                None => Suite::default(),
+            },
+            padding: match self.padding {
+                Some(padding) => padding.parse().map_err(ConfigError::Invalid)?,
+                None => DEFAULT_PADDING,
+            },
+            sealed_sender: self.sealed_sender.unwrap_or(true),
+            tui: self.tui.unwrap_or(false),
+            log_format: self.log_format.unwrap_or_default(),
+            max_line_length: at_least_one(
+                "max_line_length",
+                self.max_line_length,
+                DEFAULT_MAX_LINE_LENGTH,
+            )?,
+            report_blocks: self.report_blocks.unwrap_or(false),
+        })
+    }
+}
@@ -0,0 +1,293 @@
+//! The client's connection to the relay. Requests written while it is down are queued until
+//! it is back, attempts to reconnect back off, and what the outbox holds is sent again.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::future::{self, Future};
+use std::io;
+use std::time::Duration;
+
+use futures::SinkExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+use crate::message::{Challenge, Msg, Request};
+use crate::outbox::{Entry, Outbox, Status};
+
+/// Bounds of the delay between attempts to reconnect to the server.
+pub const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// How long the server may take to send its challenge after accepting the connection.
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opens a stream to the server, once for every attempt to connect.
+pub trait Dial {
+    type Stream: AsyncRead + AsyncWrite + Unpin;
+
+    fn dial(&self) -> impl Future<Output = io::Result<Self::Stream>>;
+}
+
+/// The address of the server.
+impl Dial for String {
+    type Stream = TcpStream;
+
+    fn dial(&self) -> impl Future<Output = io::Result<TcpStream>> {
+        TcpStream::connect(self.as_str())
+    }
+}
+
+/// Anything that opens a stream, such as a host of a `netsim::Network` connecting to another.
+impl<F, Fut, S> Dial for F
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Stream = S;
+
+    fn dial(&self) -> impl Future<Output = io::Result<S>> {
+        self()
+    }
+}
+
+// names a request in what the server's answers are logged with
+fn describe(msg: &Msg) -> String {
+    match msg {
+        Msg::EncryptedMessage(m) => {
+            format!("message to {} (device {})", m.recv_name, m.recv_device)
+        }
+        Msg::SealedMessage(m) => format!("message to {} (device {})", m.recv_name, m.recv_device),
+        Msg::SessionReset(m) => format!(
+            "session reset for {} (device {})",
+            m.recv_name, m.recv_device
+        ),
+        Msg::PreKeyRequest(d) => format!("prekeys of {} (device {})", d.user, d.device_id),
+        Msg::LinkDevice(d) => format!("linking device {}", d.device_id),
+        Msg::UnlinkDevice(d) => format!("unlinking device {}", d.device_id),
+        Msg::Register(_) => "registration".to_owned(),
+        Msg::PubKey(_) => "announced key".to_owned(),
+        Msg::PreKeyBundle(_) => "published prekeys".to_owned(),
+        Msg::RotateKey(_) => "key rotation".to_owned(),
+        Msg::AccessKey(_) => "access key".to_owned(),
+        Msg::ServerInfoRequest => "server info".to_owned(),
+        Msg::ListUsers(_) => "user list".to_owned(),
+        Msg::KeyRequest(user) => format!("keys of {}", user),
+        Msg::Block(user) => format!("blocking {}", user),
+        Msg::Unblock(user) => format!("unblocking {}", user),
+        _ => "request".to_owned(),
+    }
+}
+
+/// A request as it is written to the server.
+pub struct Outgoing {
+    pub id: u64,
+    // what the answer to it is logged with
+    pub what: String,
+    pub line: String,
+}
+
+impl Outgoing {
+    /// An entry of the outbox, sent again with the id it was sent with before.
+    pub fn again(entry: &Entry) -> Self {
+        Outgoing {
+            id: entry.id,
+            what: entry.describe(),
+            line: entry.line.clone(),
+        }
+    }
+}
+
+/// The connection to the server, requests sent while it is down are queued until it is back.
+/// Encrypted messages and session resets are not queued here, they are sent again from the
+/// `Outbox`.
+pub struct Connection<D: Dial> {
+    dial: D,
+    max_line_length: usize,
+    lines: Option<Framed<D::Stream, LinesCodec>>,
+    queue: VecDeque<Outgoing>,
+    // requests written on this connection that were not answered yet, in order
+    requests: VecDeque<(u64, String)>,
+    next_id: u64,
+    // doubled after every attempt until the server accepts us again
+    backoff: Duration,
+    retry_at: Instant,
+}
+
+impl<D: Dial> Connection<D> {
+    pub fn new(dial: D, max_line_length: usize) -> Self {
+        Connection {
+            dial,
+            max_line_length,
+            lines: None,
+            queue: VecDeque::new(),
+            requests: VecDeque::new(),
+            next_id: 0,
+            backoff: MIN_RECONNECT_DELAY,
+            retry_at: Instant::now(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.lines.is_some()
+    }
+
+    /// When to try to reconnect after the connection was lost.
+    pub fn retry_at(&self) -> Instant {
+        self.retry_at
+    }
+
+    pub fn request(&mut self, msg: Msg) -> Outgoing {
+        self.next_id += 1;
+        Outgoing {
+            id: self.next_id,
+            what: describe(&msg),
+            line: serde_json::to_string(&Msg::Request(Request::new(self.next_id, msg))).unwrap(),
+        }
+    }
+
+    /// Answers the server's challenge with `handshake`, then sends whatever piled up in the
+    /// queue.
+    pub async fn connect(
+        &mut self,
+        handshake: impl FnOnce(&Challenge) -> Vec<Msg>,
+    ) -> Result<(), Box<dyn Error>> {
+        let stream = self.dial.dial().await?;
+        let mut lines = Framed::new(
+            stream,
+            LinesCodec::new_with_max_length(self.max_line_length),
+        );
+        let challenge = match tokio::time::timeout(CHALLENGE_TIMEOUT, lines.next()).await {
+            Ok(Some(Ok(line))) => match serde_json::from_str(&line)? {
+                Msg::Challenge(challenge) => challenge,
+                Msg::Err(e) => return Err(format!("server refused the connection: {:?}", e).into()),
+                msg => return Err(format!("server did not send a challenge: {:?}", msg).into()),
+            },
+            Ok(Some(Err(e))) => return Err(e.into()),
+            Ok(None) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        };
+        self.lines = Some(lines);
+        // whatever was not answered on the last connection never will be
+        self.requests.clear();
+        for msg in handshake(&challenge) {
+            let request = self.request(msg);
+            if let Err(e) = self.write(&request).await {
+                self.lines = None;
+                return Err(e.into());
+            }
+        }
+        while let Some(request) = self.queue.pop_front() {
+            if let Err(e) = self.write(&request).await {
+                self.lines = None;
+                self.queue.push_front(request);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn disconnected(&mut self) {
+        self.lines = None;
+        self.retry_at = Instant::now() + self.backoff;
+        tracing::info!(
+            "not connected to the server, retrying in {}s",
+            self.backoff.as_secs()
+        );
+        self.backoff = (self.backoff * 2).min(MAX_RECONNECT_DELAY);
+    }
+
+    /// The server accepted us. One that turns us away right after connecting is not retried
+    /// any faster.
+    pub fn registered(&mut self) {
+        self.backoff = MIN_RECONNECT_DELAY;
+    }
+
+    async fn write(&mut self, request: &Outgoing) -> Result<(), LinesCodecError> {
+        let lines = match &mut self.lines {
+            Some(lines) => lines,
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+        };
+        lines.send(&request.line).await?;
+        self.requests.push_back((request.id, request.what.clone()));
+        Ok(())
+    }
+
+    // returns whether `request` was written, a failed write drops the connection
+    async fn try_write(&mut self, request: &Outgoing) -> bool {
+        if !self.is_connected() {
+            return false;
+        }
+        match self.write(request).await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("failed to send message to the server; error = {}", e);
+                self.disconnected();
+                false
+            }
+        }
+    }
+
+    /// Returns the id the request is answered with.
+    pub async fn send(&mut self, msg: Msg) -> u64 {
+        let request = self.request(msg);
+        let id = request.id;
+        if !self.try_write(&request).await {
+            self.queue.push_back(request);
+        }
+        id
+    }
+
+    /// Sends an entry of the outbox, it is sent again after reconnecting if that fails.
+    pub async fn send_tracked(&mut self, outbox: &mut Outbox, request: Outgoing) {
+        if self.try_write(&request).await {
+            outbox.mark_sent(request.id);
+        }
+    }
+
+    /// Sends every entry of the outbox again after reconnecting, the server may have taken what
+    /// it did not acknowledge or not.
+    pub async fn replay(&mut self, outbox: &mut Outbox) {
+        let unacknowledged = outbox
+            .entries()
+            .iter()
+            .map(Outgoing::again)
+            .collect::<Vec<_>>();
+        for request in unacknowledged {
+            self.send_tracked(outbox, request).await;
+        }
+    }
+
+    /// Sends the entries the server refused for the moment again.
+    pub async fn resend_pending(&mut self, outbox: &mut Outbox) {
+        let refused = outbox
+            .entries()
+            .iter()
+            .filter(|x| x.status == Status::Pending)
+            .map(Outgoing::again)
+            .collect::<Vec<_>>();
+        for request in refused {
+            self.send_tracked(outbox, request).await;
+        }
+    }
+
+    /// The request an answer is for, answers without an id are for the oldest one.
+    pub fn answered(&mut self, id: Option<u64>) -> Option<(u64, String)> {
+        let i = match id {
+            Some(id) => self.requests.iter().position(|x| x.0 == id)?,
+            None => 0,
+        };
+        self.requests.remove(i)
+    }
+
+    /// Never finishes while disconnected.
+    pub async fn next(&mut self) -> Option<Result<String, LinesCodecError>> {
+        match &mut self.lines {
+            Some(lines) => lines.next().await,
+            None => future::pending().await,
+        }
+    }
+}
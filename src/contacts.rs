@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Standing {
+    /// Messages are shown and replies sent.
+    Accepted,
+    /// Wrote to us first. Their messages are shown, replies wait until the request is accepted.
+    Requested,
+    /// Their messages are dropped without being shown.
+    Blocked,
+}
+
+/// What this device thinks of the users it heard from or wrote to, keyed by name.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Contacts {
+    users: HashMap<String, Standing>,
+}
+
+impl Contacts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `None` for users we never heard from.
+    pub fn standing(&self, user: &str) -> Option<Standing> {
+        self.users.get(user).copied()
+    }
+
+    pub fn is_blocked(&self, user: &str) -> bool {
+        self.standing(user) == Some(Standing::Blocked)
+    }
+
+    /// Whether replies to `user` may be sent, users we never heard from may be written to first.
+    pub fn may_send(&self, user: &str) -> bool {
+        matches!(self.standing(user), None | Some(Standing::Accepted))
+    }
+
+    /// Takes a message from `user`, returns `true` if it is the first one and opens a request.
+    pub fn received(&mut self, user: &str) -> bool {
+        if self.users.contains_key(user) {
+            return false;
+        }
+        self.users.insert(user.to_owned(), Standing::Requested);
+        true
+    }
+
+    /// Makes `user` a contact, whether they asked first or not.
+    pub fn accept(&mut self, user: &str) {
+        self.users.insert(user.to_owned(), Standing::Accepted);
+    }
+
+    pub fn block(&mut self, user: &str) {
+        self.users.insert(user.to_owned(), Standing::Blocked);
+    }
+
+    /// Forgets a blocked user, their next message is a request again. Returns `false` if they
+    /// were not blocked.
+    pub fn unblock(&mut self, user: &str) -> bool {
+        if !self.is_blocked(user) {
+            return false;
+        }
+        self.users.remove(user);
+        true
+    }
+
+    /// Every user with the given standing, sorted.
+    pub fn with_standing(&self, standing: Standing) -> Vec<&str> {
+        let mut users = self
+            .users
+            .iter()
+            .filter(|(_, x)| **x == standing)
+            .map(|(user, _)| user.as_str())
+            .collect::<Vec<_>>();
+        users.sort();
+        users
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_contact_is_a_request() {
+        let mut contacts = Contacts::new();
+        assert!(contacts.may_send("bob"));
+
+        assert!(contacts.received("bob"));
+        assert!(!contacts.received("bob"));
+        assert_eq!(contacts.standing("bob"), Some(Standing::Requested));
+        assert!(!contacts.may_send("bob"));
+
+        contacts.accept("bob");
+        assert!(contacts.may_send("bob"));
+        // later messages do not open a new request
+        assert!(!contacts.received("bob"));
+        assert_eq!(contacts.with_standing(Standing::Accepted), ["bob"]);
+    }
+
+    #[test]
+    fn unblocking_forgets() {
+        let mut contacts = Contacts::new();
+        contacts.accept("bob");
+        assert!(!contacts.unblock("bob"));
+
+        contacts.block("bob");
+        assert!(contacts.is_blocked("bob"));
+        assert!(!contacts.may_send("bob"));
+        assert!(!contacts.received("bob"));
+
+        assert!(contacts.unblock("bob"));
+        assert_eq!(contacts.standing("bob"), None);
+        assert!(contacts.received("bob"));
+    }
+}
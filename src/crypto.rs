@@ -1,9 +1,27 @@
+use std::fmt;
+use std::str::FromStr;
+
+use aes_gcm::Aes256Gcm;
+use aes_siv::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128SivAead,
+};
+use chacha20poly1305::{aead::NewAead, ChaCha20Poly1305};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 use hex_literal::hex;
 use hkdf::Hkdf;
-use rand_core::OsRng;
-use serde::{Deserialize, Serialize};
-use sha2::Sha256;
-use x25519_dalek::{PublicKey, StaticSecret};
+use hmac::{Hmac, Mac};
+use ml_kem::kem::{Decapsulate, DecapsulationKey, EncapsulationKey};
+use ml_kem::{
+    Ciphertext, EncapsulateDeterministic, EncodedSizeUser, KemCore, MlKem768, MlKem768Params, B32,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Sha256, Sha512};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::padding::Padding;
 
 use StaticSecret as PrivateKey;
 
@@ -13,6 +31,282 @@ pub struct KeyPair {
     public: PublicKey,
 }
 
+/// Long-term ed25519 key of a device, used to sign the keys it announces.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SigningKeyPair {
+    #[serde(with = "keypair_bytes")]
+    keypair: SigningKey,
+}
+
+// the 64 bytes of secret and public key, as the key pairs of ed25519-dalek 1 were stored
+mod keypair_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(key: &SigningKey, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&key.to_keypair_bytes())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SigningKey, D::Error> {
+        let bytes = Zeroizing::new(Vec::<u8>::deserialize(deserializer)?);
+        let bytes: &[u8; 64] = bytes[..]
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("an ed25519 key pair has 64 bytes"))?;
+        SigningKey::from_keypair_bytes(bytes).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Key derivation used by a session's ratchet.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KdfMode {
+    /// The original derivation of this crate, kept so existing sessions keep working.
+    #[default]
+    Legacy,
+    /// As in the Signal Double Ratchet specification: HKDF-SHA256 keyed with the root key
+    /// and `ROOT_KDF_INFO` for root steps, HMAC-SHA256 with 0x01/0x02 for chain steps.
+    Spec,
+}
+
+impl KdfMode {
+    pub fn root_key(
+        &self,
+        suite: &dyn CipherSuite,
+        root_key: &RootKey,
+        private_key: &PrivateKey,
+        public_key: &PublicKey,
+    ) -> (RootKey, ChainKey) {
+        let dh_out = suite.dh(private_key, public_key);
+        match self {
+            KdfMode::Legacy => {
+                let mut okm = [0u8; 64];
+                suite.hkdf(Some(&dh_out), &root_key.key, &LEGACY_INFO, &mut okm);
+                split_root_okm(okm)
+            }
+            KdfMode::Spec => kdf_rk_with(suite, root_key, &dh_out),
+        }
+    }
+
+    pub fn chain_key(&self, suite: &dyn CipherSuite, chain_key: &ChainKey) -> (ChainKey, [u8; 32]) {
+        match self {
+            KdfMode::Legacy => {
+                let mut okm = [0u8; 64];
+                suite.hkdf(None, &chain_key.key, &LEGACY_INFO, &mut okm);
+                (
+                    ChainKey {
+                        key: okm[0..32].try_into().unwrap(),
+                        count: 0,
+                    },
+                    okm[32..64].try_into().unwrap(),
+                )
+            }
+            KdfMode::Spec => kdf_ck_with(suite, chain_key),
+        }
+    }
+}
+
+/// Primitives a session's ratchet is built from: the DH function, the hash behind its KDFs
+/// and the AEAD messages are encrypted with.
+pub trait CipherSuite {
+    fn dh(&self, private_key: &PrivateKey, public_key: &PublicKey) -> [u8; 32] {
+        private_key.diffie_hellman(public_key).to_bytes()
+    }
+
+    /// HKDF with the suite's hash, fills all of `okm`.
+    fn hkdf(&self, salt: Option<&[u8]>, ikm: &[u8], info: &[u8], okm: &mut [u8]);
+
+    /// HMAC with the suite's hash, truncated to 32 bytes.
+    fn hmac(&self, key: &[u8], data: &[u8]) -> [u8; 32];
+
+    /// Encrypts with a message key and authenticates `aad` along with it. Every message key is
+    /// used for a single message only, which is why the AEADs below get away with a fixed nonce.
+    fn seal(&self, key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8>;
+
+    /// Decrypts with a message key, `None` if the ciphertext or `aad` does not authenticate.
+    fn open(&self, key: &[u8; 32], ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// X25519, SHA-256 and AES-128-SIV, what sessions used before suites could be chosen.
+pub struct Aes128SivSha256;
+
+/// X25519, SHA-512 and AES-256-GCM.
+pub struct Aes256GcmSha512;
+
+/// X25519, SHA-256 and ChaCha20-Poly1305.
+pub struct ChaCha20Poly1305Sha256;
+
+impl CipherSuite for Aes128SivSha256 {
+    fn hkdf(&self, salt: Option<&[u8]>, ikm: &[u8], info: &[u8], okm: &mut [u8]) {
+        Hkdf::<Sha256>::new(salt, ikm).expand(info, okm).expect(" ");
+    }
+
+    fn hmac(&self, key: &[u8], data: &[u8]) -> [u8; 32] {
+        hmac_sha256(key, data)
+    }
+
+    fn seal(&self, key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let cipher = Aes128SivAead::new_from_slice(key).unwrap();
+        cipher
+            .encrypt(
+                aes_siv::Nonce::from_slice(b"any unique nonce"),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .unwrap()
+    }
+
+    fn open(&self, key: &[u8; 32], ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let cipher = Aes128SivAead::new_from_slice(key).unwrap();
+        cipher
+            .decrypt(
+                aes_siv::Nonce::from_slice(b"any unique nonce"),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
+impl CipherSuite for Aes256GcmSha512 {
+    fn hkdf(&self, salt: Option<&[u8]>, ikm: &[u8], info: &[u8], okm: &mut [u8]) {
+        Hkdf::<Sha512>::new(salt, ikm).expand(info, okm).expect(" ");
+    }
+
+    fn hmac(&self, key: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut mac =
+            <Hmac<Sha512> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+        mac.update(data);
+        mac.finalize().into_bytes()[..32].try_into().unwrap()
+    }
+
+    fn seal(&self, key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+        cipher
+            .encrypt(
+                aes_gcm::Nonce::from_slice(&[0u8; 12]),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .unwrap()
+    }
+
+    fn open(&self, key: &[u8; 32], ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+        cipher
+            .decrypt(
+                aes_gcm::Nonce::from_slice(&[0u8; 12]),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
+impl CipherSuite for ChaCha20Poly1305Sha256 {
+    fn hkdf(&self, salt: Option<&[u8]>, ikm: &[u8], info: &[u8], okm: &mut [u8]) {
+        Hkdf::<Sha256>::new(salt, ikm).expand(info, okm).expect(" ");
+    }
+
+    fn hmac(&self, key: &[u8], data: &[u8]) -> [u8; 32] {
+        hmac_sha256(key, data)
+    }
+
+    fn seal(&self, key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let cipher = ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key));
+        cipher
+            .encrypt(
+                chacha20poly1305::Nonce::from_slice(&[0u8; 12]),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .unwrap()
+    }
+
+    fn open(&self, key: &[u8; 32], ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let cipher = ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key));
+        cipher
+            .decrypt(
+                chacha20poly1305::Nonce::from_slice(&[0u8; 12]),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
+/// Cipher suite of a session, stored with its state and sent along with every message so
+/// both sides can tell they agree.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Suite {
+    #[default]
+    Aes128Siv,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+    /// AES-256-GCM sessions that are only ever started with `pqxdh_initiate`/`pqxdh_respond`.
+    MlKem768Aes256Gcm,
+}
+
+const SUITES: [Suite; 4] = [
+    Suite::Aes128Siv,
+    Suite::Aes256Gcm,
+    Suite::ChaCha20Poly1305,
+    Suite::MlKem768Aes256Gcm,
+];
+
+impl Suite {
+    pub fn cipher(&self) -> &'static dyn CipherSuite {
+        match self {
+            Suite::Aes128Siv => &Aes128SivSha256,
+            Suite::Aes256Gcm => &Aes256GcmSha512,
+            Suite::ChaCha20Poly1305 => &ChaCha20Poly1305Sha256,
+            Suite::MlKem768Aes256Gcm => &Aes256GcmSha512,
+        }
+    }
+
+    /// Whether sessions have to be set up with the hybrid X25519 + ML-KEM-768 agreement.
+    pub fn post_quantum(&self) -> bool {
+        matches!(self, Suite::MlKem768Aes256Gcm)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Suite::Aes128Siv => "aes128-siv",
+            Suite::Aes256Gcm => "aes256-gcm",
+            Suite::ChaCha20Poly1305 => "chacha20-poly1305",
+            Suite::MlKem768Aes256Gcm => "mlkem768-aes256-gcm",
+        }
+    }
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Suite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SUITES
+            .into_iter()
+            .find(|x| x.name() == s)
+            .ok_or_else(|| format!("unknown cipher suite {}", s))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct State {
     pub key_pair: KeyPair,
@@ -21,6 +315,26 @@ pub struct State {
     pub chain_send: Option<ChainKey>,
     pub chain_recv: Option<ChainKey>,
     pub pn: u32,
+    #[serde(default)]
+    pub kdf: KdfMode,
+    #[serde(default)]
+    pub suite: Suite,
+    #[serde(default)]
+    pub padding: Padding,
+    // keys of messages that were skipped over, kept until they arrive late
+    #[serde(default)]
+    pub skipped: Vec<SkippedKey>,
+    // ratchet keys of the other side's earlier chains, the newest last
+    #[serde(default)]
+    pub previous_chains: Vec<PublicKey>,
+}
+
+/// Message key of a message that has not arrived yet while later ones of its chain did.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SkippedKey {
+    pub dh_pub: PublicKey,
+    pub count: u32,
+    pub key: [u8; 32],
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -54,11 +368,17 @@ impl ChainKey {
     pub fn inc_count(&mut self) {
         self.count += 1;
     }
+
+    /// The same key, as the `count`th of its chain.
+    pub fn counted(mut self, count: u32) -> Self {
+        self.count = count;
+        self
+    }
 }
 
 impl KeyPair {
     pub fn new() -> Self {
-        let private = PrivateKey::new(OsRng);
+        let private = PrivateKey::random_from_rng(OsRng);
         let public = PublicKey::from(&private);
         KeyPair { private, public }
     }
@@ -72,6 +392,48 @@ impl KeyPair {
     }
 }
 
+impl SigningKeyPair {
+    pub fn new() -> Self {
+        SigningKeyPair {
+            keypair: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn public(&self) -> [u8; 32] {
+        self.keypair.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        self.keypair.sign(msg).to_bytes().to_vec()
+    }
+}
+
+impl From<[u8; 32]> for SigningKeyPair {
+    fn from(secret: [u8; 32]) -> Self {
+        SigningKeyPair {
+            keypair: SigningKey::from_bytes(&secret),
+        }
+    }
+}
+
+impl Default for SigningKeyPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks that `signature` was made over `msg` by the owner of the ed25519 key `public`.
+pub fn verify_signature(public: &[u8; 32], msg: &[u8], signature: &[u8]) -> bool {
+    let public = match VerifyingKey::from_bytes(public) {
+        Ok(public) => public,
+        Err(_) => return false,
+    };
+    match Signature::from_slice(signature) {
+        Ok(signature) => public.verify_strict(msg, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
 impl Default for KeyPair {
     fn default() -> Self {
         Self::new()
@@ -96,6 +458,12 @@ impl From<[u8; 32]> for ChainKey {
     }
 }
 
+impl RootKey {
+    pub fn key(&self) -> [u8; 32] {
+        self.key
+    }
+}
+
 impl From<[u8; 32]> for RootKey {
     fn from(bytes: [u8; 32]) -> RootKey {
         RootKey { key: bytes }
@@ -113,9 +481,58 @@ impl State {
             chain_send: None,
             chain_recv: None,
             pn: 0,
+            kdf: KdfMode::default(),
+            suite: Suite::default(),
+            padding: Padding::default(),
+            skipped: Vec::new(),
+            previous_chains: Vec::new(),
+        }
+    }
+
+    pub fn with_kdf(mut self, kdf: KdfMode) -> State {
+        self.kdf = kdf;
+        self
+    }
+
+    /// Session on top of a root key agreed on out of band, e.g. by `x3dh_initiate`/`x3dh_respond`.
+    pub fn from_root(my_keys: KeyPair, other_pub_key: PublicKey, root_key: RootKey) -> State {
+        State {
+            key_pair: my_keys,
+            dh_pub: Some(other_pub_key),
+            root_key,
+            chain_send: None,
+            chain_recv: None,
+            pn: 0,
+            kdf: KdfMode::default(),
+            suite: Suite::default(),
+            padding: Padding::default(),
+            skipped: Vec::new(),
+            previous_chains: Vec::new(),
         }
     }
 
+    pub fn with_suite(mut self, suite: Suite) -> State {
+        self.suite = suite;
+        self
+    }
+
+    pub fn with_padding(mut self, padding: Padding) -> State {
+        self.padding = padding;
+        self
+    }
+
+    pub fn kdf(&self) -> KdfMode {
+        self.kdf
+    }
+
+    pub fn suite(&self) -> Suite {
+        self.suite
+    }
+
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
     pub fn key_pair(&self) -> &KeyPair {
         &self.key_pair
     }
@@ -145,19 +562,232 @@ impl State {
     }
 }
 
-pub fn kdf_root_key(
-    root_key: &RootKey,
-    private_key: &PrivateKey,
-    public_key: &PublicKey,
-) -> (RootKey, ChainKey) {
-    let shared_secret = private_key.diffie_hellman(public_key);
+// the DH outputs and the KEM secret are zeroized once they are dropped, as is the key material
+fn kdf_x3dh(dh: [SharedSecret; 3], kem: Option<&Zeroizing<[u8; 32]>>) -> RootKey {
+    // 32 0xff bytes in front of the key material, as in X3DH
+    let mut ikm = Zeroizing::new(Vec::with_capacity(32 * 5));
+    ikm.extend_from_slice(&[0xffu8; 32]);
+    for x in &dh {
+        ikm.extend_from_slice(x.as_bytes());
+    }
+    // the KEM secret goes last, as in PQXDH
+    let info: &[u8] = match kem {
+        Some(ss) => {
+            ikm.extend_from_slice(&ss[..]);
+            b"lib-sig pqxdh"
+        }
+        None => b"lib-sig x3dh",
+    };
 
-    let info = hex!("fee1dead"); // some random hex constant is required
-    let mut okm = [0u8; 64];
-    Hkdf::<Sha256>::new(Some(shared_secret.as_bytes()), &root_key.key)
-        .expand(&info, &mut okm)
+    let mut okm = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm)
+        .expand(info, &mut okm)
         .expect(" ");
 
+    RootKey::from(okm)
+}
+
+/// Root key of a new session, computed by the side starting it from the other side's
+/// identity key and signed prekey and a fresh ephemeral key.
+pub fn x3dh_initiate(
+    identity: &KeyPair,
+    ephemeral: &KeyPair,
+    their_identity: &PublicKey,
+    their_prekey: &PublicKey,
+) -> RootKey {
+    kdf_x3dh(
+        [
+            identity.private().diffie_hellman(their_prekey),
+            ephemeral.private().diffie_hellman(their_identity),
+            ephemeral.private().diffie_hellman(their_prekey),
+        ],
+        None,
+    )
+}
+
+/// Root key of a new session, computed by the side that was sent the initiator's
+/// identity and ephemeral keys.
+pub fn x3dh_respond(
+    identity: &KeyPair,
+    prekey: &KeyPair,
+    their_identity: &PublicKey,
+    their_ephemeral: &PublicKey,
+) -> RootKey {
+    kdf_x3dh(
+        [
+            prekey.private().diffie_hellman(their_identity),
+            identity.private().diffie_hellman(their_ephemeral),
+            prekey.private().diffie_hellman(their_ephemeral),
+        ],
+        None,
+    )
+}
+
+/// `x3dh_initiate` that also encapsulates a secret to the other side's ML-KEM-768 prekey, so the
+/// root key stays secret unless both X25519 and ML-KEM are broken. Returns the root key with the
+/// ciphertext to send along, `None` if the ML-KEM prekey is malformed.
+pub fn pqxdh_initiate(
+    identity: &KeyPair,
+    ephemeral: &KeyPair,
+    their_identity: &PublicKey,
+    their_prekey: &PublicKey,
+    their_pq_prekey: &[u8],
+) -> Option<(RootKey, Vec<u8>)> {
+    let (ss, ciphertext) = mlkem_encapsulate(their_pq_prekey)?;
+    let root = kdf_x3dh(
+        [
+            identity.private().diffie_hellman(their_prekey),
+            ephemeral.private().diffie_hellman(their_identity),
+            ephemeral.private().diffie_hellman(their_prekey),
+        ],
+        Some(&ss),
+    );
+    Some((root, ciphertext))
+}
+
+/// Counterpart of `pqxdh_initiate`, `None` if the ciphertext is malformed.
+pub fn pqxdh_respond(
+    identity: &KeyPair,
+    prekey: &KeyPair,
+    pq_prekey: &MlKemKeyPair,
+    their_identity: &PublicKey,
+    their_ephemeral: &PublicKey,
+    ciphertext: &[u8],
+) -> Option<RootKey> {
+    let ss = pq_prekey.decapsulate(ciphertext)?;
+    Some(kdf_x3dh(
+        [
+            prekey.private().diffie_hellman(their_identity),
+            identity.private().diffie_hellman(their_ephemeral),
+            prekey.private().diffie_hellman(their_ephemeral),
+        ],
+        Some(&ss),
+    ))
+}
+
+/// Length of an ML-KEM-768 encapsulation key.
+pub const MLKEM_PUBLIC_KEY_LEN: usize = 1184;
+/// Length of an ML-KEM-768 ciphertext.
+pub const MLKEM_CIPHERTEXT_LEN: usize = 1088;
+
+/// ML-KEM-768 decapsulation key of a post-quantum prekey, zeroized on drop.
+#[derive(Clone)]
+pub struct MlKemKeyPair {
+    dk: DecapsulationKey<MlKem768Params>,
+}
+
+impl MlKemKeyPair {
+    pub fn new() -> Self {
+        let (dk, _) = MlKem768::generate(&mut OsRng);
+        MlKemKeyPair { dk }
+    }
+
+    /// Key pair from the 64 byte seed `d || z` of FIPS 203.
+    pub fn from_seed(seed: &[u8; 64]) -> Self {
+        let d = B32::try_from(&seed[..32]).unwrap();
+        let z = B32::try_from(&seed[32..]).unwrap();
+        let (dk, _) = MlKem768::generate_deterministic(&d, &z);
+        MlKemKeyPair { dk }
+    }
+
+    /// The encapsulation key, `MLKEM_PUBLIC_KEY_LEN` bytes.
+    pub fn public(&self) -> Vec<u8> {
+        self.dk.encapsulation_key().as_bytes().to_vec()
+    }
+
+    /// Shared secret of a ciphertext, `None` if it has the wrong length. A ciphertext that was
+    /// tampered with decapsulates to an unrelated secret instead of failing.
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> Option<Zeroizing<[u8; 32]>> {
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext).ok()?;
+        let ss = self.dk.decapsulate(&ciphertext).ok()?;
+        Some(shared_secret(ss))
+    }
+}
+
+impl Default for MlKemKeyPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encapsulates a fresh shared secret to an ML-KEM-768 encapsulation key, returning it with the
+/// ciphertext to send. `None` if the key is malformed.
+pub fn mlkem_encapsulate(ek: &[u8]) -> Option<(Zeroizing<[u8; 32]>, Vec<u8>)> {
+    let mut m = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut *m);
+    mlkem_encapsulate_deterministic(ek, &m)
+}
+
+/// `mlkem_encapsulate` with the randomness `m` given instead of drawn, for known answer tests.
+/// The secret is only as good as `m`, which must never be used twice.
+pub fn mlkem_encapsulate_deterministic(
+    ek: &[u8],
+    m: &[u8; 32],
+) -> Option<(Zeroizing<[u8; 32]>, Vec<u8>)> {
+    let encoded = ek.try_into().ok()?;
+    let key = EncapsulationKey::<MlKem768Params>::from_bytes(encoded);
+    // decoding reduces every coefficient, a key that had unreduced ones fails the input check
+    // of FIPS 203
+    if key.as_bytes() != *encoded {
+        return None;
+    }
+    let mut m = B32::from(*m);
+    let (ciphertext, ss) = key.encapsulate_deterministic(&m).ok()?;
+    m.zeroize();
+    Some((shared_secret(ss), ciphertext.to_vec()))
+}
+
+fn shared_secret(mut ss: B32) -> Zeroizing<[u8; 32]> {
+    let secret = Zeroizing::new(ss.into());
+    ss.zeroize();
+    secret
+}
+
+// the envelope is always AES-256-GCM, whatever suite the session inside it uses
+const SEALED_SENDER_INFO: &[u8] = b"lib-sig sealed sender";
+
+fn sealed_sender_key(dh: [u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+    let mut okm = [0u8; 32];
+    Aes256GcmSha512.hkdf(Some(&salt), &dh, SEALED_SENDER_INFO, &mut okm);
+    okm
+}
+
+/// Encrypts `plaintext` to the identity key `recipient` under a fresh ephemeral key, which is
+/// returned together with the ciphertext.
+pub fn seal_to(recipient: &PublicKey, plaintext: &[u8]) -> (PublicKey, Vec<u8>) {
+    let ephemeral = KeyPair::new();
+    let dh = ephemeral.private().diffie_hellman(recipient).to_bytes();
+    let key = sealed_sender_key(dh, &ephemeral.public(), recipient);
+    (
+        ephemeral.public(),
+        Aes256GcmSha512.seal(&key, plaintext, &[]),
+    )
+}
+
+/// Counterpart of `seal_to`, `None` if the ciphertext does not authenticate.
+pub fn open_sealed(
+    identity: &KeyPair,
+    ephemeral: &PublicKey,
+    ciphertext: &[u8],
+) -> Option<Vec<u8>> {
+    let dh = identity.private().diffie_hellman(ephemeral).to_bytes();
+    let key = sealed_sender_key(dh, ephemeral, &identity.public());
+    Aes256GcmSha512.open(&key, ciphertext, &[])
+}
+
+/// What the relay keeps instead of an access key, so it can check delivery tokens without
+/// being able to produce them.
+pub fn access_key_hash(access_key: &[u8; 32]) -> [u8; 32] {
+    use sha2::Digest;
+    Sha256::digest(access_key).into()
+}
+
+// some random hex constant is required
+const LEGACY_INFO: [u8; 4] = hex!("fee1dead");
+
+fn split_root_okm(okm: [u8; 64]) -> (RootKey, ChainKey) {
     (
         RootKey {
             key: okm[..32].try_into().unwrap(),
@@ -169,19 +799,55 @@ pub fn kdf_root_key(
     )
 }
 
+pub fn kdf_root_key(
+    root_key: &RootKey,
+    private_key: &PrivateKey,
+    public_key: &PublicKey,
+) -> (RootKey, ChainKey) {
+    KdfMode::Legacy.root_key(&Aes128SivSha256, root_key, private_key, public_key)
+}
+
 pub fn kdf_chain_key(shared_secret: &ChainKey) -> (ChainKey, [u8; 32]) {
-    let info = hex!("fee1dead");
+    KdfMode::Legacy.chain_key(&Aes128SivSha256, shared_secret)
+}
+
+/// HKDF info of root steps in `KdfMode::Spec`, the same label as libsignal.
+pub const ROOT_KDF_INFO: &[u8] = b"WhisperRatchet";
+
+/// Root step of the specification: HKDF-SHA256 with the root key as salt and the DH output as
+/// input key material, expanded to a new root key and chain key.
+pub fn kdf_rk(root_key: &RootKey, dh_out: &[u8]) -> (RootKey, ChainKey) {
+    kdf_rk_with(&Aes128SivSha256, root_key, dh_out)
+}
 
+fn kdf_rk_with(suite: &dyn CipherSuite, root_key: &RootKey, dh_out: &[u8]) -> (RootKey, ChainKey) {
     let mut okm = [0u8; 64];
-    Hkdf::<Sha256>::new(None, &shared_secret.key())
-        .expand(&info, &mut okm)
-        .expect(" ");
+    suite.hkdf(Some(&root_key.key), dh_out, ROOT_KDF_INFO, &mut okm);
+    split_root_okm(okm)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Chain step of the specification: the message key is HMAC-SHA256(chain key, 0x01),
+/// the next chain key HMAC-SHA256(chain key, 0x02).
+pub fn kdf_ck(chain_key: &ChainKey) -> (ChainKey, [u8; 32]) {
+    kdf_ck_with(&Aes128SivSha256, chain_key)
+}
+
+fn kdf_ck_with(suite: &dyn CipherSuite, chain_key: &ChainKey) -> (ChainKey, [u8; 32]) {
+    let mk = suite.hmac(&chain_key.key, &[0x01]);
+    let next = suite.hmac(&chain_key.key, &[0x02]);
 
     (
         ChainKey {
-            key: okm[0..32].try_into().unwrap(),
-            count: 0,
+            key: next,
+            count: chain_key.count + 1,
         },
-        okm[32..64].try_into().unwrap(),
+        mk,
     )
 }
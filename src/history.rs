@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub from: String,
+    pub msg: String,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    // the id the sender gave the message, copies of it sent again are dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(from: String, msg: String, timestamp: u64, expires_in: Option<u64>) -> Self {
+        Self {
+            from,
+            msg,
+            timestamp,
+            expires_at: expires_in.map(|x| timestamp.saturating_add(x)),
+            id: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: Option<u64>) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(x) if x <= now)
+    }
+}
+
+/// Messages of every conversation, keyed by the name of the other user.
+#[derive(Serialize, Deserialize, Default)]
+pub struct History {
+    conversations: HashMap<String, Vec<Entry>>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, conversation: &str, entry: Entry) {
+        self.conversations
+            .entry(conversation.to_owned())
+            .or_default()
+            .push(entry);
+    }
+
+    pub fn conversation(&self, conversation: &str) -> &[Entry] {
+        self.conversations
+            .get(conversation)
+            .map(|x| x.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Whether `from` already sent a message with this id in the conversation.
+    pub fn contains(&self, conversation: &str, from: &str, id: u64) -> bool {
+        self.conversation(conversation)
+            .iter()
+            .any(|x| x.id == Some(id) && x.from == from)
+    }
+
+    /// Names of every conversation, sorted.
+    pub fn conversations(&self) -> Vec<&str> {
+        let mut names = self
+            .conversations
+            .keys()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// The last `n` messages of a conversation, oldest first.
+    pub fn last(&self, conversation: &str, n: usize) -> &[Entry] {
+        let entries = self.conversation(conversation);
+        &entries[entries.len().saturating_sub(n)..]
+    }
+
+    /// Every message containing `text`, ignoring case, ordered by time.
+    pub fn search(&self, text: &str) -> Vec<(&str, &Entry)> {
+        let text = text.to_lowercase();
+        let mut found = self
+            .conversations
+            .iter()
+            .flat_map(|(conversation, entries)| {
+                entries.iter().map(move |x| (conversation.as_str(), x))
+            })
+            .filter(|(_, x)| x.msg.to_lowercase().contains(&text))
+            .collect::<Vec<_>>();
+        found.sort_by_key(|(_, x)| x.timestamp);
+        found
+    }
+
+    /// Removes and returns every message that expired before `now`, with its conversation.
+    pub fn remove_expired(&mut self, now: u64) -> Vec<(String, Entry)> {
+        let mut expired = Vec::new();
+        for (conversation, entries) in self.conversations.iter_mut() {
+            let (gone, kept) = std::mem::take(entries)
+                .into_iter()
+                .partition(|x| x.is_expired(now));
+            *entries = kept;
+            expired.extend(gone.into_iter().map(|x: Entry| (conversation.clone(), x)));
+        }
+        expired
+    }
+}
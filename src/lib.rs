@@ -1,2 +1,15 @@
+pub mod admin;
+pub mod config;
+pub mod connection;
+pub mod contacts;
 pub mod crypto;
+pub mod history;
 pub mod message;
+pub mod metrics;
+pub mod netsim;
+pub mod outbox;
+pub mod padding;
+pub mod server;
+pub mod session;
+pub mod storage;
+pub mod vault;
@@ -1,37 +1,158 @@
-use aes_siv::{
-    aead::{Aead, KeyInit},
-    Aes128SivAead, Nonce,
-};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 use x25519_dalek::PublicKey;
 
-use crate::crypto::{kdf_chain_key, kdf_root_key, KeyPair, State};
+use crate::crypto::{
+    open_sealed, seal_to, verify_signature, KeyPair, SigningKeyPair, SkippedKey, State, Suite,
+};
+use crate::padding::unpad;
+
+pub type DeviceId = u32;
+
+/// The message could not be decrypted with the session's state.
+pub const ERR_DECRYPT: u32 = 1;
+/// The message decrypted but its content is not valid.
+pub const ERR_MALFORMED: u32 = 2;
+/// The message was encrypted with a different cipher suite than the session uses.
+pub const ERR_SUITE: u32 = 3;
+/// The sender certificate of a sealed message is not valid.
+pub const ERR_CERTIFICATE: u32 = 4;
+/// The message was decrypted before, its key is used up.
+pub const ERR_DUPLICATE: u32 = 5;
+
+/// Sent by the server as soon as a client connects. The registration signs `nonce` with the
+/// device's signing key, so a recorded one cannot be played back on another connection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Challenge {
+    pub nonce: [u8; 32],
+}
+
+impl Challenge {
+    pub fn new() -> Self {
+        Self {
+            nonce: rand::random(),
+        }
+    }
+}
+
+impl Default for Challenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// keeps registration signatures apart from those over keys and bundles
+const REGISTER_CONTEXT: &[u8] = b"lib-sig register";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RegisterMessage {
     pub client_name: String,
+    #[serde(default)]
+    pub device_id: DeviceId,
+    // ed25519 key of the device, kept by the server when the user is registered. Every later
+    // registration of the device has to be signed with it
+    #[serde(default)]
+    pub signing_key: Option<[u8; 32]>,
+    // over the server's challenge, the user and the device by `signing_key`
+    #[serde(default)]
+    pub signature: Vec<u8>,
 }
 
 impl RegisterMessage {
-    pub fn new(client_name: String) -> Self {
-        Self { client_name }
+    pub fn new(client_name: String, device_id: DeviceId) -> Self {
+        Self {
+            client_name,
+            device_id,
+            signing_key: None,
+            signature: Vec::new(),
+        }
+    }
+
+    fn signed_data(&self, challenge: &Challenge) -> Vec<u8> {
+        let mut data = REGISTER_CONTEXT.to_vec();
+        data.extend_from_slice(&challenge.nonce);
+        data.extend_from_slice(self.client_name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(&self.device_id.to_be_bytes());
+        data
+    }
+
+    /// Answers `challenge` with `signing_key`, which is announced along with it.
+    pub fn signed(mut self, challenge: &Challenge, signing_key: &SigningKeyPair) -> Self {
+        self.signing_key = Some(signing_key.public());
+        self.signature = signing_key.sign(&self.signed_data(challenge));
+        self
+    }
+
+    /// Whether `challenge` was signed with `signing_key`.
+    pub fn verify(&self, challenge: &Challenge, signing_key: &[u8; 32]) -> bool {
+        verify_signature(signing_key, &self.signed_data(challenge), &self.signature)
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Message {
     pub sender_name: String,
+    #[serde(default)]
+    pub sender_device: DeviceId,
     pub recv_name: String,
+    #[serde(default)]
+    pub recv_device: DeviceId,
+    // set on copies sent to our own devices, names the user the original was sent to
+    #[serde(default)]
+    pub sent_to: Option<String>,
     pub msg: String,
+    // seconds after which the message disappears, encrypted together with `msg`
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    // lets the recipient send sealed messages back, encrypted together with `msg`
+    #[serde(default)]
+    pub access_key: Option<[u8; 32]>,
+    // random and kept when the message is encrypted again, so the recipient can tell a copy
+    // sent again after a restart from a new message
+    #[serde(default)]
+    pub id: Option<u64>,
     pub public_key: PublicKey,
 }
 
+// what actually gets encrypted, padded as the session says
+#[derive(Serialize, Deserialize, Debug)]
+struct Content {
+    msg: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+    // left out when not set so plain messages keep their length
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    access_key: Option<[u8; 32]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EncryptedMessage {
     pub sender_name: String,
+    #[serde(default)]
+    pub sender_device: DeviceId,
     pub recv_name: String,
+    #[serde(default)]
+    pub recv_device: DeviceId,
+    #[serde(default)]
+    pub sent_to: Option<String>,
+    // seconds the relay may keep the message queued
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    #[serde(default)]
+    pub suite: Suite,
     pub encrypted_msg: Vec<u8>,
+    // ratchet key of the sending chain the message belongs to
     pub public_key: PublicKey,
+    // number of the message in its sending chain
+    #[serde(default)]
+    pub count: u32,
+    // length of the sender's previous sending chain, for the keys of its messages still missing
+    #[serde(default)]
+    pub previous_count: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -59,94 +180,784 @@ impl Info {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PubKey {
     pub user: String,
+    #[serde(default)]
+    pub device_id: DeviceId,
     pub public_key: PublicKey,
+    // ed25519 key that signs `public_key`, its rotations and the prekeys of the device. The
+    // server keeps the first one it sees, peers check prekey bundles against it
+    #[serde(default)]
+    pub signing_key: Option<[u8; 32]>,
+    // over `public_key` by `signing_key`. The server passes it on with the key, so peers check
+    // it as well
+    #[serde(default)]
+    pub signature: Vec<u8>,
+    // cipher suite of the sessions the device starts, the other side adopts it
+    #[serde(default)]
+    pub suite: Suite,
 }
 
 impl PubKey {
-    pub fn new(user: String, public_key: PublicKey) -> Self {
-        Self { user, public_key }
+    pub fn new(user: String, device_id: DeviceId, public_key: PublicKey) -> Self {
+        Self {
+            user,
+            device_id,
+            public_key,
+            signing_key: None,
+            signature: Vec::new(),
+            suite: Suite::default(),
+        }
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = self.user.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&self.device_id.to_be_bytes());
+        data.extend_from_slice(self.public_key.as_bytes());
+        data
+    }
+
+    /// Announces `signing_key` along with the key and signs both.
+    pub fn signed(mut self, signing_key: &SigningKeyPair) -> Self {
+        self.signing_key = Some(signing_key.public());
+        self.signature = signing_key.sign(&self.signed_data());
+        self
+    }
+
+    /// Whether the key is signed with the signing key it announces.
+    pub fn verify(&self) -> bool {
+        match &self.signing_key {
+            Some(key) => verify_signature(key, &self.signed_data(), &self.signature),
+            None => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreKeyBundle {
+    pub user: String,
+    #[serde(default)]
+    pub device_id: DeviceId,
+    pub identity_key: PublicKey,
+    pub signed_prekey: PublicKey,
+    pub prekey_id: u32,
+    // ML-KEM-768 encapsulation key for hybrid session setup, signed together with the rest
+    #[serde(default)]
+    pub pq_prekey: Option<Vec<u8>>,
+    // cipher suite of the sessions the device starts
+    #[serde(default)]
+    pub suite: Suite,
+    #[serde(default)]
+    pub signature: Vec<u8>,
+    // over `identity_key` as a `PubKey` signs it, what peers are handed when the key rotates
+    #[serde(default)]
+    pub key_signature: Vec<u8>,
+}
+
+impl PreKeyBundle {
+    pub fn new(
+        user: String,
+        device_id: DeviceId,
+        identity_key: PublicKey,
+        signed_prekey: PublicKey,
+        prekey_id: u32,
+    ) -> Self {
+        Self {
+            user,
+            device_id,
+            identity_key,
+            signed_prekey,
+            prekey_id,
+            pq_prekey: None,
+            suite: Suite::default(),
+            signature: Vec::new(),
+            key_signature: Vec::new(),
+        }
+    }
+
+    pub fn with_pq_prekey(mut self, pq_prekey: Vec<u8>) -> Self {
+        self.pq_prekey = Some(pq_prekey);
+        self
+    }
+
+    pub fn with_suite(mut self, suite: Suite) -> Self {
+        self.suite = suite;
+        self
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = self.user.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&self.device_id.to_be_bytes());
+        data.extend_from_slice(self.identity_key.as_bytes());
+        data.extend_from_slice(self.signed_prekey.as_bytes());
+        data.extend_from_slice(&self.prekey_id.to_be_bytes());
+        if let Some(pq_prekey) = &self.pq_prekey {
+            data.extend_from_slice(pq_prekey);
+        }
+        data.extend_from_slice(self.suite.name().as_bytes());
+        data
+    }
+
+    /// Signs every field of the bundle with the device's long-term key, and the identity key
+    /// on its own.
+    pub fn signed(mut self, signing_key: &SigningKeyPair) -> Self {
+        self.signature = signing_key.sign(&self.signed_data());
+        self.key_signature = PubKey::new(self.user.clone(), self.device_id, self.identity_key)
+            .signed(signing_key)
+            .signature;
+        self
+    }
+
+    /// The identity key as a `PubKey` signed with `signing_key`.
+    pub fn announced_key(&self, signing_key: [u8; 32]) -> PubKey {
+        PubKey {
+            signing_key: Some(signing_key),
+            signature: self.key_signature.clone(),
+            suite: self.suite,
+            ..PubKey::new(self.user.clone(), self.device_id, self.identity_key)
+        }
+    }
+
+    pub fn verify(&self, signing_key: &[u8; 32]) -> bool {
+        verify_signature(signing_key, &self.signed_data(), &self.signature)
+            && self.announced_key(*signing_key).verify()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Device {
+    pub user: String,
+    pub device_id: DeviceId,
+    // only set when linking, the key the new device has to register with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<[u8; 32]>,
+}
+
+impl Device {
+    pub fn new(user: String, device_id: DeviceId) -> Self {
+        Self {
+            user,
+            device_id,
+            signing_key: None,
+        }
+    }
+
+    /// Names the signing key the device is linked with.
+    pub fn with_signing_key(mut self, signing_key: [u8; 32]) -> Self {
+        self.signing_key = Some(signing_key);
+        self
+    }
+}
+
+/// Vouches that a device announced `identity_key`. Issued by the server and only ever sent
+/// inside sealed messages, where it stands in for the sender name the server no longer sees.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SenderCertificate {
+    pub user: String,
+    pub device_id: DeviceId,
+    pub identity_key: PublicKey,
+    // unix time from which on the certificate is rejected
+    pub expires: u64,
+    // ed25519 key of the server that signed it
+    #[serde(default)]
+    pub server_key: [u8; 32],
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+impl SenderCertificate {
+    pub fn new(user: String, device_id: DeviceId, identity_key: PublicKey, expires: u64) -> Self {
+        Self {
+            user,
+            device_id,
+            identity_key,
+            expires,
+            server_key: [0; 32],
+            signature: Vec::new(),
+        }
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = self.user.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&self.device_id.to_be_bytes());
+        data.extend_from_slice(self.identity_key.as_bytes());
+        data.extend_from_slice(&self.expires.to_be_bytes());
+        data
+    }
+
+    pub fn signed(mut self, server_key: &SigningKeyPair) -> Self {
+        self.server_key = server_key.public();
+        self.signature = server_key.sign(&self.signed_data());
+        self
+    }
+
+    /// Whether `server_key` signed the certificate and it has not expired at `now`.
+    pub fn verify(&self, server_key: &[u8; 32], now: u64) -> bool {
+        self.server_key == *server_key
+            && now < self.expires
+            && verify_signature(server_key, &self.signed_data(), &self.signature)
+    }
+}
+
+/// An `EncryptedMessage` and the sender's certificate, encrypted to the recipient's identity
+/// key. The relay only sees who it is for and the recipient's access key as delivery token.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SealedMessage {
+    pub recv_name: String,
+    pub recv_device: DeviceId,
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    pub delivery_token: [u8; 32],
+    pub ephemeral_key: PublicKey,
+    pub sealed: Vec<u8>,
+}
+
+// what a `SealedMessage` decrypts to
+#[derive(Serialize, Deserialize, Debug)]
+struct SealedContent {
+    certificate: SenderCertificate,
+    message: EncryptedMessage,
+}
+
+/// Registers the hash of the access key a device hands out to its peers, the relay only
+/// delivers sealed messages whose delivery token hashes to it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccessKey {
+    pub hash: [u8; 32],
+}
+
+impl AccessKey {
+    pub fn new(hash: [u8; 32]) -> Self {
+        Self { hash }
+    }
+}
+
+/// A message to the server with an id picked by the client, the server answers every request
+/// with an `Ack` or a `Nack` carrying the same id, in the order they came in.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub id: u64,
+    pub msg: Box<Msg>,
+}
+
+impl Request {
+    pub fn new(id: u64, msg: Msg) -> Self {
+        Self {
+            id,
+            msg: Box::new(msg),
+        }
+    }
+}
+
+/// The server handled the request, messages were delivered or queued.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ack {
+    pub id: u64,
+}
+
+impl Ack {
+    pub fn new(id: u64) -> Self {
+        Self { id }
+    }
+}
+
+/// Why the server refused a request.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NackReason {
+    /// No such user or device.
+    UnknownRecipient,
+    /// The device sends messages faster than the server allows, it may try again after
+    /// `retry_after` seconds.
+    RateLimited { retry_after: u64 },
+    /// The request is longer than the server reads.
+    TooLarge,
+    /// The request is not a message the server understands.
+    Malformed,
+    /// The device is not linked to the user it tried to register as.
+    NotRegistered,
+    /// The device is already connected.
+    AlreadyConnected,
+    /// The delivery token of a sealed message is not the recipient's access key.
+    InvalidDeliveryToken,
+    /// Published keys are not signed with the signing key the server keeps for the device.
+    InvalidSignature,
+    /// The request concerns the keys or devices of someone else, or links a device again with
+    /// another signing key.
+    NotPermitted,
+    /// The device has not published prekeys.
+    NoPreKeys,
+    /// The user or its address was banned by the administrator.
+    Banned,
+    /// The server failed to handle the request.
+    Internal,
+}
+
+impl fmt::Display for NackReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NackReason::UnknownRecipient => f.write_str("unknown user or device"),
+            NackReason::RateLimited { retry_after } => {
+                write!(f, "rate limited, retry after {}s", retry_after)
+            }
+            NackReason::TooLarge => f.write_str("too large"),
+            NackReason::Malformed => f.write_str("malformed request"),
+            NackReason::NotRegistered => f.write_str("device is not linked"),
+            NackReason::AlreadyConnected => f.write_str("device is already connected"),
+            NackReason::InvalidDeliveryToken => f.write_str("invalid delivery token"),
+            NackReason::InvalidSignature => f.write_str("invalid signature"),
+            NackReason::NotPermitted => f.write_str("not permitted"),
+            NackReason::NoPreKeys => f.write_str("no prekeys"),
+            NackReason::Banned => f.write_str("banned from this server"),
+            NackReason::Internal => f.write_str("server error"),
+        }
+    }
+}
+
+/// The server refused the request. Requests it could not read have no id, being answered in
+/// order it is the oldest request that was not answered yet.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Nack {
+    pub id: Option<u64>,
+    pub reason: NackReason,
+}
+
+impl Nack {
+    pub fn new(id: Option<u64>, reason: NackReason) -> Self {
+        Self { id, reason }
+    }
+}
+
+/// Sent by the server whenever a device connects or disconnects.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Presence {
+    pub user: String,
+    pub device_id: DeviceId,
+    pub online: bool,
+}
+
+impl Presence {
+    pub fn new(user: String, device_id: DeviceId, online: bool) -> Self {
+        Self {
+            user,
+            device_id,
+            online,
+        }
+    }
+}
+
+/// Most users a `UserList` holds, longer lists are paged.
+pub const MAX_USER_LIST: usize = 100;
+
+/// Asks the server for the registered users, answered with a `UserList`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UserQuery {
+    // only users whose name starts with it
+    #[serde(default)]
+    pub prefix: Option<String>,
+    // matching users to skip
+    #[serde(default)]
+    pub offset: usize,
+    // at most `MAX_USER_LIST`
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl UserQuery {
+    pub fn new(prefix: Option<String>, offset: usize, limit: Option<usize>) -> Self {
+        Self {
+            prefix,
+            offset,
+            limit,
+        }
+    }
+}
+
+/// One page of the users matching a `UserQuery`, sorted by name.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct UserList {
+    pub users: Vec<String>,
+    pub offset: usize,
+    // how many users match, on every page together
+    pub total: usize,
+}
+
+impl UserList {
+    pub fn new(users: Vec<String>, offset: usize, total: usize) -> Self {
+        Self {
+            users,
+            offset,
+            total,
+        }
+    }
+}
+
+/// Protocol features a server may support, listed in its `ServerInfo`.
+pub const FEATURES: &[&str] = &[
+    "requests",
+    "offline_queue",
+    "message_ttl",
+    "prekeys",
+    "key_rotation",
+    "linked_devices",
+    "sealed_sender",
+    "presence",
+    "user_list",
+    "block_list",
+    "device_auth",
+];
+
+/// What a server is and what it accepts, the answer to `Msg::ServerInfoRequest`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: String,
+    pub motd: String,
+    /// Longest line the server reads, in bytes.
+    pub max_line_length: usize,
+    /// Messages a device may send per second.
+    pub rate_limit: usize,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl ServerInfo {
+    /// Describes a server of this version, with every feature of `FEATURES`.
+    pub fn new(name: String, motd: String, max_line_length: usize, rate_limit: usize) -> Self {
+        Self {
+            name,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            motd,
+            max_line_length,
+            rate_limit,
+            features: FEATURES.iter().map(|x| x.to_string()).collect(),
+        }
     }
 }
 
+/// Starts a new session from scratch, sent when the old one can no longer decrypt messages.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionReset {
+    pub sender_name: String,
+    pub sender_device: DeviceId,
+    pub recv_name: String,
+    pub recv_device: DeviceId,
+    pub identity_key: PublicKey,
+    pub ephemeral_key: PublicKey,
+    // the recipient's signed prekey the session was started with
+    pub prekey_id: u32,
+    // ML-KEM-768 ciphertext to the recipient's `pq_prekey` if the session is post-quantum
+    #[serde(default)]
+    pub pq_ciphertext: Option<Vec<u8>>,
+    // cipher suite the session is set up with, the recipient adopts it
+    #[serde(default)]
+    pub suite: Suite,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Msg {
     Message(Message),
     EncryptedMessage(EncryptedMessage),
+    // the first thing the server sends, answered by `Register`
+    Challenge(Challenge),
     Register(RegisterMessage),
     Err(ErrMessage),
     Info(Info),
     PubKey(PubKey),
+    PreKeyBundle(PreKeyBundle),
+    // a signed bundle with a new identity key replaces the announced `PubKey`
+    RotateKey(PreKeyBundle),
+    // asks the server for the `PreKeyBundle` of a device
+    PreKeyRequest(Device),
+    SessionReset(SessionReset),
+    LinkDevice(Device),
+    UnlinkDevice(Device),
+    SealedMessage(SealedMessage),
+    // issued by the server once the device's key is known and whenever it rotates
+    SenderCertificate(SenderCertificate),
+    AccessKey(AccessKey),
+    Presence(Presence),
+    ServerInfoRequest,
+    ServerInfo(ServerInfo),
+    ListUsers(UserQuery),
+    UserList(UserList),
+    // asks the server for the `PubKey` of every device of a user, who learns ours in turn
+    KeyRequest(String),
+    // the server drops messages the user sends us directly, sealed ones it cannot tell apart
+    Block(String),
+    Unblock(String),
+    Request(Request),
+    Ack(Ack),
+    Nack(Nack),
+}
+
+impl Msg {
+    /// Splits off the request id, messages that are not wrapped in a `Request` have none.
+    pub fn into_request(self) -> (Option<u64>, Msg) {
+        match self {
+            Msg::Request(request) => (Some(request.id), *request.msg),
+            msg => (None, msg),
+        }
+    }
 }
 
 impl Message {
-    pub fn new(msg: String, from: String, to: String, public_key: PublicKey) -> Self {
+    pub fn new(
+        msg: String,
+        from: (String, DeviceId),
+        to: (String, DeviceId),
+        public_key: PublicKey,
+    ) -> Self {
         Message {
-            sender_name: from,
-            recv_name: to,
+            sender_name: from.0,
+            sender_device: from.1,
+            recv_name: to.0,
+            recv_device: to.1,
+            sent_to: None,
             msg,
+            expires_in: None,
+            access_key: None,
+            id: Some(rand::random()),
             public_key,
         }
     }
 
     pub fn encrypt(&self, state: &State) -> Result<(EncryptedMessage, State), u32> {
-        let new_dh = KeyPair::new();
-        let (root_key, chain_key) =
-            kdf_root_key(state.root_key(), new_dh.private(), &state.dh_pub().unwrap());
+        let suite = state.suite().cipher();
+        let mut state = state.clone();
 
-        let (chain_key_send, mk) = kdf_chain_key(&chain_key);
+        // the first message after hearing from the other side starts a new sending chain
+        if state.chain_send.is_none() {
+            let new_dh = KeyPair::new();
+            let dh_pub = state.dh_pub().ok_or(ERR_DECRYPT)?;
+            let (root_key, chain_key) =
+                state
+                    .kdf()
+                    .root_key(suite, state.root_key(), new_dh.private(), &dh_pub);
+            state.root_key = root_key;
+            state.key_pair = new_dh;
+            state.chain_send = Some(chain_key.counted(0));
+        }
 
-        let nonce = Nonce::from_slice(b"any unique nonce");
-        let cipher = Aes128SivAead::new_from_slice(&mk).unwrap();
-        let encrypted_msg = cipher.encrypt(nonce, self.msg.as_bytes()).unwrap();
+        let chain_key = state.chain_send.take().unwrap();
+        let (next, mk) = state.kdf().chain_key(suite, &chain_key);
+        state.chain_send = Some(next.counted(chain_key.count() + 1));
 
-        Ok((
-            EncryptedMessage {
-                sender_name: self.sender_name.clone(),
-                recv_name: self.recv_name.clone(),
-                encrypted_msg,
-                public_key: new_dh.public(),
-            },
-            State {
-                key_pair: new_dh,
-                dh_pub: state.dh_pub(),
-                root_key,
-                chain_send: Some(chain_key_send),
-                chain_recv: state.chain_recv.clone(),
-                pn: state.pn(),
-            },
-        ))
+        let content = state.padding().pad(
+            serde_json::to_vec(&Content {
+                msg: self.msg.clone(),
+                expires_in: self.expires_in,
+                access_key: self.access_key,
+                id: self.id,
+            })
+            .unwrap(),
+        );
+        let mut encrypted = EncryptedMessage {
+            sender_name: self.sender_name.clone(),
+            sender_device: self.sender_device,
+            recv_name: self.recv_name.clone(),
+            recv_device: self.recv_device,
+            sent_to: self.sent_to.clone(),
+            ttl: self.expires_in,
+            suite: state.suite(),
+            encrypted_msg: Vec::new(),
+            public_key: state.key_pair().public(),
+            count: chain_key.count(),
+            previous_count: state.pn(),
+        };
+        encrypted.encrypted_msg = suite.seal(&mk, &content, &encrypted.associated_data());
+        Ok((encrypted, state))
+    }
+}
+
+// how many messages of a single chain may be skipped over, more are taken as forged
+const MAX_SKIP: u32 = 1000;
+// skipped keys kept at most, the oldest are forgotten first
+const MAX_SKIPPED_KEYS: usize = 2000;
+// earlier chains of the other side that are recognized, older ones are taken as new chains
+const MAX_PREVIOUS_CHAINS: usize = 100;
+
+// keeps the keys of the receiving chain's messages before `until`
+fn skip_keys(state: &mut State, until: u32) -> Result<(), u32> {
+    let (mut chain_key, dh_pub) = match (state.chain_recv.take(), state.dh_pub()) {
+        (Some(chain_key), Some(dh_pub)) => (chain_key, dh_pub),
+        (chain_key, _) => {
+            state.chain_recv = chain_key;
+            return Ok(());
+        }
+    };
+    if until > chain_key.count().saturating_add(MAX_SKIP) {
+        return Err(ERR_DECRYPT);
     }
+    let suite = state.suite().cipher();
+    while chain_key.count() < until {
+        let (next, key) = state.kdf().chain_key(suite, &chain_key);
+        state.skipped.push(SkippedKey {
+            dh_pub,
+            count: chain_key.count(),
+            key,
+        });
+        chain_key = next.counted(chain_key.count() + 1);
+    }
+    let excess = state.skipped.len().saturating_sub(MAX_SKIPPED_KEYS);
+    state.skipped.drain(..excess);
+    state.chain_recv = Some(chain_key);
+    Ok(())
 }
 
 impl EncryptedMessage {
+    // the header is authenticated along with the content, so the relay cannot move a message
+    // to another chain position, suite or conversation
+    fn associated_data(&self) -> Vec<u8> {
+        let mut data = self.sender_name.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&self.sender_device.to_be_bytes());
+        data.extend_from_slice(self.recv_name.as_bytes());
+        data.push(0);
+        data.extend_from_slice(&self.recv_device.to_be_bytes());
+        if let Some(sent_to) = &self.sent_to {
+            data.extend_from_slice(sent_to.as_bytes());
+        }
+        data.push(0);
+        data.extend_from_slice(self.suite.name().as_bytes());
+        data.push(0);
+        data.extend_from_slice(self.public_key.as_bytes());
+        data.extend_from_slice(&self.count.to_be_bytes());
+        data.extend_from_slice(&self.previous_count.to_be_bytes());
+        data
+    }
+
     pub fn decrypt(&self, state: &State) -> Result<(Message, State), u32> {
-        let (root_key, chain_key) = kdf_root_key(
-            state.root_key(),
-            state.key_pair().private(),
-            &self.public_key,
-        );
+        if self.suite != state.suite() {
+            return Err(ERR_SUITE);
+        }
+        let suite = state.suite().cipher();
+        let mut state = state.clone();
 
-        let (chain_key_recv, mk) = kdf_chain_key(&chain_key);
+        let skipped = state
+            .skipped
+            .iter()
+            .position(|x| x.dh_pub == self.public_key && x.count == self.count);
+        let mk = match skipped {
+            Some(i) => state.skipped.remove(i).key,
+            // every key of an earlier chain that is still missing was kept above
+            None if state.previous_chains.contains(&self.public_key) => return Err(ERR_DUPLICATE),
+            None => {
+                // a new chain of the other side, what is left of its last one may still arrive
+                if state.chain_recv.is_none() || state.dh_pub() != Some(self.public_key) {
+                    if let (Some(_), Some(dh_pub)) = (&state.chain_recv, state.dh_pub()) {
+                        state.previous_chains.push(dh_pub);
+                        let excess = state
+                            .previous_chains
+                            .len()
+                            .saturating_sub(MAX_PREVIOUS_CHAINS);
+                        state.previous_chains.drain(..excess);
+                    }
+                    skip_keys(&mut state, self.previous_count)?;
+                    let (root_key, chain_key) = state.kdf().root_key(
+                        suite,
+                        state.root_key(),
+                        state.key_pair().private(),
+                        &self.public_key,
+                    );
+                    state.root_key = root_key;
+                    state.dh_pub = Some(self.public_key);
+                    state.chain_recv = Some(chain_key.counted(0));
+                    state.pn = state.chain_send.take().map_or(0, |x| x.count());
+                }
+                skip_keys(&mut state, self.count)?;
+                let chain_key = state.chain_recv.take().unwrap();
+                if chain_key.count() != self.count {
+                    // received already, or older than the keys that were kept
+                    return Err(ERR_DUPLICATE);
+                }
+                let (next, mk) = state.kdf().chain_key(suite, &chain_key);
+                state.chain_recv = Some(next.counted(chain_key.count() + 1));
+                mk
+            }
+        };
 
-        let nonce = Nonce::from_slice(b"any unique nonce");
-        let cipher = Aes128SivAead::new_from_slice(&mk).unwrap();
-        let decrypted_msg = cipher.decrypt(nonce, self.encrypted_msg.as_ref()).unwrap();
+        let decrypted_msg = suite
+            .open(&mk, &self.encrypted_msg, &self.associated_data())
+            .ok_or(ERR_DECRYPT)?;
+        let content = unpad(&decrypted_msg).ok_or(ERR_MALFORMED)?;
+        let content: Content = serde_json::from_slice(content).map_err(|_| ERR_MALFORMED)?;
 
         Ok((
             Message {
                 sender_name: self.sender_name.clone(),
+                sender_device: self.sender_device,
                 recv_name: self.recv_name.clone(),
-                msg: String::from_utf8(decrypted_msg).unwrap(),
+                recv_device: self.recv_device,
+                sent_to: self.sent_to.clone(),
+                msg: content.msg,
+                expires_in: content.expires_in,
+                access_key: content.access_key,
+                id: content.id,
                 public_key: self.public_key,
             },
-            State {
-                key_pair: state.key_pair().clone(),
-                dh_pub: Some(self.public_key),
-                root_key,
-                chain_send: Some(chain_key_recv),
-                chain_recv: state.chain_recv.clone(),
-                pn: state.pn(),
-            },
+            state,
         ))
     }
 }
+
+impl EncryptedMessage {
+    /// Seals the message for the recipient's `identity_key`. `delivery_token` is the access key
+    /// the recipient handed out.
+    pub fn seal(
+        self,
+        certificate: &SenderCertificate,
+        identity_key: &PublicKey,
+        delivery_token: [u8; 32],
+    ) -> SealedMessage {
+        let (recv_name, recv_device, ttl) = (self.recv_name.clone(), self.recv_device, self.ttl);
+        let content = SealedContent {
+            certificate: certificate.clone(),
+            message: self,
+        };
+        let (ephemeral_key, sealed) = seal_to(identity_key, &serde_json::to_vec(&content).unwrap());
+        SealedMessage {
+            recv_name,
+            recv_device,
+            ttl,
+            delivery_token,
+            ephemeral_key,
+            sealed,
+        }
+    }
+}
+
+impl SealedMessage {
+    /// Opens the message with the recipient's identity key and checks the sender certificate
+    /// against `server_key`. The returned certificate still has to be matched against the
+    /// identity key known for the sender.
+    pub fn unseal(
+        &self,
+        identity: &KeyPair,
+        server_key: &[u8; 32],
+    ) -> Result<(SenderCertificate, EncryptedMessage), u32> {
+        let content =
+            open_sealed(identity, &self.ephemeral_key, &self.sealed).ok_or(ERR_DECRYPT)?;
+        let content: SealedContent = serde_json::from_slice(&content).map_err(|_| ERR_MALFORMED)?;
+        let (certificate, message) = (content.certificate, content.message);
+
+        if message.recv_name != self.recv_name || message.recv_device != self.recv_device {
+            return Err(ERR_MALFORMED);
+        }
+        if !certificate.verify(server_key, unix_time())
+            || certificate.user != message.sender_name
+            || certificate.device_id != message.sender_device
+        {
+            return Err(ERR_CERTIFICATE);
+        }
+        Ok((certificate, message))
+    }
+}
+
+/// Seconds since the unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
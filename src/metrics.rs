@@ -0,0 +1,206 @@
+//! Counters and gauges of the relay, served in the Prometheus text format.
+
+use std::io;
+use std::time::Duration;
+
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::message::NackReason;
+
+// longest request head read from a scraper
+const MAX_REQUEST: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Collectors are shared, clones count into the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Devices currently connected, set when scraped.
+    pub connected_peers: IntGauge,
+    /// Messages waiting for offline devices, set when scraped.
+    pub queued_messages: IntGauge,
+    /// Messages delivered or queued, by kind. Their rate is the relayed messages per second.
+    pub relayed_messages: IntCounterVec,
+    pub received_bytes: IntCounter,
+    pub sent_bytes: IntCounter,
+    /// Messages that were refused, blocked or expired in a queue, by reason.
+    pub dropped_messages: IntCounterVec,
+    pub registration_failures: IntCounterVec,
+    /// Lines that are not a message or too long to read.
+    pub decode_errors: IntCounterVec,
+}
+
+fn gauge(registry: &Registry, name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::new(name, help).unwrap();
+    registry.register(Box::new(gauge.clone())).unwrap();
+    gauge
+}
+
+fn counter(registry: &Registry, name: &str, help: &str) -> IntCounter {
+    let counter = IntCounter::new(name, help).unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+fn counters(registry: &Registry, name: &str, help: &str, label: &str) -> IntCounterVec {
+    let counters = IntCounterVec::new(Opts::new(name, help), &[label]).unwrap();
+    registry.register(Box::new(counters.clone())).unwrap();
+    counters
+}
+
+fn label(reason: &NackReason) -> &'static str {
+    match reason {
+        NackReason::UnknownRecipient => "unknown_recipient",
+        NackReason::RateLimited { .. } => "rate_limited",
+        NackReason::TooLarge => "too_large",
+        NackReason::Malformed => "malformed",
+        NackReason::NotRegistered => "not_registered",
+        NackReason::AlreadyConnected => "already_connected",
+        NackReason::InvalidDeliveryToken => "invalid_delivery_token",
+        NackReason::InvalidSignature => "invalid_signature",
+        NackReason::NotPermitted => "not_permitted",
+        NackReason::NoPreKeys => "no_prekeys",
+        NackReason::Banned => "banned",
+        NackReason::Internal => "internal",
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("lib_sig".to_owned()), None).unwrap();
+        Metrics {
+            connected_peers: gauge(&registry, "connected_peers", "Connected devices"),
+            queued_messages: gauge(
+                &registry,
+                "queued_messages",
+                "Messages queued for offline devices",
+            ),
+            relayed_messages: counters(
+                &registry,
+                "relayed_messages_total",
+                "Messages delivered or queued",
+                "kind",
+            ),
+            received_bytes: counter(&registry, "received_bytes_total", "Bytes read from clients"),
+            sent_bytes: counter(&registry, "sent_bytes_total", "Bytes written to clients"),
+            dropped_messages: counters(
+                &registry,
+                "dropped_messages_total",
+                "Messages refused or expired before delivery",
+                "reason",
+            ),
+            registration_failures: counters(
+                &registry,
+                "registration_failures_total",
+                "Connections that failed to register",
+                "reason",
+            ),
+            decode_errors: counters(
+                &registry,
+                "decode_errors_total",
+                "Lines that could not be read as a message",
+                "reason",
+            ),
+            registry,
+        }
+    }
+
+    pub fn relayed(&self, kind: &str) {
+        self.relayed_messages.with_label_values(&[kind]).inc();
+    }
+
+    pub fn dropped(&self, reason: &NackReason) {
+        self.dropped_messages
+            .with_label_values(&[label(reason)])
+            .inc();
+    }
+
+    // messages from users the recipient blocked, the sender is not told
+    pub fn blocked(&self) {
+        self.dropped_messages.with_label_values(&["blocked"]).inc();
+    }
+
+    pub fn expired(&self, n: usize) {
+        self.dropped_messages
+            .with_label_values(&["expired"])
+            .inc_by(n as u64);
+    }
+
+    pub fn registration_failed(&self, reason: &str) {
+        self.registration_failures
+            .with_label_values(&[reason])
+            .inc();
+    }
+
+    pub fn decode_failed(&self, reason: &str) {
+        self.decode_errors.with_label_values(&[reason]).inc();
+    }
+
+    /// Every metric in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Answers `GET /metrics` on `listener` with whatever `render` returns, other paths get a 404.
+pub async fn serve<F, Fut>(listener: TcpListener, render: F) -> io::Result<()>
+where
+    F: Fn() -> Fut + Clone + Send + 'static,
+    Fut: std::future::Future<Output = String> + Send,
+{
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let render = render.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, render).await {
+                tracing::info!("failed to answer metrics request from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn respond<F, Fut>(mut stream: TcpStream, render: F) -> io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = String>,
+{
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|x| x == b"\r\n\r\n") {
+        let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buffer))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        if n == 0 || head.len() + n > MAX_REQUEST {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        head.extend_from_slice(&buffer[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&head);
+    let mut words = request.split_whitespace();
+    let response = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render().await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
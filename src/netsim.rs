@@ -0,0 +1,458 @@
+//! In-memory network for running the relay and clients over links that lose, duplicate, delay
+//! and reorder what is sent, or are cut off entirely. Faults are drawn from a seeded RNG and
+//! delays follow tokio's clock, so a test with paused time runs the same way every time.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
+
+// port every host listens on
+const LISTEN_PORT: u16 = 6142;
+
+/// Faults of the link between two hosts, the same in both directions. The protocol is line
+/// based, so faults apply to whole lines and never cut one apart.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Link {
+    /// Delay of every line.
+    pub latency: Duration,
+    /// Share of lines that are lost, from 0 to 1.
+    pub drop_rate: f64,
+    /// Share of lines that arrive twice.
+    pub duplicate_rate: f64,
+    /// Share of lines held back by `reorder_delay`, the ones sent after them overtake them.
+    pub reorder_rate: f64,
+    pub reorder_delay: Duration,
+}
+
+/// Hosts connected by links, clones are handles to the same network.
+#[derive(Clone)]
+pub struct Network {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    rng: StdRng,
+    hosts: HashMap<String, IpAddr>,
+    listeners: HashMap<String, mpsc::UnboundedSender<(Stream, SocketAddr)>>,
+    // keyed by the sorted pair of hosts
+    links: HashMap<(String, String), Link>,
+    partitions: HashSet<(String, String)>,
+    connections: Vec<Connection>,
+    next_port: u16,
+}
+
+// lets `disconnect` find both directions of a connection
+struct Connection {
+    hosts: (String, String),
+    pipes: [Weak<Mutex<Pipe>>; 2],
+}
+
+// one direction of a connection
+#[derive(Default)]
+struct Pipe {
+    // lines on their way, by arrival and then by the order they were sent in
+    in_flight: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    sent: u64,
+    // arrived but not read yet
+    arrived: VecDeque<u8>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl Pipe {
+    fn send(&mut self, at: Instant, line: Vec<u8>) {
+        self.sent += 1;
+        self.in_flight.push(Reverse((at, self.sent, line)));
+        self.wake();
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.wake();
+    }
+
+    // a reset connection loses what it did not deliver yet
+    fn reset(&mut self) {
+        self.in_flight.clear();
+        self.arrived.clear();
+        self.close();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_owned(), b.to_owned())
+    } else {
+        (b.to_owned(), a.to_owned())
+    }
+}
+
+impl Inner {
+    fn ip(&mut self, host: &str) -> IpAddr {
+        let n = self.hosts.len() as u32 + 1;
+        *self
+            .hosts
+            .entry(host.to_owned())
+            .or_insert_with(|| IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n)))
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        self.rng.gen::<f64>() < rate
+    }
+}
+
+impl Network {
+    pub fn new(seed: u64) -> Self {
+        Network {
+            inner: Arc::new(Mutex::new(Inner {
+                rng: StdRng::seed_from_u64(seed),
+                hosts: HashMap::new(),
+                listeners: HashMap::new(),
+                links: HashMap::new(),
+                partitions: HashSet::new(),
+                connections: Vec::new(),
+                next_port: 40000,
+            })),
+        }
+    }
+
+    /// Accepts connections to `host`, replacing whoever listened there before.
+    pub fn listen(&self, host: &str) -> Listener {
+        let mut inner = self.inner.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        inner.listeners.insert(host.to_owned(), tx);
+        Listener {
+            addr: SocketAddr::new(inner.ip(host), LISTEN_PORT),
+            incoming: rx,
+        }
+    }
+
+    /// Connects `from` to the host listening as `to`, refused while they are partitioned.
+    pub async fn connect(&self, from: &str, to: &str) -> io::Result<Stream> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.partitions.contains(&pair(from, to)) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let listener = match inner.listeners.get(to) {
+            Some(listener) => listener.clone(),
+            None => return Err(io::ErrorKind::ConnectionRefused.into()),
+        };
+
+        inner.next_port = inner.next_port.wrapping_add(1);
+        let local = SocketAddr::new(inner.ip(from), inner.next_port);
+        let remote = SocketAddr::new(inner.ip(to), LISTEN_PORT);
+        let (up, down) = (Arc::default(), Arc::default());
+        inner.connections.push(Connection {
+            hosts: pair(from, to),
+            pipes: [Arc::downgrade(&up), Arc::downgrade(&down)],
+        });
+        drop(inner);
+
+        let client = Stream::new(
+            self,
+            (from, local),
+            (to, remote),
+            Arc::clone(&down),
+            Arc::clone(&up),
+        );
+        let server = Stream::new(self, (to, remote), (from, local), up, down);
+        listener
+            .send((server, local))
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+
+    pub fn set_link(&self, a: &str, b: &str, link: Link) {
+        self.inner.lock().unwrap().links.insert(pair(a, b), link);
+    }
+
+    /// Drops everything sent between `a` and `b` until `heal` is called, connections between
+    /// them stay open.
+    pub fn partition(&self, a: &str, b: &str) {
+        self.inner.lock().unwrap().partitions.insert(pair(a, b));
+    }
+
+    pub fn heal(&self, a: &str, b: &str) {
+        self.inner.lock().unwrap().partitions.remove(&pair(a, b));
+    }
+
+    /// Resets every connection between `a` and `b`, what is still on its way is lost.
+    pub fn disconnect(&self, a: &str, b: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let hosts = pair(a, b);
+        inner.connections.retain(|connection| {
+            if connection.hosts != hosts {
+                return connection.pipes.iter().any(|x| x.strong_count() > 0);
+            }
+            for pipe in connection.pipes.iter().filter_map(Weak::upgrade) {
+                pipe.lock().unwrap().reset();
+            }
+            false
+        });
+    }
+
+    // passes a line from `from` to `to` as the link between them allows
+    fn transmit(&self, from: &str, to: &str, pipe: &Mutex<Pipe>, line: Vec<u8>) {
+        let mut inner = self.inner.lock().unwrap();
+        let hosts = pair(from, to);
+        if inner.partitions.contains(&hosts) {
+            return;
+        }
+        let link = inner.links.get(&hosts).copied().unwrap_or_default();
+        if inner.chance(link.drop_rate) {
+            return;
+        }
+        let copies = if inner.chance(link.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut delay = link.latency;
+            if inner.chance(link.reorder_rate) {
+                delay += link.reorder_delay;
+            }
+            pipe.lock()
+                .unwrap()
+                .send(Instant::now() + delay, line.clone());
+        }
+    }
+}
+
+/// Connections to a host of a `Network`.
+pub struct Listener {
+    addr: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<(Stream, SocketAddr)>,
+}
+
+impl Listener {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The next connection and the address it comes from.
+    pub async fn accept(&mut self) -> io::Result<(Stream, SocketAddr)> {
+        self.incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// One end of a connection, used like a `TcpStream`.
+pub struct Stream {
+    net: Network,
+    local: (String, SocketAddr),
+    remote: (String, SocketAddr),
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+    // written after the last complete line
+    partial: Vec<u8>,
+    // until the next line on its way arrives
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Stream {
+    fn new(
+        net: &Network,
+        (local, local_addr): (&str, SocketAddr),
+        (remote, peer_addr): (&str, SocketAddr),
+        incoming: Arc<Mutex<Pipe>>,
+        outgoing: Arc<Mutex<Pipe>>,
+    ) -> Self {
+        Stream {
+            net: net.clone(),
+            local: (local.to_owned(), local_addr),
+            remote: (remote.to_owned(), peer_addr),
+            incoming,
+            outgoing,
+            partial: Vec::new(),
+            sleep: None,
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local.1
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.remote.1
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let next = {
+                let mut pipe = self.incoming.lock().unwrap();
+                let now = Instant::now();
+                while let Some(Reverse((at, _, _))) = pipe.in_flight.peek() {
+                    if *at > now {
+                        break;
+                    }
+                    let Reverse((_, _, line)) = pipe.in_flight.pop().unwrap();
+                    pipe.arrived.extend(line);
+                }
+
+                if !pipe.arrived.is_empty() {
+                    let n = buf.remaining().min(pipe.arrived.len());
+                    let bytes = pipe.arrived.drain(..n).collect::<Vec<_>>();
+                    buf.put_slice(&bytes);
+                    return Poll::Ready(Ok(()));
+                }
+                pipe.waker = Some(cx.waker().clone());
+                match pipe.in_flight.peek() {
+                    Some(Reverse((at, _, _))) => *at,
+                    // nothing is on its way, reading an empty buffer means the end of the stream
+                    None if pipe.closed => return Poll::Ready(Ok(())),
+                    None => return Poll::Pending,
+                }
+            };
+
+            let sleep = self
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(next)));
+            sleep.as_mut().reset(next);
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.outgoing.lock().unwrap().closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        self.partial.extend_from_slice(buf);
+        while let Some(i) = self.partial.iter().position(|x| *x == b'\n') {
+            let line = self.partial.drain(..=i).collect();
+            self.net
+                .transmit(&self.local.0, &self.remote.0, &self.outgoing, line);
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.outgoing.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+// what was sent before still arrives, the other side's writes fail from now on
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.outgoing.lock().unwrap().close();
+        self.incoming.lock().unwrap().close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    async fn lines(net: &Network, link: Link, n: usize) -> Vec<String> {
+        net.set_link("client", "server", link);
+        let mut listener = net.listen("server");
+        let mut client = net.connect("client", "server").await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        for i in 0..n {
+            client
+                .write_all(format!("{}\n", i).as_bytes())
+                .await
+                .unwrap();
+        }
+        drop(client);
+
+        let mut received = Vec::new();
+        let mut server = BufReader::new(server).lines();
+        while let Some(line) = server.next_line().await.unwrap() {
+            received.push(line);
+        }
+        received
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn faults_follow_the_seed() {
+        let link = Link {
+            latency: Duration::from_millis(20),
+            drop_rate: 0.2,
+            duplicate_rate: 0.2,
+            reorder_rate: 0.2,
+            reorder_delay: Duration::from_millis(50),
+        };
+        let first = lines(&Network::new(7), link, 100).await;
+        assert_eq!(first, lines(&Network::new(7), link, 100).await);
+        assert_ne!(first, lines(&Network::new(8), link, 100).await);
+
+        let sent = (0..100).map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_ne!(first, sent);
+        assert!(first.iter().all(|x| sent.contains(x)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lines_arrive_after_the_latency() {
+        let net = Network::new(0);
+        let link = Link {
+            latency: Duration::from_millis(100),
+            ..Link::default()
+        };
+        let start = Instant::now();
+        assert_eq!(lines(&net, link, 3).await, ["0", "1", "2"]);
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partitions_drop_lines_and_connections() {
+        let net = Network::new(0);
+        let mut listener = net.listen("server");
+        let mut client = net.connect("client", "server").await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        net.partition("client", "server");
+        client.write_all(b"lost\n").await.unwrap();
+        assert!(net.connect("client", "server").await.is_err());
+        net.heal("client", "server");
+        client.write_all(b"kept\n").await.unwrap();
+
+        let mut server = BufReader::new(server).lines();
+        assert_eq!(server.next_line().await.unwrap().unwrap(), "kept");
+
+        net.disconnect("client", "server");
+        assert_eq!(server.next_line().await.unwrap(), None);
+        assert!(client.write_all(b"gone\n").await.is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+
+use crate::message::{DeviceId, Message, SessionReset};
+use crate::padding::Padding;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Not written to the server yet, or refused for the moment. It is sent again later.
+    Pending,
+    /// Written to the server, which has not acknowledged it yet.
+    Sent,
+}
+
+/// What an entry sends.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Item {
+    /// The plaintext, encrypted again if the session it was encrypted for is gone.
+    Message(Message),
+    /// Starts the session the messages after it are encrypted with, useless once that is gone.
+    Reset(SessionReset),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    // id of the request it is sent with
+    pub id: u64,
+    #[serde(flatten)]
+    pub item: Item,
+    pub padding: Padding,
+    // the message as it is sent to the server, only valid for the session it was encrypted with
+    #[serde(skip)]
+    pub line: String,
+    pub status: Status,
+}
+
+impl Entry {
+    pub fn new(id: u64, message: Message, padding: Padding, line: String) -> Self {
+        Self {
+            id,
+            item: Item::Message(message),
+            padding,
+            line,
+            status: Status::Pending,
+        }
+    }
+
+    pub fn reset(id: u64, reset: SessionReset, line: String) -> Self {
+        Self {
+            id,
+            item: Item::Reset(reset),
+            padding: Padding::None,
+            line,
+            status: Status::Pending,
+        }
+    }
+
+    /// The plaintext, `None` for a session reset.
+    pub fn message(&self) -> Option<&Message> {
+        match &self.item {
+            Item::Message(message) => Some(message),
+            Item::Reset(_) => None,
+        }
+    }
+
+    pub fn recipient(&self) -> (&str, DeviceId) {
+        match &self.item {
+            Item::Message(message) => (&message.recv_name, message.recv_device),
+            Item::Reset(reset) => (&reset.recv_name, reset.recv_device),
+        }
+    }
+
+    /// Names the entry like the request it is sent with is named in the logs.
+    pub fn describe(&self) -> String {
+        let (user, device) = self.recipient();
+        match self.item {
+            Item::Message(_) => format!("message to {} (device {})", user, device),
+            Item::Reset(_) => format!("session reset for {} (device {})", user, device),
+        }
+    }
+}
+
+/// Encrypted messages and session resets the server has not acknowledged yet, in the order
+/// they were sent.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Outbox {
+    entries: Vec<Entry>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        self.entries.push(entry);
+    }
+
+    pub fn mark_sent(&mut self, id: u64) {
+        self.set_status(id, Status::Sent);
+    }
+
+    /// Marks a message the server refused for now, to send it again later.
+    pub fn mark_pending(&mut self, id: u64) {
+        self.set_status(id, Status::Pending);
+    }
+
+    fn set_status(&mut self, id: u64, status: Status) {
+        if let Some(entry) = self.entries.iter_mut().find(|x| x.id == id) {
+            entry.status = status;
+        }
+    }
+
+    /// Removes a message the server acknowledged or refused for good, `None` if it is not in
+    /// the outbox.
+    pub fn remove(&mut self, id: u64) -> Option<Entry> {
+        let i = self.entries.iter().position(|x| x.id == id)?;
+        Some(self.entries.remove(i))
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Removes the session resets sent to a device, the session they started was given up.
+    pub fn remove_resets(&mut self, recipient: (&str, DeviceId)) {
+        self.entries
+            .retain(|x| !matches!(x.item, Item::Reset(_)) || x.recipient() != recipient);
+    }
+
+    /// Removes every entry and returns the messages, to encrypt them again after a restart.
+    /// Session resets are dropped, the sessions they started are gone.
+    pub fn take(&mut self) -> Vec<(Message, Padding)> {
+        std::mem::take(&mut self.entries)
+            .into_iter()
+            .filter_map(|x| match x.item {
+                Item::Message(message) => Some((message, x.padding)),
+                Item::Reset(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    fn entry(outbox: &mut Outbox, id: u64, text: &str) {
+        let msg = Message::new(
+            text.to_owned(),
+            ("alice".to_owned(), 0),
+            ("bob".to_owned(), 1),
+            KeyPair::new().public(),
+        );
+        outbox.push(Entry::new(id, msg, Padding::None, format!("line {}", id)));
+    }
+
+    #[test]
+    fn remove_takes_the_message() {
+        let mut outbox = Outbox::new();
+        entry(&mut outbox, 1, "first");
+        entry(&mut outbox, 2, "second");
+
+        outbox.mark_sent(1);
+        outbox.mark_sent(2);
+        outbox.mark_pending(2);
+        assert_eq!(outbox.entries()[0].status, Status::Sent);
+        assert_eq!(outbox.entries()[1].status, Status::Pending);
+
+        assert_eq!(outbox.remove(1).unwrap().message().unwrap().msg, "first");
+        assert!(outbox.remove(1).is_none());
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.entries()[0].recipient(), ("bob", 1));
+    }
+
+    #[test]
+    fn plaintext_survives_storing() {
+        let mut outbox = Outbox::new();
+        entry(&mut outbox, 1, "hi");
+
+        let mut stored: Outbox =
+            serde_json::from_str(&serde_json::to_string(&outbox).unwrap()).unwrap();
+        // the ciphertext is useless without the session, only the plaintext is kept
+        assert!(stored.entries()[0].line.is_empty());
+        let messages = stored.take();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0.msg, "hi");
+        // encrypting it again keeps the id the recipient drops copies by
+        assert!(messages[0].0.id.is_some());
+        assert_eq!(
+            Some(messages[0].0.id),
+            outbox.entries()[0].message().map(|x| x.id)
+        );
+        assert!(stored.is_empty());
+    }
+
+    #[test]
+    fn resets_are_not_kept_for_the_next_start() {
+        let mut outbox = Outbox::new();
+        let reset = SessionReset {
+            sender_name: "alice".to_owned(),
+            sender_device: 0,
+            recv_name: "bob".to_owned(),
+            recv_device: 1,
+            identity_key: KeyPair::new().public(),
+            ephemeral_key: KeyPair::new().public(),
+            prekey_id: 1,
+            pq_ciphertext: None,
+            suite: Default::default(),
+        };
+        outbox.push(Entry::reset(1, reset.clone(), "line 1".to_owned()));
+        entry(&mut outbox, 2, "after the reset");
+        outbox.push(Entry::reset(3, reset, "line 3".to_owned()));
+        assert_eq!(outbox.entries()[0].recipient(), ("bob", 1));
+        assert!(outbox.entries()[0].message().is_none());
+
+        let mut stored: Outbox =
+            serde_json::from_str(&serde_json::to_string(&outbox).unwrap()).unwrap();
+        let messages = stored.take();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0.msg, "after the reset");
+
+        // giving up the session drops its resets, not the messages
+        outbox.remove_resets(("bob", 1));
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.entries()[0].id, 2);
+    }
+}
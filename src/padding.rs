@@ -0,0 +1,184 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// padded lengths of `Padding::Buckets`, longer plaintexts are padded to a multiple of the last one
+const BUCKETS: &[usize] = &[64, 256, 1024, 4096, 16384];
+// at most this many bytes are added by `Padding::Random`
+const MAX_RANDOM: usize = 256;
+
+// separates the plaintext from the padding, which is all zeros after it
+const MARKER: u8 = 0x80;
+
+/// How plaintexts are padded before they are encrypted, so the length of a ciphertext
+/// says less about the length of the message.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    /// Padded to the next of a few fixed sizes.
+    Buckets,
+    /// Padmé, which leaks at most O(log log n) bits of the length and adds at most 12%.
+    Padme,
+    /// A random number of bytes is added.
+    Random,
+}
+
+const SCHEMES: [Padding; 4] = [
+    Padding::None,
+    Padding::Buckets,
+    Padding::Padme,
+    Padding::Random,
+];
+
+impl Padding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Padding::None => "none",
+            Padding::Buckets => "buckets",
+            Padding::Padme => "padme",
+            Padding::Random => "random",
+        }
+    }
+
+    // length of a padded plaintext of `len` bytes, marker included
+    fn padded_len(&self, len: usize) -> usize {
+        match self {
+            Padding::None => len,
+            Padding::Buckets => match BUCKETS.iter().find(|x| **x >= len) {
+                Some(bucket) => *bucket,
+                None => {
+                    let last = BUCKETS[BUCKETS.len() - 1];
+                    len.div_ceil(last) * last
+                }
+            },
+            Padding::Padme => padme(len),
+            Padding::Random => len + rand::thread_rng().gen_range(0..=MAX_RANDOM),
+        }
+    }
+
+    /// Pads `data`, which must not end with a zero byte or the marker unless the scheme
+    /// is `Padding::None`.
+    pub fn pad(&self, mut data: Vec<u8>) -> Vec<u8> {
+        if *self == Padding::None {
+            return data;
+        }
+        data.push(MARKER);
+        let len = self.padded_len(data.len());
+        data.resize(len, 0);
+        data
+    }
+}
+
+/// Strips any padding added by `Padding::pad`, whatever the scheme. `None` if the padding
+/// is malformed.
+pub fn unpad(data: &[u8]) -> Option<&[u8]> {
+    match data.last() {
+        Some(0) | Some(&MARKER) => {
+            let end = data.iter().rposition(|x| *x != 0)?;
+            if data[end] != MARKER {
+                return None;
+            }
+            Some(&data[..end])
+        }
+        _ => Some(data),
+    }
+}
+
+// https://lbarman.ch/blog/padme/
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let e = usize::BITS - 1 - len.leading_zeros();
+    let s = u32::BITS - e.leading_zeros();
+    let mask = (1 << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SCHEMES
+            .into_iter()
+            .find(|x| x.name() == s)
+            .ok_or_else(|| format!("unknown padding scheme {}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &[u8] = br#"{"msg":"hello"}"#;
+
+    #[test]
+    fn round_trip() {
+        for padding in SCHEMES {
+            for len in [0, 1, 15, 63, 64, 1000, 20000] {
+                let mut data = vec![b'a'; len];
+                data.push(b'}');
+                let padded = padding.pad(data.clone());
+                assert_eq!(unpad(&padded), Some(data.as_slice()));
+            }
+        }
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(Padding::Buckets.pad(JSON.to_vec()).len(), 64);
+        assert_eq!(Padding::Buckets.pad(vec![b'}'; 64]).len(), 256);
+        assert_eq!(Padding::Buckets.pad(vec![b'}'; 20000]).len(), 32768);
+    }
+
+    #[test]
+    fn padme_lengths() {
+        assert_eq!(padme(9), 10);
+        assert_eq!(padme(100), 104);
+        assert_eq!(padme(1000), 1024);
+        assert_eq!(padme(1024), 1024);
+        for len in 2..5000 {
+            assert!(padme(len) >= len && padme(len) <= len + len / 8);
+        }
+    }
+
+    #[test]
+    fn random() {
+        let lens = (0..20)
+            .map(|_| Padding::Random.pad(JSON.to_vec()).len())
+            .collect::<Vec<_>>();
+        assert!(lens
+            .iter()
+            .all(|x| *x > JSON.len() && *x <= JSON.len() + 1 + MAX_RANDOM));
+        assert!(lens.iter().any(|x| *x != lens[0]));
+    }
+
+    #[test]
+    fn unpadded_data_is_left_alone() {
+        assert_eq!(unpad(JSON), Some(JSON));
+        assert_eq!(Padding::None.pad(JSON.to_vec()), JSON);
+    }
+
+    #[test]
+    fn malformed_padding() {
+        assert_eq!(unpad(&[b'}', 1, 0, 0]), None);
+        assert_eq!(unpad(&[0, 0]), None);
+    }
+
+    #[test]
+    fn names() {
+        for padding in SCHEMES {
+            assert_eq!(padding.to_string().parse::<Padding>().unwrap(), padding);
+        }
+        assert!("lots".parse::<Padding>().is_err());
+    }
+}
@@ -0,0 +1,1257 @@
+use crate::admin::{self, Command, Target};
+use crate::config::ServerConfig;
+use crate::crypto::{access_key_hash, SigningKeyPair};
+use crate::message::{
+    unix_time, Ack, Challenge, Device, DeviceId, ErrMessage, Info, Msg, Nack, NackReason,
+    PreKeyBundle, Presence, PubKey, SenderCertificate, ServerInfo, UserList, UserQuery,
+    MAX_USER_LIST,
+};
+use crate::metrics::{self, Metrics};
+use crate::storage::Storage;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+
+use futures::SinkExt;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use x25519_dalek::PublicKey;
+
+/// Relay that passes encrypted messages between clients and queues them for offline devices.
+pub struct Server {
+    state: Arc<Mutex<Shared>>,
+    max_connections: usize,
+    max_line_length: usize,
+    rate_limit: usize,
+    metrics: Metrics,
+    metrics_listener: Option<TcpListener>,
+    admin_listener: Option<UnixListener>,
+}
+
+impl Server {
+    pub fn new(storage: Box<dyn Storage>, config: &ServerConfig) -> Self {
+        Server {
+            state: Arc::new(Mutex::new(Shared::new(storage, config))),
+            max_connections: config.max_connections,
+            max_line_length: config.max_line_length,
+            rate_limit: config.rate_limit,
+            metrics: Metrics::new(),
+            metrics_listener: None,
+            admin_listener: None,
+        }
+    }
+
+    /// Serves the metrics on `listener` once the server runs.
+    pub fn with_metrics(mut self, listener: TcpListener) -> Self {
+        self.metrics_listener = Some(listener);
+        self
+    }
+
+    /// Accepts admin commands on `listener` once the server runs, see `admin`.
+    pub fn with_admin(mut self, listener: UnixListener) -> Self {
+        self.admin_listener = Some(listener);
+        self
+    }
+
+    /// Serves clients connecting to `listener`, only returns if accepting a connection fails.
+    pub async fn run(mut self, listener: TcpListener) -> io::Result<()> {
+        if let Some(listener) = self.metrics_listener.take() {
+            let (state, metrics) = (Arc::clone(&self.state), self.metrics.clone());
+            tokio::spawn(async move {
+                let render = move || {
+                    let (state, metrics) = (Arc::clone(&state), metrics.clone());
+                    async move { encode_metrics(&state, &metrics).await }
+                };
+                if let Err(e) = metrics::serve(listener, render).await {
+                    tracing::error!("metrics endpoint stopped, error: {}", e);
+                }
+            });
+        }
+
+        if let Some(listener) = self.admin_listener.take() {
+            let state = Arc::clone(&self.state);
+            tokio::spawn(async move {
+                loop {
+                    let stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            tracing::error!("admin socket stopped, error: {}", e);
+                            break;
+                        }
+                    };
+                    let state = Arc::clone(&state);
+                    tokio::spawn(async move {
+                        if let Err(e) = administer(state, stream).await {
+                            tracing::info!("admin connection failed, error: {}", e);
+                        }
+                    });
+                }
+            });
+        }
+
+        // queued messages past their ttl are also dropped for devices that never reconnect
+        {
+            let (state, metrics) = (Arc::clone(&self.state), self.metrics.clone());
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(60));
+                loop {
+                    interval.tick().await;
+                    match state.lock().await.storage.purge_expired() {
+                        Ok(0) => (),
+                        Ok(n) => {
+                            tracing::info!("dropped {} expired queued messages", n);
+                            metrics.expired(n);
+                        }
+                        Err(e) => tracing::error!("failed to purge expired messages, error: {}", e),
+                    }
+                }
+            });
+        }
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            self.serve(stream, addr).await;
+        }
+    }
+
+    /// Serves one client connected from `addr` over any stream, such as the in-memory ones of
+    /// `netsim`, on its own task.
+    pub async fn serve<S>(&self, stream: S, addr: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let refused = {
+            let state = self.state.lock().await;
+            if state.bans.contains(&Target::Ip(addr.ip())) {
+                Some(("banned", "banned from this server"))
+            } else if state.peers.len() >= self.max_connections {
+                Some(("server_full", "the server is full"))
+            } else {
+                None
+            }
+        };
+        if let Some((reason, error)) = refused {
+            tracing::info!("turned away {}: {}", addr, error);
+            self.metrics.registration_failed(reason);
+            tokio::spawn(async move {
+                let mut lines = Framed::new(stream, LinesCodec::new());
+                reject(&mut lines, error.to_owned()).await;
+            });
+            return;
+        }
+
+        let (state, metrics) = (Arc::clone(&self.state), self.metrics.clone());
+        let (max_line_length, rate_limit) = (self.max_line_length, self.rate_limit);
+
+        tokio::spawn(async move {
+            tracing::info!("accepted connection on address: {}", addr);
+            let result = process(state, stream, addr, max_line_length, rate_limit, metrics).await;
+            if let Err(e) = result {
+                tracing::info!("an error occurred; error = {:?}", e);
+            }
+        });
+    }
+}
+
+// the gauges are brought up to date for every scrape
+async fn encode_metrics(state: &Mutex<Shared>, metrics: &Metrics) -> String {
+    {
+        let state = state.lock().await;
+        metrics.connected_peers.set(state.peers.len() as i64);
+        match state.storage.queued_count() {
+            Ok(n) => metrics.queued_messages.set(n as i64),
+            Err(e) => tracing::error!("failed to count queued messages, error: {}", e),
+        }
+    }
+    metrics.encode()
+}
+
+// answers the commands of one admin connection
+async fn administer(state: Arc<Mutex<Shared>>, stream: UnixStream) -> Result<(), Box<dyn Error>> {
+    let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(MAX_ADMIN_LINE));
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        tracing::info!("admin command: {}", line);
+        let result = match Command::parse(&line) {
+            Ok(command) => state.lock().await.administer(command),
+            Err(e) => Err(e),
+        };
+        let reply = match result {
+            Ok(mut output) => {
+                output.push("ok".to_owned());
+                output.join("\n")
+            }
+            Err(e) => format!("error: {}", e),
+        };
+        lines.send(reply).await?;
+    }
+    Ok(())
+}
+
+const MAX_ADMIN_LINE: usize = 64 * 1024;
+
+// sender certificates outlive the client's daily key rotation, which renews them
+const CERTIFICATE_LIFETIME: u64 = 2 * 24 * 60 * 60;
+
+type Tx = mpsc::UnboundedSender<String>;
+type Rx = mpsc::UnboundedReceiver<String>;
+
+struct Data {
+    addr: SocketAddr,
+    name: String,
+    device: DeviceId,
+    tx: Tx,
+    pub_key: Option<PublicKey>,
+}
+
+impl Data {
+    fn set_key(&mut self, key: PublicKey) {
+        self.pub_key = Some(key);
+    }
+}
+
+struct Shared {
+    peers: Vec<Data>,
+    storage: Box<dyn Storage>,
+    // signs sender certificates, a restart invalidates the ones handed out before
+    certificate_key: SigningKeyPair,
+    // the admin may change the motd, until the server stops
+    info: ServerInfo,
+    // only kept until the server stops
+    bans: HashSet<Target>,
+    // keys and presence go to everyone, not only to the users that were introduced
+    broadcast_keys: bool,
+    // whether `ListUsers` finds users by prefix, or only by their full name
+    list_users: bool,
+}
+
+struct Peer<S> {
+    lines: Framed<S, LinesCodec>,
+    rx: Rx,
+}
+
+impl Shared {
+    fn new(storage: Box<dyn Storage>, config: &ServerConfig) -> Self {
+        Shared {
+            peers: Vec::new(),
+            storage,
+            certificate_key: SigningKeyPair::new(),
+            info: ServerInfo::new(
+                config.name.clone(),
+                config.motd.clone(),
+                config.max_line_length,
+                config.rate_limit,
+            ),
+            bans: HashSet::new(),
+            broadcast_keys: config.broadcast_keys,
+            list_users: config.list_users,
+        }
+    }
+    async fn send(
+        &mut self,
+        peer_name: &String,
+        device: DeviceId,
+        msg: &str,
+    ) -> Result<(), String> {
+        match self.get_device(peer_name, device) {
+            Some(x) => {
+                if let Err(e) = x.tx.send(msg.to_string()) {
+                    tracing::info!("failed to send message to {}, msg: {}", peer_name, e);
+                }
+                Ok(())
+            }
+            None => {
+                tracing::info!("user {} with device {} does not exist", peer_name, device);
+                Err("user does not exist".to_string())
+            }
+        }
+    }
+    fn get_device(&self, peer_name: &String, device: DeviceId) -> Option<&Data> {
+        self.peers
+            .iter()
+            .find(|&x| x.name == *peer_name && x.device == device)
+    }
+
+    // users that may learn the keys and presence of `user`'s devices, `None` if everyone may
+    fn audience(&self, user: &str) -> Option<HashSet<String>> {
+        if self.broadcast_keys {
+            return None;
+        }
+        let mut users = self
+            .storage
+            .introduced(user)
+            .unwrap_or_else(|e| {
+                tracing::error!("failed to load introductions of {}, error: {}", user, e);
+                Vec::new()
+            })
+            .into_iter()
+            .collect::<HashSet<_>>();
+        users.insert(user.to_owned());
+        Some(users)
+    }
+
+    // sends `msg` about `user` to every connection except the one on `from` that may learn it
+    fn announce(&self, from: SocketAddr, user: &str, msg: &Msg) {
+        let audience = self.audience(user);
+        let msg = serde_json::to_string(msg).unwrap();
+        for x in self.peers.iter() {
+            if x.addr != from && audience.as_ref().is_none_or(|a| a.contains(&x.name)) {
+                if let Err(e) = x.tx.send(msg.clone()) {
+                    tracing::error!("failed to send message to {}, msg: {}", x.name, e);
+                }
+            }
+        }
+    }
+
+    // `signature` is passed on with the key, so peers do not have to take it from us
+    fn set_key(
+        &mut self,
+        peer_name: &String,
+        device: DeviceId,
+        key: PublicKey,
+        signature: Vec<u8>,
+    ) {
+        for x in self.peers.iter_mut() {
+            if x.name == *peer_name && x.device == device {
+                x.set_key(key);
+                break;
+            }
+        }
+
+        let result = self
+            .storage
+            .set_identity_key(peer_name, device, key)
+            .and_then(|()| self.storage.set_key_signature(peer_name, device, signature));
+        if let Err(e) = result {
+            tracing::error!("failed to store key of {}, error: {}", peer_name, e);
+        }
+    }
+
+    fn signing_key(&self, peer_name: &str, device: DeviceId) -> Option<[u8; 32]> {
+        self.storage
+            .signing_key(peer_name, device)
+            .unwrap_or_else(|e| {
+                tracing::error!("failed to load signing key of {}, error: {}", peer_name, e);
+                None
+            })
+    }
+
+    // the first signing key a device announces is kept, every later key has to be signed with it
+    fn check_signing_key(&mut self, key: &PubKey) -> Result<(), NackReason> {
+        let announced = match key.signing_key {
+            Some(announced) if key.verify() => announced,
+            Some(_) => return Err(NackReason::InvalidSignature),
+            // devices that never announced a signing key cannot publish prekeys either
+            None => match self.signing_key(&key.user, key.device_id) {
+                Some(_) => return Err(NackReason::InvalidSignature),
+                None => return Ok(()),
+            },
+        };
+        match self.signing_key(&key.user, key.device_id) {
+            Some(pinned) if pinned == announced => Ok(()),
+            Some(_) => Err(NackReason::InvalidSignature),
+            None => self
+                .storage
+                .set_signing_key(&key.user, key.device_id, announced)
+                .map_err(|e| {
+                    tracing::error!("failed to store signing key of {}, error: {}", key.user, e);
+                    NackReason::Internal
+                }),
+        }
+    }
+
+    fn certificate(&self, peer_name: &str, device: DeviceId, key: PublicKey) -> String {
+        let certificate = SenderCertificate::new(
+            peer_name.to_owned(),
+            device,
+            key,
+            unix_time() + CERTIFICATE_LIFETIME,
+        )
+        .signed(&self.certificate_key);
+        serde_json::to_string(&Msg::SenderCertificate(certificate)).unwrap()
+    }
+
+    // sealed messages are only delivered with the access key the recipient handed out
+    fn may_deliver_sealed(&self, peer_name: &str, device: DeviceId, token: &[u8; 32]) -> bool {
+        match self.storage.access_key(peer_name, device) {
+            Ok(Some(hash)) => hash == access_key_hash(token),
+            Ok(None) => false,
+            Err(e) => {
+                tracing::error!("failed to load access key of {}, error: {}", peer_name, e);
+                false
+            }
+        }
+    }
+
+    // checks the bundle against the signing key kept for its device
+    fn verify(&self, bundle: &PreKeyBundle) -> bool {
+        match self.signing_key(&bundle.user, bundle.device_id) {
+            Some(key) => bundle.verify(&key),
+            None => false,
+        }
+    }
+
+    fn devices(&self, peer_name: &str) -> Vec<DeviceId> {
+        self.storage.devices(peer_name).unwrap_or_else(|e| {
+            tracing::error!("failed to load devices of {}, error: {}", peer_name, e);
+            Vec::new()
+        })
+    }
+
+    // keys of every linked device `user` may learn, including those that are currently offline
+    fn known_keys(&self, user: &str) -> Vec<PubKey> {
+        let users = match self.audience(user) {
+            Some(audience) => audience.into_iter().collect(),
+            None => match self.storage.users() {
+                Ok(users) => users,
+                Err(e) => {
+                    tracing::error!("failed to list registered users, error: {}", e);
+                    return Vec::new();
+                }
+            },
+        };
+        users.iter().flat_map(|x| self.device_keys(x)).collect()
+    }
+
+    // keys of every linked device of `user`
+    fn device_keys(&self, user: &str) -> Vec<PubKey> {
+        let mut keys = Vec::new();
+        for device in self.devices(user) {
+            match self.storage.identity_key(user, device) {
+                Ok(Some(key)) => {
+                    let mut k = PubKey::new(user.to_owned(), device, key);
+                    k.signing_key = self.signing_key(user, device);
+                    match self.storage.key_signature(user, device) {
+                        Ok(signature) => k.signature = signature.unwrap_or_default(),
+                        Err(e) => tracing::error!(
+                            "failed to load key signature of {}, error: {}",
+                            user,
+                            e
+                        ),
+                    }
+                    // the suite is announced again with every bundle
+                    if let Ok(Some(bundle)) = self.storage.prekey_bundle(user, device) {
+                        k.suite = bundle.suite;
+                    }
+                    keys.push(k);
+                }
+                Ok(None) => (),
+                Err(e) => tracing::error!("failed to load key of {}, error: {}", user, e),
+            }
+        }
+        keys
+    }
+
+    // whether `user` blocked `other`
+    fn has_blocked(&self, user: &str, other: &str) -> bool {
+        self.storage.has_blocked(user, other).unwrap_or_else(|e| {
+            tracing::error!("failed to load blocks of {}, error: {}", user, e);
+            false
+        })
+    }
+
+    // one page of the registered users matching `query`
+    fn list_users(&self, query: &UserQuery) -> Result<UserList, NackReason> {
+        let users = self.storage.users().map_err(|e| {
+            tracing::error!("failed to list registered users, error: {}", e);
+            NackReason::Internal
+        })?;
+        let matching = users
+            .into_iter()
+            .filter(|x| match &query.prefix {
+                Some(prefix) if self.list_users => x.starts_with(prefix.as_str()),
+                // without the directory only a name given in full is found
+                Some(prefix) => x == prefix,
+                None => self.list_users,
+            })
+            .collect::<Vec<_>>();
+        let limit = query.limit.unwrap_or(MAX_USER_LIST).min(MAX_USER_LIST);
+        let page = matching
+            .iter()
+            .skip(query.offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok(UserList::new(page, query.offset, matching.len()))
+    }
+
+    // records that `user` asked for `other`, who has to exist and not have blocked them
+    fn meet(&mut self, user: &str, other: &str) -> Result<(), NackReason> {
+        match self.storage.is_registered(other) {
+            // users who blocked someone look like they do not exist to them
+            Ok(true) if !self.has_blocked(other, user) => (),
+            Ok(_) => return Err(NackReason::UnknownRecipient),
+            Err(e) => {
+                tracing::error!("failed to look up user {}, error: {}", other, e);
+                return Err(NackReason::Internal);
+            }
+        }
+        if user != other {
+            if let Err(e) = self.storage.introduce(user, other) {
+                tracing::error!("failed to introduce {} to {}, error: {}", user, other, e);
+                return Err(NackReason::Internal);
+            }
+        }
+        Ok(())
+    }
+
+    // hands the keys and presence of `other`'s devices to the device that asked, and those of
+    // the asking user to `other`'s online devices, so either side can start sessions
+    async fn introduce(
+        &mut self,
+        user: &String,
+        device: DeviceId,
+        other: &str,
+    ) -> Result<(), NackReason> {
+        self.meet(user, other)?;
+
+        let mut theirs = self
+            .device_keys(other)
+            .into_iter()
+            .filter(|x| x.user != *user || x.device_id != device)
+            .map(Msg::PubKey)
+            .collect::<Vec<_>>();
+        for x in self.peers.iter().filter(|x| x.name == other) {
+            theirs.push(Msg::Presence(Presence::new(x.name.clone(), x.device, true)));
+        }
+        for msg in theirs {
+            let _ = self
+                .send(user, device, &serde_json::to_string(&msg).unwrap())
+                .await;
+        }
+
+        if user == other {
+            return Ok(());
+        }
+        let mut ours = self
+            .device_keys(user)
+            .into_iter()
+            .map(Msg::PubKey)
+            .collect::<Vec<_>>();
+        for x in self.peers.iter().filter(|x| x.name == *user) {
+            ours.push(Msg::Presence(Presence::new(x.name.clone(), x.device, true)));
+        }
+        for msg in ours {
+            let msg = serde_json::to_string(&msg).unwrap();
+            for x in self.peers.iter().filter(|x| x.name == other) {
+                if let Err(e) = x.tx.send(msg.clone()) {
+                    tracing::error!("failed to send message to {}, msg: {}", x.name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // hands the prekey bundle of `other` to the device that asked, like a key it is only given
+    // to users `other` has not blocked
+    async fn send_prekeys(
+        &mut self,
+        user: &String,
+        device: DeviceId,
+        other: &Device,
+    ) -> Result<(), NackReason> {
+        self.meet(user, &other.user)?;
+        match self.storage.prekey_bundle(&other.user, other.device_id) {
+            Ok(Some(bundle)) => {
+                let reply = Msg::PreKeyBundle(bundle);
+                let _ = self
+                    .send(user, device, &serde_json::to_string(&reply).unwrap())
+                    .await;
+                Ok(())
+            }
+            Ok(None) => Err(NackReason::NoPreKeys),
+            Err(e) => {
+                tracing::error!("failed to load prekeys of {}, error: {}", other.user, e);
+                Err(NackReason::Internal)
+            }
+        }
+    }
+
+    // delivers `msg` to the device if it is online, queues it if it is only offline
+    fn route(
+        &mut self,
+        peer_name: &String,
+        device: DeviceId,
+        msg: String,
+        ttl: Option<u64>,
+    ) -> Result<(), NackReason> {
+        if let Some(peer) = self.get_device(peer_name, device) {
+            if let Err(e) = peer.tx.send(msg) {
+                tracing::error!("username `{}` has no matching socket, {}", peer_name, e);
+                return Err(NackReason::Internal);
+            }
+            Ok(())
+        } else if self.devices(peer_name).contains(&device) {
+            self.queue(peer_name, device, msg, ttl)
+        } else {
+            tracing::info!(
+                "tried to send message to nonexisting user {} device {}",
+                peer_name,
+                device
+            );
+            Err(NackReason::UnknownRecipient)
+        }
+    }
+
+    fn queue(
+        &mut self,
+        peer_name: &str,
+        device: DeviceId,
+        msg: String,
+        ttl: Option<u64>,
+    ) -> Result<(), NackReason> {
+        let expires_at = ttl.map(|x| unix_time().saturating_add(x));
+        match self
+            .storage
+            .queue_message(peer_name, device, msg, expires_at)
+        {
+            Ok(()) => {
+                tracing::info!(
+                    "queued message for offline user {} device {}",
+                    peer_name,
+                    device
+                );
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("failed to queue message for {}, error: {}", peer_name, e);
+                Err(NackReason::Internal)
+            }
+        }
+    }
+
+    // drops the connections of the devices `f` matches after telling them why, their sessions
+    // end once they read that
+    fn disconnect(&mut self, f: impl Fn(&Data) -> bool, why: &str) -> Vec<String> {
+        let info = serde_json::to_string(&Msg::Info(Info::new(why.to_owned()))).unwrap();
+        let mut disconnected = Vec::new();
+        self.peers.retain(|x| {
+            if !f(x) {
+                return true;
+            }
+            let _ = x.tx.send(info.clone());
+            disconnected.push(format!("disconnected {} {} {}", x.name, x.device, x.addr));
+            false
+        });
+        disconnected
+    }
+
+    fn administer(&mut self, command: Command) -> Result<Vec<String>, String> {
+        match command {
+            Command::List => {
+                let mut peers = self
+                    .peers
+                    .iter()
+                    .map(|x| format!("{} {} {}", x.name, x.device, x.addr))
+                    .collect::<Vec<_>>();
+                peers.sort();
+                Ok(peers)
+            }
+            Command::Kick(user, device) => {
+                let kicked = self.disconnect(
+                    |x| x.name == user && device.is_none_or(|d| x.device == d),
+                    "disconnected by the administrator",
+                );
+                if kicked.is_empty() {
+                    return Err(format!("{} is not connected", user));
+                }
+                Ok(kicked)
+            }
+            Command::Ban(target) => {
+                let kicked = self.disconnect(
+                    |x| match &target {
+                        Target::User(user) => x.name == *user,
+                        Target::Ip(ip) => x.addr.ip() == *ip,
+                    },
+                    "banned from this server",
+                );
+                self.bans.insert(target);
+                Ok(kicked)
+            }
+            Command::Unban(target) => {
+                if !self.bans.remove(&target) {
+                    return Err(format!("{} is not banned", target));
+                }
+                Ok(Vec::new())
+            }
+            Command::Bans => {
+                let mut bans = self.bans.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                bans.sort();
+                Ok(bans)
+            }
+            Command::Broadcast(text) => {
+                let info = serde_json::to_string(&Msg::Info(Info::new(text))).unwrap();
+                for x in self.peers.iter() {
+                    if let Err(e) = x.tx.send(info.clone()) {
+                        tracing::error!("failed to send message to {}, msg: {}", x.name, e);
+                    }
+                }
+                Ok(vec![format!("sent to {} devices", self.peers.len())])
+            }
+            Command::Motd(None) => Ok(vec![self.info.motd.clone()]),
+            Command::Motd(Some(motd)) => {
+                self.info.motd = motd;
+                Ok(Vec::new())
+            }
+            Command::Help => Ok(admin::HELP.lines().map(str::to_owned).collect()),
+        }
+    }
+
+    // answers a request, messages that were not sent as a request are only answered when refused
+    fn respond(
+        &self,
+        peer_name: &String,
+        device: DeviceId,
+        id: Option<u64>,
+        result: Result<(), NackReason>,
+    ) {
+        let msg = match (id, result) {
+            (Some(id), Ok(())) => Msg::Ack(Ack::new(id)),
+            (None, Ok(())) => return,
+            (id, Err(reason)) => Msg::Nack(Nack::new(id, reason)),
+        };
+        if let Some(peer) = self.get_device(peer_name, device) {
+            if let Err(e) = peer.tx.send(serde_json::to_string(&msg).unwrap()) {
+                tracing::error!("failed to send message to {}, msg: {}", peer_name, e);
+            }
+        }
+    }
+}
+
+// token bucket over the messages a connection sends to other devices
+struct RateLimit {
+    per_second: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimit {
+    // allows bursts of up to a second's worth of messages
+    fn new(per_second: usize) -> Self {
+        RateLimit {
+            per_second: per_second as f64,
+            tokens: per_second as f64,
+            last: Instant::now(),
+        }
+    }
+
+    // takes a token, or returns the seconds until there is one
+    fn take(&mut self) -> Result<(), NackReason> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.per_second;
+        self.tokens = (self.tokens + refill).min(self.per_second);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let retry_after = ((1.0 - self.tokens) / self.per_second).ceil() as u64;
+        Err(NackReason::RateLimited { retry_after })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Peer<S> {
+    async fn new(
+        state: Arc<Mutex<Shared>>,
+        lines: Framed<S, LinesCodec>,
+        addr: SocketAddr,
+        username: &String,
+        device: DeviceId,
+    ) -> io::Result<Peer<S>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        for key in state.lock().await.known_keys(username) {
+            if key.user == *username && key.device_id == device {
+                continue;
+            }
+            let k = Msg::PubKey(key);
+            if let Err(e) = tx.send(serde_json::to_string(&k).unwrap()) {
+                tracing::error!("failed to send message to {}, msg: {}", username, e);
+            }
+        }
+
+        let mut st = state.lock().await;
+        let audience = st.audience(username);
+        for x in st.peers.iter() {
+            if audience.as_ref().is_some_and(|a| !a.contains(&x.name)) {
+                continue;
+            }
+            let p = Msg::Presence(Presence::new(x.name.clone(), x.device, true));
+            if let Err(e) = tx.send(serde_json::to_string(&p).unwrap()) {
+                tracing::error!("failed to send message to {}, msg: {}", username, e);
+            }
+        }
+        st.announce(
+            addr,
+            username,
+            &Msg::Presence(Presence::new(username.to_owned(), device, true)),
+        );
+
+        st.peers.push(Data {
+            addr,
+            name: username.to_owned(),
+            device,
+            tx,
+            pub_key: None,
+        });
+
+        Ok(Peer { lines, rx })
+    }
+}
+
+async fn reply<S: AsyncRead + AsyncWrite + Unpin>(lines: &mut Framed<S, LinesCodec>, msg: &Msg) {
+    if let Err(e) = lines.send(serde_json::to_string(msg).unwrap()).await {
+        tracing::error!("failed to send error message, msg: {}", e);
+    }
+}
+
+async fn reject<S: AsyncRead + AsyncWrite + Unpin>(
+    lines: &mut Framed<S, LinesCodec>,
+    error: String,
+) {
+    reply(lines, &Msg::Err(ErrMessage::new(error))).await;
+}
+
+async fn process<S: AsyncRead + AsyncWrite + Unpin>(
+    state: Arc<Mutex<Shared>>,
+    stream: S,
+    addr: SocketAddr,
+    max_line_length: usize,
+    rate_limit: usize,
+    metrics: Metrics,
+) -> Result<(), Box<dyn Error>> {
+    let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(max_line_length));
+    let challenge = Challenge::new();
+    reply(&mut lines, &Msg::Challenge(challenge.clone())).await;
+
+    // try to get username
+    let (username, device, id) = match lines.next().await {
+        Some(Ok(line)) => {
+            metrics.received_bytes.inc_by(line.len() as u64 + 1);
+            let msg: Msg = match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    tracing::info!("client {} sent a malformed message; error = {}", addr, e);
+                    metrics.decode_failed("malformed");
+                    metrics.registration_failed("malformed");
+                    let nack = Nack::new(None, NackReason::Malformed);
+                    reply(&mut lines, &Msg::Nack(nack)).await;
+                    return Ok(());
+                }
+            };
+
+            match msg.into_request() {
+                (id, Msg::Register(msg)) => {
+                    let mut st = state.lock().await;
+
+                    if st.bans.contains(&Target::User(msg.client_name.clone())) {
+                        drop(st);
+                        tracing::info!("refused banned user {}", msg.client_name);
+                        metrics.registration_failed("banned");
+                        let nack = Nack::new(id, NackReason::Banned);
+                        reply(&mut lines, &Msg::Nack(nack)).await;
+                        return Ok(());
+                    }
+
+                    if st.get_device(&msg.client_name, msg.device_id).is_some() {
+                        drop(st);
+                        metrics.registration_failed("already_connected");
+                        let nack = Nack::new(id, NackReason::AlreadyConnected);
+                        reply(&mut lines, &Msg::Nack(nack)).await;
+                        return Ok(());
+                    }
+
+                    // a device with a signing key has to prove it holds it, one without gets
+                    // the first it registers with
+                    let pinned = st.signing_key(&msg.client_name, msg.device_id);
+                    let authenticated = match (pinned, msg.signing_key) {
+                        (Some(key), _) | (None, Some(key)) => msg.verify(&challenge, &key),
+                        (None, None) => true,
+                    };
+                    if !authenticated {
+                        drop(st);
+                        tracing::info!(
+                            "{} failed to prove it holds the signing key of device {}",
+                            msg.client_name,
+                            msg.device_id
+                        );
+                        metrics.registration_failed("invalid_signature");
+                        let nack = Nack::new(id, NackReason::InvalidSignature);
+                        reply(&mut lines, &Msg::Nack(nack)).await;
+                        return Ok(());
+                    }
+
+                    match st.storage.register_user(&msg.client_name) {
+                        Ok(true) => {
+                            tracing::info!("registered new user {}", msg.client_name);
+                            if let Err(e) = st.storage.link_device(&msg.client_name, msg.device_id)
+                            {
+                                tracing::error!(
+                                    "failed to link device of {}, error: {}",
+                                    msg.client_name,
+                                    e
+                                );
+                            }
+                        }
+                        // new devices have to be linked from a device that is already linked
+                        Ok(false) if !st.devices(&msg.client_name).contains(&msg.device_id) => {
+                            drop(st);
+                            tracing::info!(
+                                "{} tried to log in with unlinked device {}",
+                                msg.client_name,
+                                msg.device_id
+                            );
+                            metrics.registration_failed("not_registered");
+                            let nack = Nack::new(id, NackReason::NotRegistered);
+                            reply(&mut lines, &Msg::Nack(nack)).await;
+                            return Ok(());
+                        }
+                        Ok(false) => (),
+                        Err(e) => tracing::error!(
+                            "failed to register user {}, error: {}",
+                            msg.client_name,
+                            e
+                        ),
+                    }
+                    if let (None, Some(key)) = (pinned, msg.signing_key) {
+                        if let Err(e) =
+                            st.storage
+                                .set_signing_key(&msg.client_name, msg.device_id, key)
+                        {
+                            tracing::error!(
+                                "failed to store signing key of {}, error: {}",
+                                msg.client_name,
+                                e
+                            );
+                        }
+                    }
+                    (msg.client_name, msg.device_id, id)
+                }
+                (id, _) => {
+                    tracing::error!(
+                        "client {} did not send a register message. msg: {}",
+                        addr,
+                        line
+                    );
+                    metrics.registration_failed("not_a_registration");
+                    let nack = Nack::new(id, NackReason::NotRegistered);
+                    reply(&mut lines, &Msg::Nack(nack)).await;
+                    return Ok(());
+                }
+            }
+        }
+        _ => {
+            tracing::error!("failed to parse register message. client: {}", addr);
+            return Ok(());
+        }
+    };
+
+    let mut peer = Peer::new(state.clone(), lines, addr, &username, device).await?;
+    tracing::info!(
+        "{} has connected to the server with device {}",
+        &username,
+        device
+    );
+
+    {
+        let mut st = state.lock().await;
+        st.respond(&username, device, id, Ok(()));
+
+        // deliver everything that arrived while the device was offline
+        match st.storage.take_queued(&username, device) {
+            Ok(queued) => {
+                for msg in queued {
+                    let _ = st.send(&username, device, &msg).await;
+                }
+            }
+            Err(e) => tracing::error!("failed to load queue of {}, error: {}", username, e),
+        }
+    }
+
+    let mut limit = RateLimit::new(rate_limit);
+    // the codec ends the stream once after a line that is too long, reading goes on after that
+    let mut errored = false;
+
+    loop {
+        tokio::select! {
+        msg = peer.rx.recv() => match msg {
+            Some(msg) => {
+                metrics.sent_bytes.inc_by(msg.len() as u64 + 1);
+                peer.lines.send(&msg).await?
+            }
+            // the device was unlinked
+            None => break,
+        },
+        result = peer.lines.next() => match result {
+            Some(Ok(message)) => {
+                metrics.received_bytes.inc_by(message.len() as u64 + 1);
+                let msg: Msg = match serde_json::from_str(&message) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        tracing::info!("{} sent a malformed message; error = {}", username, e);
+                        metrics.decode_failed("malformed");
+                        state.lock().await.respond(&username, device, None, Err(NackReason::Malformed));
+                        continue;
+                    }
+                };
+                let (id, msg) = msg.into_request();
+                // what is passed on to other devices, without the request around it
+                let forward = serde_json::to_string(&msg).unwrap();
+                let kind = match &msg {
+                    Msg::EncryptedMessage(_) => Some("encrypted"),
+                    Msg::SealedMessage(_) => Some("sealed"),
+                    Msg::SessionReset(_) => Some("session_reset"),
+                    _ => None,
+                };
+                let state = &mut state.lock().await;
+                // messages that name their sender have to come from it, sealed ones name nobody
+                let sender = match &msg {
+                    Msg::EncryptedMessage(msg) => Some((&msg.sender_name, msg.sender_device)),
+                    Msg::SessionReset(msg) => Some((&msg.sender_name, msg.sender_device)),
+                    _ => None,
+                };
+                if sender.is_some_and(|(name, d)| *name != username || d != device) {
+                    tracing::error!("{} (device {}) sent a message in the name of another device", username, device);
+                    state.respond(&username, device, id, Err(NackReason::NotPermitted));
+                    continue;
+                }
+                // the sender is answered as if the message was delivered, sealed messages are not
+                // looked into and left for the recipient's client to drop
+                let recipient = match &msg {
+                    Msg::EncryptedMessage(msg) => Some(&msg.recv_name),
+                    Msg::SessionReset(msg) => Some(&msg.recv_name),
+                    _ => None,
+                };
+                if recipient.is_some_and(|x| state.has_blocked(x, &username)) {
+                    tracing::info!("dropped message from {} to a user who blocked them", username);
+                    metrics.blocked();
+                    state.respond(&username, device, id, Ok(()));
+                    continue;
+                }
+                // lookups are charged like messages, or names could be guessed at line rate
+                if matches!(msg, Msg::KeyRequest(_) | Msg::PreKeyRequest(_) | Msg::ListUsers(_)) {
+                    if let Err(e) = limit.take() {
+                        state.respond(&username, device, id, Err(e));
+                        continue;
+                    }
+                }
+                let result = match msg {
+                    Msg::EncryptedMessage(msg) => limit
+                        .take()
+                        .and_then(|()| state.route(&msg.recv_name, msg.recv_device, forward, msg.ttl)),
+                    Msg::SessionReset(msg) => state.route(&msg.recv_name, msg.recv_device, forward, None),
+                    Msg::SealedMessage(msg) => {
+                        if !state.may_deliver_sealed(&msg.recv_name, msg.recv_device, &msg.delivery_token) {
+                            tracing::info!("rejected sealed message with an invalid delivery token");
+                            Err(NackReason::InvalidDeliveryToken)
+                        } else {
+                            limit.take().and_then(|()| state.route(&msg.recv_name, msg.recv_device, forward, msg.ttl))
+                        }
+                    },
+                    Msg::ServerInfoRequest => {
+                        let info = serde_json::to_string(&Msg::ServerInfo(state.info.clone())).unwrap();
+                        let _ = state.send(&username, device, &info).await;
+                        Ok(())
+                    },
+                    Msg::ListUsers(query) => match state.list_users(&query) {
+                        Ok(list) => {
+                            let reply = serde_json::to_string(&Msg::UserList(list)).unwrap();
+                            let _ = state.send(&username, device, &reply).await;
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    },
+                    Msg::KeyRequest(user) => state.introduce(&username, device, &user).await,
+                    Msg::AccessKey(k) => match state.storage.set_access_key(&username, device, k.hash) {
+                        Ok(()) => Ok(()),
+                        Err(e) => {
+                            tracing::error!("failed to store access key of {}, error: {}", username, e);
+                            Err(NackReason::Internal)
+                        }
+                    },
+                    Msg::PreKeyRequest(d) => state.send_prekeys(&username, device, &d).await,
+                    Msg::PubKey(msg) => match state.get_device(&username, device) {
+                        // later keys have to come as signed rotations
+                        Some(p) if p.pub_key.is_some() => {
+                            tracing::error!("{} tried to replace its key without signing it", username);
+                            Err(NackReason::NotPermitted)
+                        }
+                        Some(_) => {
+                            let k = PubKey {
+                                user: username.clone(),
+                                device_id: device,
+                                ..msg
+                            };
+                            if let Err(e) = state.check_signing_key(&k) {
+                                tracing::error!("{} announced a key not signed with the signing key of device {}", username, device);
+                                state.respond(&username, device, id, Err(e));
+                                continue;
+                            }
+                            state.set_key(&username, device, k.public_key, k.signature.clone());
+
+                            let certificate = state.certificate(&username, device, k.public_key);
+                            state.announce(addr, &username, &Msg::PubKey(k));
+                            let _ = state.send(&username, device, &certificate).await;
+                            Ok(())
+                        }
+                        None => Ok(()),
+                    },
+                    Msg::PreKeyBundle(bundle) | Msg::RotateKey(bundle) if bundle.user != username || bundle.device_id != device => {
+                        tracing::error!("{} tried to publish prekeys of {}", username, bundle.user);
+                        Err(NackReason::NotPermitted)
+                    },
+                    Msg::PreKeyBundle(bundle) => {
+                        if !state.verify(&bundle) {
+                            tracing::error!("{} published prekeys with an invalid signature", username);
+                            Err(NackReason::InvalidSignature)
+                        } else if let Err(e) = state.storage.set_prekey_bundle(bundle) {
+                            tracing::error!("failed to store prekeys of {}, error: {}", username, e);
+                            Err(NackReason::Internal)
+                        } else {
+                            Ok(())
+                        }
+                    },
+                    Msg::RotateKey(bundle) => {
+                        let signing_key = match state.signing_key(&username, device) {
+                            Some(key) if bundle.verify(&key) => key,
+                            _ => {
+                                tracing::error!("{} sent a key rotation with an invalid signature", username);
+                                state.respond(&username, device, id, Err(NackReason::InvalidSignature));
+                                continue;
+                            }
+                        };
+
+                        tracing::info!("{} rotated the key of device {}", username, device);
+                        // peers start new sessions with the fresh key, checked like the first one
+                        let k = bundle.announced_key(signing_key);
+                        let key = k.public_key;
+                        state.set_key(&username, device, key, k.signature.clone());
+                        if let Err(e) = state.storage.set_prekey_bundle(bundle) {
+                            tracing::error!("failed to store prekeys of {}, error: {}", username, e);
+                        }
+                        state.announce(addr, &username, &Msg::PubKey(k));
+
+                        let certificate = state.certificate(&username, device, key);
+                        let _ = state.send(&username, device, &certificate).await;
+                        Ok(())
+                    },
+                    Msg::Block(user) | Msg::Unblock(user) if user == username => Err(NackReason::NotPermitted),
+                    Msg::Block(user) => match state.storage.block(&username, &user) {
+                        Ok(()) => Ok(()),
+                        Err(e) => {
+                            tracing::error!("failed to store block of {}, error: {}", username, e);
+                            Err(NackReason::Internal)
+                        }
+                    },
+                    Msg::Unblock(user) => match state.storage.unblock(&username, &user) {
+                        Ok(_) => Ok(()),
+                        Err(e) => {
+                            tracing::error!("failed to remove block of {}, error: {}", username, e);
+                            Err(NackReason::Internal)
+                        }
+                    },
+                    Msg::LinkDevice(d) if d.user == username => {
+                        // the new device registers with the signing key it is linked with
+                        let signing_key = match d.signing_key {
+                            Some(signing_key) => signing_key,
+                            None => {
+                                state.respond(&username, device, id, Err(NackReason::Malformed));
+                                continue;
+                            }
+                        };
+                        // an unlinked device keeps its key, another one would let whoever holds it in
+                        if state.signing_key(&username, d.device_id).is_some_and(|x| x != signing_key) {
+                            tracing::error!("{} tried to link device {} with another signing key", username, d.device_id);
+                            state.respond(&username, device, id, Err(NackReason::NotPermitted));
+                            continue;
+                        }
+                        let result = state.storage.link_device(&username, d.device_id).and_then(|linked| {
+                            // a device that is linked already keeps its key
+                            if linked {
+                                state.storage.set_signing_key(&username, d.device_id, signing_key)?;
+                            }
+                            Ok(linked)
+                        });
+                        match result {
+                            Ok(linked) => {
+                                let info = if linked {
+                                    format!("device {} linked", d.device_id)
+                                } else {
+                                    format!("device {} is already linked", d.device_id)
+                                };
+                                let _ = state.send(&username, device, &serde_json::to_string(&Msg::Info(Info::new(info))).unwrap()).await;
+                                Ok(())
+                            }
+                            Err(e) => {
+                                tracing::error!("failed to link device of {}, error: {}", username, e);
+                                Err(NackReason::Internal)
+                            }
+                        }
+                    },
+                    Msg::UnlinkDevice(d) if d.user == username => {
+                        match state.storage.unlink_device(&username, d.device_id) {
+                            Ok(true) => {
+                                tracing::info!("{} unlinked device {}", username, d.device_id);
+                                // dropping the sender disconnects the device if it is online
+                                state.peers.retain(|x| x.name != username || x.device != d.device_id);
+                                state.announce(addr, &username, &Msg::UnlinkDevice(d));
+                                Ok(())
+                            }
+                            Ok(false) => Err(NackReason::UnknownRecipient),
+                            Err(e) => {
+                                tracing::error!("failed to unlink device of {}, error: {}", username, e);
+                                Err(NackReason::Internal)
+                            }
+                        }
+                    },
+                    Msg::LinkDevice(d) | Msg::UnlinkDevice(d) => {
+                        tracing::error!("{} tried to change devices of {}", username, d.user);
+                        Err(NackReason::NotPermitted)
+                    },
+                    _ => Ok(()),
+                };
+                match (kind, &result) {
+                    (Some(kind), Ok(())) => metrics.relayed(kind),
+                    (Some(_), Err(e)) => metrics.dropped(e),
+                    (None, _) => (),
+                }
+                state.respond(&username, device, id, result);
+            }
+            // the codec skips the rest of the line, it is answered without an id
+            Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                tracing::info!("{} sent a line longer than the limit", username);
+                metrics.decode_failed("too_large");
+                state.lock().await.respond(&username, device, None, Err(NackReason::TooLarge));
+                errored = true;
+            }
+            Some(Err(e)) => {
+                tracing::error!("failed to read messages: {:?}", e);
+            }
+            None if errored => errored = false,
+            None => break,
+            },
+        }
+    }
+
+    let mut state = state.lock().await;
+    for (i, x) in state.peers.iter().enumerate() {
+        if x.addr == addr {
+            state.peers.remove(i);
+            break;
+        }
+    }
+    state.announce(
+        addr,
+        &username,
+        &Msg::Presence(Presence::new(username.clone(), device, false)),
+    );
+
+    tracing::info!("{} has disconnected from the server", username);
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use crate::crypto::State;
+use crate::message::{DeviceId, EncryptedMessage, Message, ERR_DUPLICATE};
+use crate::padding::Padding;
+
+/// Ratchet state with another device that also copes with both sides sending first.
+///
+/// If both sides start the session at the same time each of them ratchets away from
+/// the common initial state, and the first message of either side only decrypts with
+/// the initial state. The tie is broken by comparing `(user, device)` of both sides:
+/// the smaller one keeps its own session, the other one throws its own away and
+/// continues from the winner's first message. Messages the loser sent before it
+/// noticed are still decrypted by the winner on a separate branch.
+pub struct Session {
+    state: State,
+    // state before the other side was known to use our session
+    initial: Option<State>,
+    // the loser's side of a crossed start, only kept by the winner
+    crossed: Option<State>,
+    wins_ties: bool,
+}
+
+impl Session {
+    pub fn new(state: State, local: (&str, DeviceId), remote: (&str, DeviceId)) -> Self {
+        Session {
+            initial: Some(state.clone()),
+            state,
+            crossed: None,
+            wins_ties: local < remote,
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Changes how messages sent from now on are padded, received ones are unpadded either way.
+    pub fn set_padding(&mut self, padding: Padding) {
+        for state in std::iter::once(&mut self.state)
+            .chain(self.initial.as_mut())
+            .chain(self.crossed.as_mut())
+        {
+            state.padding = padding;
+        }
+    }
+
+    pub fn encrypt(&mut self, msg: &Message) -> Result<EncryptedMessage, u32> {
+        let (encrypted, state) = msg.encrypt(&self.state)?;
+        self.state = state;
+        Ok(encrypted)
+    }
+
+    pub fn decrypt(&mut self, msg: &EncryptedMessage) -> Result<Message, u32> {
+        let error = match msg.decrypt(&self.state) {
+            Ok((decrypted, state)) => {
+                // the other side is on our session, no crossed start is possible anymore
+                self.state = state;
+                self.initial = None;
+                self.crossed = None;
+                return Ok(decrypted);
+            }
+            Err(e) => e,
+        };
+
+        // a message seen on any branch before is a duplicate, not a sign the sessions diverged
+        let mut duplicate = error == ERR_DUPLICATE;
+        if let Some(crossed) = &self.crossed {
+            match msg.decrypt(crossed) {
+                Ok((decrypted, state)) => {
+                    self.crossed = Some(state);
+                    return Ok(decrypted);
+                }
+                Err(e) => duplicate |= e == ERR_DUPLICATE,
+            }
+        }
+
+        if let Some(initial) = &self.initial {
+            match msg.decrypt(initial) {
+                Ok((decrypted, state)) => {
+                    if self.wins_ties {
+                        self.crossed = Some(state);
+                    } else {
+                        self.state = state;
+                    }
+                    self.initial = None;
+                    return Ok(decrypted);
+                }
+                Err(e) => duplicate |= e == ERR_DUPLICATE,
+            }
+        }
+
+        Err(if duplicate { ERR_DUPLICATE } else { error })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    fn pair() -> (Session, Session) {
+        let (alice_keys, bob_keys) = (KeyPair::new(), KeyPair::new());
+        let alice = Session::new(
+            State::new(alice_keys.clone(), bob_keys.public()),
+            ("alice", 0),
+            ("bob", 0),
+        );
+        let bob = Session::new(
+            State::new(bob_keys, alice_keys.public()),
+            ("bob", 0),
+            ("alice", 0),
+        );
+        (alice, bob)
+    }
+
+    fn send(from: &mut Session, text: &str) -> EncryptedMessage {
+        let msg = Message::new(
+            text.to_owned(),
+            ("from".to_owned(), 0),
+            ("to".to_owned(), 0),
+            from.state().key_pair().public(),
+        );
+        from.encrypt(&msg).unwrap()
+    }
+
+    fn recv(to: &mut Session, msg: &EncryptedMessage) -> String {
+        to.decrypt(msg).unwrap().msg
+    }
+
+    #[test]
+    fn one_side_starts() {
+        let (mut alice, mut bob) = pair();
+
+        let m = send(&mut alice, "hi bob");
+        assert_eq!(recv(&mut bob, &m), "hi bob");
+        let m = send(&mut bob, "hi alice");
+        assert_eq!(recv(&mut alice, &m), "hi alice");
+        let m = send(&mut alice, "again");
+        assert_eq!(recv(&mut bob, &m), "again");
+    }
+
+    #[test]
+    fn concurrent_first_messages() {
+        let (mut alice, mut bob) = pair();
+
+        let to_bob = send(&mut alice, "hi bob");
+        let to_alice = send(&mut bob, "hi alice");
+
+        assert_eq!(recv(&mut bob, &to_bob), "hi bob");
+        assert_eq!(recv(&mut alice, &to_alice), "hi alice");
+
+        let m = send(&mut bob, "after");
+        assert_eq!(recv(&mut alice, &m), "after");
+        let m = send(&mut alice, "after too");
+        assert_eq!(recv(&mut bob, &m), "after too");
+    }
+
+    #[test]
+    fn concurrent_first_messages_delivered_in_other_order() {
+        let (mut alice, mut bob) = pair();
+
+        let to_bob = send(&mut alice, "hi bob");
+        let to_alice = send(&mut bob, "hi alice");
+
+        assert_eq!(recv(&mut alice, &to_alice), "hi alice");
+        assert_eq!(recv(&mut bob, &to_bob), "hi bob");
+
+        let m = send(&mut alice, "after");
+        assert_eq!(recv(&mut bob, &m), "after");
+        let m = send(&mut bob, "after too");
+        assert_eq!(recv(&mut alice, &m), "after too");
+    }
+
+    #[test]
+    fn concurrent_bursts() {
+        let (mut alice, mut bob) = pair();
+
+        let to_bob = (0..3)
+            .map(|i| send(&mut alice, &format!("a{}", i)))
+            .collect::<Vec<_>>();
+        let to_alice = (0..3)
+            .map(|i| send(&mut bob, &format!("b{}", i)))
+            .collect::<Vec<_>>();
+
+        for (i, m) in to_alice.iter().enumerate() {
+            assert_eq!(recv(&mut alice, m), format!("b{}", i));
+        }
+        for (i, m) in to_bob.iter().enumerate() {
+            assert_eq!(recv(&mut bob, m), format!("a{}", i));
+        }
+
+        for i in 0..3 {
+            let m = send(&mut bob, &format!("b{}", i + 3));
+            assert_eq!(recv(&mut alice, &m), format!("b{}", i + 3));
+            let m = send(&mut alice, &format!("a{}", i + 3));
+            assert_eq!(recv(&mut bob, &m), format!("a{}", i + 3));
+        }
+    }
+
+    #[test]
+    fn loser_keeps_sending_before_it_notices() {
+        let (mut alice, mut bob) = pair();
+
+        let to_bob = send(&mut alice, "hi bob");
+        let first = send(&mut bob, "hi alice");
+        let second = send(&mut bob, "still there?");
+
+        // alice wins the tie and reads both of bob's messages on the crossed branch
+        assert_eq!(recv(&mut alice, &first), "hi alice");
+        assert_eq!(recv(&mut alice, &second), "still there?");
+
+        assert_eq!(recv(&mut bob, &to_bob), "hi bob");
+        let m = send(&mut bob, "switched");
+        assert_eq!(recv(&mut alice, &m), "switched");
+    }
+
+    #[test]
+    fn duplicates_are_recognized() {
+        let (mut alice, mut bob) = pair();
+
+        // on the crossed branch
+        let to_bob = send(&mut alice, "hi bob");
+        let first = send(&mut bob, "hi alice");
+        assert_eq!(recv(&mut alice, &first), "hi alice");
+        assert_eq!(alice.decrypt(&first).unwrap_err(), ERR_DUPLICATE);
+
+        // on the session itself, also once the chain it came on ended
+        assert_eq!(recv(&mut bob, &to_bob), "hi bob");
+        let m = send(&mut bob, "switched");
+        assert_eq!(recv(&mut alice, &m), "switched");
+        assert_eq!(alice.decrypt(&m).unwrap_err(), ERR_DUPLICATE);
+        let reply = send(&mut alice, "reply");
+        assert_eq!(recv(&mut bob, &reply), "reply");
+        let m2 = send(&mut bob, "new chain");
+        assert_eq!(recv(&mut alice, &m2), "new chain");
+        assert_eq!(alice.decrypt(&m).unwrap_err(), ERR_DUPLICATE);
+    }
+
+    #[test]
+    fn padding_hides_length() {
+        let (mut alice, mut bob) = pair();
+        alice.set_padding(Padding::Buckets);
+
+        let (short, long) = (
+            "hi".repeat(40),
+            "a somewhat longer message than that".repeat(4),
+        );
+        let short_msg = send(&mut alice, &short);
+        let long_msg = send(&mut alice, &long);
+        assert_eq!(short_msg.encrypted_msg.len(), long_msg.encrypted_msg.len());
+        assert_eq!(recv(&mut bob, &short_msg), short);
+        assert_eq!(recv(&mut bob, &long_msg), long);
+
+        // replies are not padded, bob did not ask for it
+        let m = send(&mut bob, "ok");
+        assert_eq!(recv(&mut alice, &m), "ok");
+        assert!(m.encrypted_msg.len() < short_msg.encrypted_msg.len());
+    }
+
+    #[test]
+    fn garbage_does_not_break_the_session() {
+        let (mut alice, mut bob) = pair();
+
+        let mut m = send(&mut alice, "hi bob");
+        let good = m.encrypted_msg.clone();
+        m.encrypted_msg[0] ^= 1;
+        assert!(bob.decrypt(&m).is_err());
+
+        m.encrypted_msg = good;
+        assert_eq!(recv(&mut bob, &m), "hi bob");
+    }
+}
@@ -0,0 +1,943 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use x25519_dalek::PublicKey;
+
+use crate::message::{unix_time, DeviceId, PreKeyBundle};
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Db(sled::Error),
+    Encoding(serde_json::Error),
+    UnknownSchema(u32),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Db(e) => write!(f, "database error: {}", e),
+            StorageError::Encoding(e) => write!(f, "encoding error: {}", e),
+            StorageError::UnknownSchema(v) => {
+                write!(f, "database schema version {} is newer than supported", v)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        StorageError::Db(e)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Encoding(e)
+    }
+}
+
+/// Persistent state of the relay: registered users, the devices linked to them,
+/// their announced keys and the messages waiting for devices that are currently offline.
+pub trait Storage: Send {
+    /// Registers `user`, returns `false` if the name was already registered.
+    fn register_user(&mut self, user: &str) -> Result<bool>;
+    fn is_registered(&self, user: &str) -> Result<bool>;
+    fn users(&self) -> Result<Vec<String>>;
+
+    /// Links `device` to `user`, returns `false` if it was already linked.
+    fn link_device(&mut self, user: &str, device: DeviceId) -> Result<bool>;
+    /// Unlinks `device` and drops its keys and queue, returns `false` if it was not linked. The
+    /// signing key is kept, see `set_signing_key`.
+    fn unlink_device(&mut self, user: &str, device: DeviceId) -> Result<bool>;
+    fn devices(&self, user: &str) -> Result<Vec<DeviceId>>;
+
+    fn set_identity_key(&mut self, user: &str, device: DeviceId, key: PublicKey) -> Result<()>;
+    fn identity_key(&self, user: &str, device: DeviceId) -> Result<Option<PublicKey>>;
+    /// Signature of the signing key over the identity key, handed to peers along with the key.
+    fn set_key_signature(&mut self, user: &str, device: DeviceId, signature: Vec<u8>)
+        -> Result<()>;
+    fn key_signature(&self, user: &str, device: DeviceId) -> Result<Option<Vec<u8>>>;
+
+    /// Ed25519 key the device registers with and signs its keys with. It outlives unlinking, so
+    /// the device cannot be linked again with another key.
+    fn set_signing_key(&mut self, user: &str, device: DeviceId, key: [u8; 32]) -> Result<()>;
+    fn signing_key(&self, user: &str, device: DeviceId) -> Result<Option<[u8; 32]>>;
+
+    fn set_prekey_bundle(&mut self, bundle: PreKeyBundle) -> Result<()>;
+    fn prekey_bundle(&self, user: &str, device: DeviceId) -> Result<Option<PreKeyBundle>>;
+
+    /// Hash of the access key senders of sealed messages to the device have to present.
+    fn set_access_key(&mut self, user: &str, device: DeviceId, hash: [u8; 32]) -> Result<()>;
+    fn access_key(&self, user: &str, device: DeviceId) -> Result<Option<[u8; 32]>>;
+
+    /// Appends a serialized message to the offline queue of a device, it is
+    /// dropped once `expires_at` (unix time) has passed.
+    fn queue_message(
+        &mut self,
+        user: &str,
+        device: DeviceId,
+        msg: String,
+        expires_at: Option<u64>,
+    ) -> Result<()>;
+    /// Removes and returns all unexpired queued messages of a device in the order they were queued.
+    fn take_queued(&mut self, user: &str, device: DeviceId) -> Result<Vec<String>>;
+    /// Drops every expired message from all queues, returns how many were dropped.
+    fn purge_expired(&mut self) -> Result<usize>;
+    /// How many messages wait in all queues together.
+    fn queued_count(&self) -> Result<usize>;
+
+    /// Records that `user` and `other` learned each other's keys, both are told about changes
+    /// to them from then on.
+    fn introduce(&mut self, user: &str, other: &str) -> Result<()>;
+    /// Every user `user` was introduced to, sorted.
+    fn introduced(&self, user: &str) -> Result<Vec<String>>;
+
+    /// Records that `user` does not want messages from `other`.
+    fn block(&mut self, user: &str, other: &str) -> Result<()>;
+    /// Returns `false` if `other` was not blocked.
+    fn unblock(&mut self, user: &str, other: &str) -> Result<bool>;
+    fn has_blocked(&self, user: &str, other: &str) -> Result<bool>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueuedMessage {
+    pub msg: String,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl QueuedMessage {
+    pub fn new(msg: String, expires_at: Option<u64>) -> Self {
+        Self { msg, expires_at }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(x) if x <= now)
+    }
+}
+
+#[derive(Default)]
+struct DeviceRecord {
+    identity_key: Option<PublicKey>,
+    key_signature: Option<Vec<u8>>,
+    signing_key: Option<[u8; 32]>,
+    prekey_bundle: Option<PreKeyBundle>,
+    access_key: Option<[u8; 32]>,
+    queue: Vec<QueuedMessage>,
+}
+
+/// Keeps everything in memory, nothing survives a restart. Laid out like `SledStorage`:
+/// registering, linking and the records of devices are independent of each other.
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: BTreeSet<String>,
+    devices: HashMap<String, BTreeSet<DeviceId>>,
+    records: HashMap<String, BTreeMap<DeviceId, DeviceRecord>>,
+    introductions: HashMap<String, BTreeSet<String>>,
+    blocks: HashMap<String, BTreeSet<String>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn device(&self, user: &str, device: DeviceId) -> Option<&DeviceRecord> {
+        self.records.get(user).and_then(|x| x.get(&device))
+    }
+
+    fn device_mut(&mut self, user: &str, device: DeviceId) -> &mut DeviceRecord {
+        self.records
+            .entry(user.to_owned())
+            .or_default()
+            .entry(device)
+            .or_default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn register_user(&mut self, user: &str) -> Result<bool> {
+        Ok(self.users.insert(user.to_owned()))
+    }
+
+    fn is_registered(&self, user: &str) -> Result<bool> {
+        Ok(self.users.contains(user))
+    }
+
+    fn users(&self) -> Result<Vec<String>> {
+        Ok(self.users.iter().cloned().collect())
+    }
+
+    fn link_device(&mut self, user: &str, device: DeviceId) -> Result<bool> {
+        Ok(self
+            .devices
+            .entry(user.to_owned())
+            .or_default()
+            .insert(device))
+    }
+
+    fn unlink_device(&mut self, user: &str, device: DeviceId) -> Result<bool> {
+        if let Some(x) = self.records.get_mut(user).and_then(|x| x.get_mut(&device)) {
+            *x = DeviceRecord {
+                signing_key: x.signing_key,
+                ..DeviceRecord::default()
+            };
+        }
+        Ok(self
+            .devices
+            .get_mut(user)
+            .is_some_and(|x| x.remove(&device)))
+    }
+
+    fn devices(&self, user: &str) -> Result<Vec<DeviceId>> {
+        Ok(self
+            .devices
+            .get(user)
+            .map(|x| x.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    fn set_identity_key(&mut self, user: &str, device: DeviceId, key: PublicKey) -> Result<()> {
+        self.device_mut(user, device).identity_key = Some(key);
+        Ok(())
+    }
+
+    fn set_key_signature(
+        &mut self,
+        user: &str,
+        device: DeviceId,
+        signature: Vec<u8>,
+    ) -> Result<()> {
+        self.device_mut(user, device).key_signature = Some(signature);
+        Ok(())
+    }
+
+    fn key_signature(&self, user: &str, device: DeviceId) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .device(user, device)
+            .and_then(|x| x.key_signature.clone()))
+    }
+
+    fn identity_key(&self, user: &str, device: DeviceId) -> Result<Option<PublicKey>> {
+        Ok(self.device(user, device).and_then(|x| x.identity_key))
+    }
+
+    fn set_signing_key(&mut self, user: &str, device: DeviceId, key: [u8; 32]) -> Result<()> {
+        self.device_mut(user, device).signing_key = Some(key);
+        Ok(())
+    }
+
+    fn signing_key(&self, user: &str, device: DeviceId) -> Result<Option<[u8; 32]>> {
+        Ok(self.device(user, device).and_then(|x| x.signing_key))
+    }
+
+    fn set_prekey_bundle(&mut self, bundle: PreKeyBundle) -> Result<()> {
+        let (user, device) = (bundle.user.clone(), bundle.device_id);
+        self.device_mut(&user, device).prekey_bundle = Some(bundle);
+        Ok(())
+    }
+
+    fn prekey_bundle(&self, user: &str, device: DeviceId) -> Result<Option<PreKeyBundle>> {
+        Ok(self
+            .device(user, device)
+            .and_then(|x| x.prekey_bundle.clone()))
+    }
+
+    fn set_access_key(&mut self, user: &str, device: DeviceId, hash: [u8; 32]) -> Result<()> {
+        self.device_mut(user, device).access_key = Some(hash);
+        Ok(())
+    }
+
+    fn access_key(&self, user: &str, device: DeviceId) -> Result<Option<[u8; 32]>> {
+        Ok(self.device(user, device).and_then(|x| x.access_key))
+    }
+
+    fn queue_message(
+        &mut self,
+        user: &str,
+        device: DeviceId,
+        msg: String,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.device_mut(user, device)
+            .queue
+            .push(QueuedMessage::new(msg, expires_at));
+        Ok(())
+    }
+
+    fn take_queued(&mut self, user: &str, device: DeviceId) -> Result<Vec<String>> {
+        let now = unix_time();
+        Ok(self
+            .records
+            .get_mut(user)
+            .and_then(|x| x.get_mut(&device))
+            .map(|x| std::mem::take(&mut x.queue))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|x| !x.is_expired(now))
+            .map(|x| x.msg)
+            .collect())
+    }
+
+    fn purge_expired(&mut self) -> Result<usize> {
+        let now = unix_time();
+        let mut purged = 0;
+        for device in self.records.values_mut().flat_map(|x| x.values_mut()) {
+            let len = device.queue.len();
+            device.queue.retain(|x| !x.is_expired(now));
+            purged += len - device.queue.len();
+        }
+        Ok(purged)
+    }
+
+    fn queued_count(&self) -> Result<usize> {
+        Ok(self
+            .records
+            .values()
+            .flat_map(|x| x.values())
+            .map(|x| x.queue.len())
+            .sum())
+    }
+
+    fn introduce(&mut self, user: &str, other: &str) -> Result<()> {
+        for (a, b) in [(user, other), (other, user)] {
+            self.introductions
+                .entry(a.to_owned())
+                .or_default()
+                .insert(b.to_owned());
+        }
+        Ok(())
+    }
+
+    fn introduced(&self, user: &str) -> Result<Vec<String>> {
+        Ok(self
+            .introductions
+            .get(user)
+            .map(|x| x.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn block(&mut self, user: &str, other: &str) -> Result<()> {
+        self.blocks
+            .entry(user.to_owned())
+            .or_default()
+            .insert(other.to_owned());
+        Ok(())
+    }
+
+    fn unblock(&mut self, user: &str, other: &str) -> Result<bool> {
+        Ok(self.blocks.get_mut(user).is_some_and(|x| x.remove(other)))
+    }
+
+    fn has_blocked(&self, user: &str, other: &str) -> Result<bool> {
+        Ok(self.blocks.get(user).is_some_and(|x| x.contains(other)))
+    }
+}
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+const USERS: &str = "users";
+const DEVICES: &str = "devices";
+const IDENTITY_KEYS: &str = "identity_keys";
+const PREKEY_BUNDLES: &str = "prekey_bundles";
+const QUEUES: &str = "queues";
+const ACCESS_KEYS: &str = "access_keys";
+const INTRODUCTIONS: &str = "introductions";
+const BLOCKS: &str = "blocks";
+const SIGNING_KEYS: &str = "signing_keys";
+const KEY_SIGNATURES: &str = "key_signatures";
+
+// the writes of a migration to each tree
+type Writes = Vec<(&'static str, sled::Batch)>;
+
+type Migration = fn(&sled::Db) -> Result<Writes>;
+
+/// Schema migrations, the entry at index `i` upgrades the database from version `i` to `i + 1`.
+/// They only read and return their writes, which are applied in one transaction together with
+/// the new version so a crash never leaves the two out of step. New migrations are only ever
+/// appended.
+const MIGRATIONS: &[Migration] = &[
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+    migrate_v4_to_v5,
+    migrate_v5_to_v6,
+    migrate_v6_to_v7,
+    migrate_v7_to_v8,
+];
+
+fn migrate_v0_to_v1(db: &sled::Db) -> Result<Writes> {
+    for tree in [USERS, IDENTITY_KEYS, PREKEY_BUNDLES, QUEUES] {
+        db.open_tree(tree)?;
+    }
+    Ok(Vec::new())
+}
+
+// v2 introduced devices, everything stored so far belongs to device 0
+fn migrate_v1_to_v2(db: &sled::Db) -> Result<Writes> {
+    let mut writes = Vec::new();
+    let mut devices = sled::Batch::default();
+    for user in db.open_tree(USERS)?.iter().keys() {
+        let user = String::from_utf8_lossy(&user?).into_owned();
+        devices.insert(device_key(&user, 0), &[]);
+    }
+    writes.push((DEVICES, devices));
+
+    for name in [IDENTITY_KEYS, PREKEY_BUNDLES] {
+        let mut batch = sled::Batch::default();
+        for entry in db.open_tree(name)?.iter() {
+            let (user, value) = entry?;
+            batch.remove(&user);
+            batch.insert(device_key(&String::from_utf8_lossy(&user), 0), value);
+        }
+        writes.push((name, batch));
+    }
+
+    // old queue keys are `user \0 id`
+    let mut queues = sled::Batch::default();
+    for entry in db.open_tree(QUEUES)?.iter() {
+        let (key, value) = entry?;
+        let (user, id) = key.split_at(key.len() - 8);
+        let mut new_key = device_key(&String::from_utf8_lossy(&user[..user.len() - 1]), 0);
+        new_key.extend_from_slice(id);
+        queues.remove(&key);
+        queues.insert(new_key, value);
+    }
+    writes.push((QUEUES, queues));
+    Ok(writes)
+}
+
+// v3 stores queued messages as `QueuedMessage` instead of the raw line
+fn migrate_v2_to_v3(db: &sled::Db) -> Result<Writes> {
+    let mut queues = sled::Batch::default();
+    for entry in db.open_tree(QUEUES)?.iter() {
+        let (key, value) = entry?;
+        let msg = QueuedMessage::new(String::from_utf8_lossy(&value).into_owned(), None);
+        queues.insert(key, encode(&msg)?);
+    }
+    Ok(vec![(QUEUES, queues)])
+}
+
+// v4 added the access keys of sealed sender
+fn migrate_v3_to_v4(db: &sled::Db) -> Result<Writes> {
+    db.open_tree(ACCESS_KEYS)?;
+    Ok(Vec::new())
+}
+
+// v5 added the users that were introduced to each other, keyed by `user \0 other`
+fn migrate_v4_to_v5(db: &sled::Db) -> Result<Writes> {
+    db.open_tree(INTRODUCTIONS)?;
+    Ok(Vec::new())
+}
+
+// v6 added the users each user blocked, keyed by `user \0 other`
+fn migrate_v5_to_v6(db: &sled::Db) -> Result<Writes> {
+    db.open_tree(BLOCKS)?;
+    Ok(Vec::new())
+}
+
+// v7 added the signing keys devices announced first, their keys are only accepted signed with it
+fn migrate_v6_to_v7(db: &sled::Db) -> Result<Writes> {
+    db.open_tree(SIGNING_KEYS)?;
+    Ok(Vec::new())
+}
+
+// v8 added the signatures over identity keys, so peers can check the keys they are handed
+fn migrate_v7_to_v8(db: &sled::Db) -> Result<Writes> {
+    db.open_tree(KEY_SIGNATURES)?;
+    Ok(Vec::new())
+}
+
+/// File-backed storage on top of sled.
+pub struct SledStorage {
+    db: sled::Db,
+    users: sled::Tree,
+    devices: sled::Tree,
+    identity_keys: sled::Tree,
+    key_signatures: sled::Tree,
+    signing_keys: sled::Tree,
+    prekey_bundles: sled::Tree,
+    access_keys: sled::Tree,
+    queues: sled::Tree,
+    introductions: sled::Tree,
+    blocks: sled::Tree,
+}
+
+impl SledStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    fn from_db(db: sled::Db) -> Result<Self> {
+        migrate(&db)?;
+
+        Ok(SledStorage {
+            users: db.open_tree(USERS)?,
+            devices: db.open_tree(DEVICES)?,
+            identity_keys: db.open_tree(IDENTITY_KEYS)?,
+            key_signatures: db.open_tree(KEY_SIGNATURES)?,
+            signing_keys: db.open_tree(SIGNING_KEYS)?,
+            prekey_bundles: db.open_tree(PREKEY_BUNDLES)?,
+            access_keys: db.open_tree(ACCESS_KEYS)?,
+            queues: db.open_tree(QUEUES)?,
+            introductions: db.open_tree(INTRODUCTIONS)?,
+            blocks: db.open_tree(BLOCKS)?,
+            db,
+        })
+    }
+
+    pub fn schema_version(&self) -> Result<u32> {
+        schema_version(&self.db)
+    }
+}
+
+fn schema_version(db: &sled::Db) -> Result<u32> {
+    Ok(match db.get(SCHEMA_VERSION_KEY)? {
+        Some(v) => decode(&v)?,
+        None => 0,
+    })
+}
+
+fn migrate(db: &sled::Db) -> Result<()> {
+    let version = schema_version(db)?;
+    if version as usize > MIGRATIONS.len() {
+        return Err(StorageError::UnknownSchema(version));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        tracing::info!("migrating storage schema from version {} to {}", i, i + 1);
+        let writes = migration(db)?;
+        let version = encode(&(i as u32 + 1))?;
+
+        // the default tree with the version goes last
+        let mut trees = writes
+            .iter()
+            .map(|(name, _)| db.open_tree(name))
+            .collect::<sled::Result<Vec<_>>>()?;
+        trees.push((**db).clone());
+        trees[..]
+            .transaction(|trees| {
+                for (tree, (_, batch)) in trees.iter().zip(&writes) {
+                    tree.apply_batch(batch)?;
+                }
+                trees[writes.len()].insert(SCHEMA_VERSION_KEY, version.clone())?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => StorageError::Db(e),
+                TransactionError::Abort(()) => unreachable!("migrations never abort"),
+            })?;
+        db.flush()?;
+    }
+    Ok(())
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(value)?)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+fn user_prefix(user: &str) -> Vec<u8> {
+    let mut prefix = user.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+// relations between two users are keyed by `user \0 other`
+fn pair_key(user: &str, other: &str) -> Vec<u8> {
+    let mut key = user_prefix(user);
+    key.extend_from_slice(other.as_bytes());
+    key
+}
+
+// per device records are keyed by `user \0 device`, queued messages by
+// `user \0 device id` so a prefix scan returns them in order
+fn device_key(user: &str, device: DeviceId) -> Vec<u8> {
+    let mut key = user_prefix(user);
+    key.extend_from_slice(&device.to_be_bytes());
+    key
+}
+
+impl Storage for SledStorage {
+    fn register_user(&mut self, user: &str) -> Result<bool> {
+        let prev = self
+            .users
+            .compare_and_swap(user, None as Option<&[u8]>, Some(&[][..]))?;
+        self.users.flush()?;
+        Ok(prev.is_ok())
+    }
+
+    fn is_registered(&self, user: &str) -> Result<bool> {
+        Ok(self.users.contains_key(user)?)
+    }
+
+    fn users(&self) -> Result<Vec<String>> {
+        self.users
+            .iter()
+            .keys()
+            .map(|k| Ok(String::from_utf8_lossy(&k?).into_owned()))
+            .collect()
+    }
+
+    fn link_device(&mut self, user: &str, device: DeviceId) -> Result<bool> {
+        let prev = self.devices.insert(device_key(user, device), &[])?;
+        self.devices.flush()?;
+        Ok(prev.is_none())
+    }
+
+    fn unlink_device(&mut self, user: &str, device: DeviceId) -> Result<bool> {
+        let key = device_key(user, device);
+        let prev = self.devices.remove(&key)?;
+        self.identity_keys.remove(&key)?;
+        self.key_signatures.remove(&key)?;
+        self.prekey_bundles.remove(&key)?;
+        self.access_keys.remove(&key)?;
+        for k in self.queues.scan_prefix(&key).keys() {
+            self.queues.remove(k?)?;
+        }
+        self.db.flush()?;
+        Ok(prev.is_some())
+    }
+
+    fn devices(&self, user: &str) -> Result<Vec<DeviceId>> {
+        let prefix = user_prefix(user);
+        self.devices
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|k| {
+                let k = k?;
+                let mut id = [0u8; 4];
+                id.copy_from_slice(&k[prefix.len()..]);
+                Ok(DeviceId::from_be_bytes(id))
+            })
+            .collect()
+    }
+
+    fn set_identity_key(&mut self, user: &str, device: DeviceId, key: PublicKey) -> Result<()> {
+        self.identity_keys
+            .insert(device_key(user, device), encode(&key)?)?;
+        self.identity_keys.flush()?;
+        Ok(())
+    }
+
+    fn identity_key(&self, user: &str, device: DeviceId) -> Result<Option<PublicKey>> {
+        self.identity_keys
+            .get(device_key(user, device))?
+            .map(|v| decode(&v))
+            .transpose()
+    }
+
+    fn set_key_signature(
+        &mut self,
+        user: &str,
+        device: DeviceId,
+        signature: Vec<u8>,
+    ) -> Result<()> {
+        self.key_signatures
+            .insert(device_key(user, device), signature)?;
+        self.key_signatures.flush()?;
+        Ok(())
+    }
+
+    fn key_signature(&self, user: &str, device: DeviceId) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .key_signatures
+            .get(device_key(user, device))?
+            .map(|v| v.to_vec()))
+    }
+
+    fn set_signing_key(&mut self, user: &str, device: DeviceId, key: [u8; 32]) -> Result<()> {
+        self.signing_keys.insert(device_key(user, device), &key)?;
+        self.signing_keys.flush()?;
+        Ok(())
+    }
+
+    fn signing_key(&self, user: &str, device: DeviceId) -> Result<Option<[u8; 32]>> {
+        Ok(self
+            .signing_keys
+            .get(device_key(user, device))?
+            .and_then(|v| v.as_ref().try_into().ok()))
+    }
+
+    fn set_prekey_bundle(&mut self, bundle: PreKeyBundle) -> Result<()> {
+        self.prekey_bundles
+            .insert(device_key(&bundle.user, bundle.device_id), encode(&bundle)?)?;
+        self.prekey_bundles.flush()?;
+        Ok(())
+    }
+
+    fn prekey_bundle(&self, user: &str, device: DeviceId) -> Result<Option<PreKeyBundle>> {
+        self.prekey_bundles
+            .get(device_key(user, device))?
+            .map(|v| decode(&v))
+            .transpose()
+    }
+
+    fn set_access_key(&mut self, user: &str, device: DeviceId, hash: [u8; 32]) -> Result<()> {
+        self.access_keys.insert(device_key(user, device), &hash)?;
+        self.access_keys.flush()?;
+        Ok(())
+    }
+
+    fn access_key(&self, user: &str, device: DeviceId) -> Result<Option<[u8; 32]>> {
+        Ok(self
+            .access_keys
+            .get(device_key(user, device))?
+            .and_then(|v| v.as_ref().try_into().ok()))
+    }
+
+    fn queue_message(
+        &mut self,
+        user: &str,
+        device: DeviceId,
+        msg: String,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let mut key = device_key(user, device);
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        self.queues
+            .insert(key, encode(&QueuedMessage::new(msg, expires_at))?)?;
+        self.queues.flush()?;
+        Ok(())
+    }
+
+    fn take_queued(&mut self, user: &str, device: DeviceId) -> Result<Vec<String>> {
+        let now = unix_time();
+        let mut msgs = Vec::new();
+        for entry in self.queues.scan_prefix(device_key(user, device)) {
+            let (key, value) = entry?;
+            self.queues.remove(key)?;
+            let msg: QueuedMessage = decode(&value)?;
+            if !msg.is_expired(now) {
+                msgs.push(msg.msg);
+            }
+        }
+        self.queues.flush()?;
+        Ok(msgs)
+    }
+
+    fn purge_expired(&mut self) -> Result<usize> {
+        let now = unix_time();
+        let mut purged = 0;
+        for entry in self.queues.iter() {
+            let (key, value) = entry?;
+            if decode::<QueuedMessage>(&value)?.is_expired(now) {
+                self.queues.remove(key)?;
+                purged += 1;
+            }
+        }
+        self.queues.flush()?;
+        Ok(purged)
+    }
+
+    fn queued_count(&self) -> Result<usize> {
+        Ok(self.queues.len())
+    }
+    fn introduce(&mut self, user: &str, other: &str) -> Result<()> {
+        for (a, b) in [(user, other), (other, user)] {
+            self.introductions.insert(pair_key(a, b), &[])?;
+        }
+        self.introductions.flush()?;
+        Ok(())
+    }
+
+    fn introduced(&self, user: &str) -> Result<Vec<String>> {
+        let prefix = user_prefix(user);
+        self.introductions
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|k| Ok(String::from_utf8_lossy(&k?[prefix.len()..]).into_owned()))
+            .collect()
+    }
+
+    fn block(&mut self, user: &str, other: &str) -> Result<()> {
+        self.blocks.insert(pair_key(user, other), &[])?;
+        self.blocks.flush()?;
+        Ok(())
+    }
+
+    fn unblock(&mut self, user: &str, other: &str) -> Result<bool> {
+        let prev = self.blocks.remove(pair_key(user, other))?;
+        self.blocks.flush()?;
+        Ok(prev.is_some())
+    }
+
+    fn has_blocked(&self, user: &str, other: &str) -> Result<bool> {
+        Ok(self.blocks.contains_key(pair_key(user, other))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sled keeps its lock for a moment after a database is dropped, so tests never reopen one
+    fn temporary() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn check_queues(storage: &mut dyn Storage) {
+        assert!(storage.register_user("alice").unwrap());
+        assert!(!storage.register_user("alice").unwrap());
+        assert!(storage.is_registered("alice").unwrap());
+        assert!(!storage.is_registered("bob").unwrap());
+        assert!(storage.link_device("alice", 0).unwrap());
+        assert!(storage.link_device("alice", 1).unwrap());
+        assert!(!storage.link_device("alice", 1).unwrap());
+        assert_eq!(storage.devices("alice").unwrap(), [0, 1]);
+        storage.set_signing_key("alice", 1, [1; 32]).unwrap();
+        assert_eq!(storage.signing_key("alice", 1).unwrap(), Some([1; 32]));
+        assert_eq!(storage.signing_key("alice", 0).unwrap(), None);
+        storage.set_key_signature("alice", 1, vec![2; 64]).unwrap();
+        assert_eq!(
+            storage.key_signature("alice", 1).unwrap(),
+            Some(vec![2; 64])
+        );
+
+        for msg in ["first", "second", "third"] {
+            storage
+                .queue_message("alice", 0, msg.to_owned(), None)
+                .unwrap();
+        }
+        storage
+            .queue_message("alice", 0, "expired".to_owned(), Some(1))
+            .unwrap();
+        storage
+            .queue_message("alice", 1, "other device".to_owned(), None)
+            .unwrap();
+        assert_eq!(storage.queued_count().unwrap(), 5);
+
+        assert_eq!(
+            storage.take_queued("alice", 0).unwrap(),
+            ["first", "second", "third"]
+        );
+        assert!(storage.take_queued("alice", 0).unwrap().is_empty());
+        assert_eq!(storage.queued_count().unwrap(), 1);
+
+        storage
+            .queue_message("alice", 1, "expired".to_owned(), Some(1))
+            .unwrap();
+        assert_eq!(storage.purge_expired().unwrap(), 1);
+        assert!(storage.unlink_device("alice", 1).unwrap());
+        assert!(!storage.unlink_device("alice", 1).unwrap());
+        assert_eq!(storage.devices("alice").unwrap(), [0]);
+        assert_eq!(storage.signing_key("alice", 1).unwrap(), Some([1; 32]));
+        assert_eq!(storage.key_signature("alice", 1).unwrap(), None);
+        assert_eq!(storage.queued_count().unwrap(), 0);
+    }
+
+    // both storages keep registering, linking and the records of devices apart
+    fn check_registration(storage: &mut dyn Storage) {
+        assert!(storage.link_device("bob", 0).unwrap());
+        storage
+            .set_identity_key("carol", 0, PublicKey::from([1; 32]))
+            .unwrap();
+        storage.set_access_key("carol", 1, [2; 32]).unwrap();
+        assert!(!storage.is_registered("bob").unwrap());
+        assert!(!storage.is_registered("carol").unwrap());
+        assert!(storage.users().unwrap().is_empty());
+        assert!(storage.devices("carol").unwrap().is_empty());
+
+        assert!(storage.register_user("carol").unwrap());
+        assert!(storage.register_user("bob").unwrap());
+        assert_eq!(storage.users().unwrap(), ["bob", "carol"]);
+        assert_eq!(storage.devices("bob").unwrap(), [0]);
+        assert_eq!(
+            storage.identity_key("carol", 0).unwrap(),
+            Some(PublicKey::from([1; 32]))
+        );
+        assert_eq!(storage.access_key("carol", 1).unwrap(), Some([2; 32]));
+        assert!(!storage.unlink_device("carol", 1).unwrap());
+        assert_eq!(storage.access_key("carol", 1).unwrap(), None);
+    }
+
+    fn check_relations(storage: &mut dyn Storage) {
+        storage.introduce("alice", "bob").unwrap();
+        storage.introduce("alice", "carol").unwrap();
+        assert_eq!(storage.introduced("alice").unwrap(), ["bob", "carol"]);
+        assert_eq!(storage.introduced("bob").unwrap(), ["alice"]);
+        assert!(storage.introduced("dave").unwrap().is_empty());
+
+        storage.block("alice", "bob").unwrap();
+        assert!(storage.has_blocked("alice", "bob").unwrap());
+        assert!(!storage.has_blocked("bob", "alice").unwrap());
+        assert!(storage.unblock("alice", "bob").unwrap());
+        assert!(!storage.unblock("alice", "bob").unwrap());
+        assert!(!storage.has_blocked("alice", "bob").unwrap());
+    }
+
+    #[test]
+    fn memory_storage() {
+        check_queues(&mut MemoryStorage::new());
+        check_registration(&mut MemoryStorage::new());
+        check_relations(&mut MemoryStorage::new());
+    }
+
+    #[test]
+    fn sled_storage() {
+        let mut storage = SledStorage::from_db(temporary()).unwrap();
+        assert_eq!(storage.schema_version().unwrap(), MIGRATIONS.len() as u32);
+        check_queues(&mut storage);
+        check_relations(&mut storage);
+        check_registration(&mut SledStorage::from_db(temporary()).unwrap());
+    }
+
+    #[test]
+    fn migrates_v1_to_latest() {
+        let db = temporary();
+        let key = PublicKey::from([7u8; 32]);
+        // the layout of version 1: everything keyed by user, queued lines stored as they are
+        db.insert(SCHEMA_VERSION_KEY, encode(&1u32).unwrap())
+            .unwrap();
+        db.open_tree(USERS).unwrap().insert("alice", &[]).unwrap();
+        db.open_tree(IDENTITY_KEYS)
+            .unwrap()
+            .insert("alice", encode(&key).unwrap())
+            .unwrap();
+        let queues = db.open_tree(QUEUES).unwrap();
+        for (id, msg) in [(1u64, "first"), (2, "second")] {
+            let mut k = user_prefix("alice");
+            k.extend_from_slice(&id.to_be_bytes());
+            queues.insert(k, msg.as_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+
+        let mut storage = SledStorage::from_db(db.clone()).unwrap();
+        assert_eq!(storage.schema_version().unwrap(), 8);
+        assert!(storage.is_registered("alice").unwrap());
+        assert_eq!(storage.devices("alice").unwrap(), [0]);
+        assert_eq!(storage.identity_key("alice", 0).unwrap(), Some(key));
+        assert_eq!(
+            storage.take_queued("alice", 0).unwrap(),
+            ["first", "second"]
+        );
+        check_relations(&mut storage);
+
+        // opening it again does not migrate twice
+        let storage = SledStorage::from_db(db).unwrap();
+        assert_eq!(storage.schema_version().unwrap(), 8);
+        assert_eq!(storage.devices("alice").unwrap(), [0]);
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let db = temporary();
+        db.insert(SCHEMA_VERSION_KEY, encode(&99u32).unwrap())
+            .unwrap();
+        assert!(matches!(
+            SledStorage::from_db(db),
+            Err(StorageError::UnknownSchema(99))
+        ));
+    }
+}
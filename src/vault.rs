@@ -0,0 +1,249 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use aes_siv::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128SivAead, Nonce,
+};
+use argon2::Argon2;
+use rand::{rngs::OsRng, RngCore};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub type Result<T> = std::result::Result<T, VaultError>;
+
+#[derive(Debug)]
+pub enum VaultError {
+    Io(io::Error),
+    Encoding(serde_json::Error),
+    KeyDerivation(argon2::Error),
+    /// Wrong passphrase or a corrupted file.
+    Decryption,
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaultError::Io(e) => write!(f, "io error: {}", e),
+            VaultError::Encoding(e) => write!(f, "encoding error: {}", e),
+            VaultError::KeyDerivation(e) => write!(f, "key derivation error: {}", e),
+            VaultError::Decryption => write!(f, "decryption failed, wrong passphrase?"),
+        }
+    }
+}
+
+impl std::error::Error for VaultError {}
+
+impl From<io::Error> for VaultError {
+    fn from(e: io::Error) -> Self {
+        VaultError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for VaultError {
+    fn from(e: serde_json::Error) -> Self {
+        VaultError::Encoding(e)
+    }
+}
+
+const SALT_FILE: &str = "salt";
+const CHECK_FILE: &str = "check";
+const CHECK_VALUE: &str = "lib-sig vault";
+
+/// Directory of files encrypted with a key derived from a passphrase,
+/// used by the client to keep its data at rest.
+pub struct Vault {
+    dir: PathBuf,
+    key: [u8; 32],
+}
+
+impl Vault {
+    /// Opens the vault in `dir`, creating it if needed. Fails with
+    /// `VaultError::Decryption` if the passphrase does not match the one it was created with.
+    pub fn open<P: AsRef<Path>>(dir: P, passphrase: &str) -> Result<Vault> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let salt_path = dir.join(SALT_FILE);
+        let salt = match fs::read(&salt_path) {
+            Ok(salt) => salt,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut salt = vec![0u8; 16];
+                OsRng.fill_bytes(&mut salt);
+                fs::write(&salt_path, &salt)?;
+                salt
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(VaultError::KeyDerivation)?;
+
+        let vault = Vault { dir, key };
+        match vault.load::<String>(CHECK_FILE) {
+            Ok(Some(check)) if check == CHECK_VALUE => (),
+            Ok(Some(_)) => return Err(VaultError::Decryption),
+            Ok(None) => vault.save(CHECK_FILE, &CHECK_VALUE)?,
+            Err(VaultError::Decryption) => vault.migrate()?,
+            Err(e) => return Err(e),
+        }
+        Ok(vault)
+    }
+
+    // vaults used to encrypt their files without binding them to their names, every file is
+    // encrypted again with its name, the check file last so an interrupted run is picked up
+    // again the next time
+    fn migrate(&self) -> Result<()> {
+        let check = self.read(CHECK_FILE, b"")?.ok_or(VaultError::Decryption)?;
+        if serde_json::from_slice::<String>(&check)? != CHECK_VALUE {
+            return Err(VaultError::Decryption);
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_file()
+                && name != SALT_FILE
+                && name != CHECK_FILE
+                && !name.ends_with(".tmp")
+            {
+                names.push(name);
+            }
+        }
+        names.push(CHECK_FILE.to_owned());
+
+        for name in names {
+            if self.read(&name, name.as_bytes()).is_ok() {
+                continue;
+            }
+            if let Some(plaintext) = self.read(&name, b"")? {
+                self.write(&name, &plaintext)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Serializes and encrypts `value` into the file `name`, replacing it atomically. The name
+    /// is authenticated along with the contents, so files cannot be swapped for one another.
+    pub fn save<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        self.write(name, &serde_json::to_vec(value)?)
+    }
+
+    /// Loads the file `name`, `None` if it does not exist yet.
+    pub fn load<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        match self.read(name, name.as_bytes())? {
+            Some(plaintext) => Ok(Some(serde_json::from_slice(&plaintext)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&self, name: &str, plaintext: &[u8]) -> Result<()> {
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = Aes128SivAead::new_from_slice(&self.key).unwrap();
+        let payload = Payload {
+            msg: plaintext,
+            aad: name.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| VaultError::Decryption)?;
+
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, self.dir.join(name))?;
+        Ok(())
+    }
+
+    fn read(&self, name: &str, aad: &[u8]) -> Result<Option<Vec<u8>>> {
+        let data = match fs::read(self.dir.join(name)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if data.len() < 16 {
+            return Err(VaultError::Decryption);
+        }
+
+        let (nonce, ciphertext) = data.split_at(16);
+        let cipher = Aes128SivAead::new_from_slice(&self.key).unwrap();
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| VaultError::Decryption)?;
+        Ok(Some(plaintext))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("lib-sig-vault-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn files_cannot_be_swapped() {
+        let dir = dir("swap");
+        let vault = Vault::open(&dir, "passphrase").unwrap();
+        vault.save("history", &"hello".to_owned()).unwrap();
+        vault.save("contacts", &"bob".to_owned()).unwrap();
+        assert_eq!(vault.load::<String>("history").unwrap().unwrap(), "hello");
+
+        fs::copy(dir.join("contacts"), dir.join("history")).unwrap();
+        assert!(matches!(
+            vault.load::<String>("history"),
+            Err(VaultError::Decryption)
+        ));
+        assert!(matches!(
+            Vault::open(&dir, "wrong"),
+            Err(VaultError::Decryption)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_without_names_are_migrated() {
+        let dir = dir("migrate");
+        let vault = Vault::open(&dir, "passphrase").unwrap();
+        // as vaults wrote their files before
+        let legacy = |name: &str, value: &str| {
+            let mut data = [7u8; 16].to_vec();
+            let cipher = Aes128SivAead::new_from_slice(&vault.key).unwrap();
+            let plaintext = serde_json::to_vec(value).unwrap();
+            data.extend(
+                cipher
+                    .encrypt(Nonce::from_slice(&[7u8; 16]), &plaintext[..])
+                    .unwrap(),
+            );
+            fs::write(dir.join(name), data).unwrap();
+        };
+        legacy(CHECK_FILE, CHECK_VALUE);
+        legacy("history", "hello");
+        assert!(Vault::open(&dir, "wrong").is_err());
+
+        let vault = Vault::open(&dir, "passphrase").unwrap();
+        assert_eq!(vault.load::<String>("history").unwrap().unwrap(), "hello");
+        assert!(vault.read("history", b"").is_err());
+        assert!(vault.read(CHECK_FILE, b"").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use lib_sig::crypto::{KdfMode, KeyPair, State, Suite};
+use lib_sig::message::{Message, ERR_DECRYPT, ERR_SUITE};
+use lib_sig::session::Session;
+
+const SUITES: [Suite; 3] = [Suite::Aes128Siv, Suite::Aes256Gcm, Suite::ChaCha20Poly1305];
+
+fn pair(alice_suite: Suite, bob_suite: Suite) -> (Session, Session) {
+    let (alice_keys, bob_keys) = (KeyPair::new(), KeyPair::new());
+    let alice = State::new(alice_keys.clone(), bob_keys.public())
+        .with_kdf(KdfMode::Spec)
+        .with_suite(alice_suite);
+    let bob = State::new(bob_keys, alice_keys.public())
+        .with_kdf(KdfMode::Spec)
+        .with_suite(bob_suite);
+    (
+        Session::new(alice, ("alice", 0), ("bob", 0)),
+        Session::new(bob, ("bob", 0), ("alice", 0)),
+    )
+}
+
+fn message(from: &Session, text: &str) -> Message {
+    Message::new(
+        text.to_owned(),
+        ("alice".to_owned(), 0),
+        ("bob".to_owned(), 0),
+        from.state().key_pair().public(),
+    )
+}
+
+#[test]
+fn round_trip() {
+    for suite in SUITES {
+        let (mut alice, mut bob) = pair(suite, suite);
+        for i in 0..3 {
+            let m = alice.encrypt(&message(&alice, &format!("a{}", i))).unwrap();
+            assert_eq!(m.suite, suite);
+            assert_eq!(bob.decrypt(&m).unwrap().msg, format!("a{}", i));
+
+            let m = bob.encrypt(&message(&bob, &format!("b{}", i))).unwrap();
+            assert_eq!(alice.decrypt(&m).unwrap().msg, format!("b{}", i));
+        }
+    }
+}
+
+#[test]
+fn suites_produce_different_ciphertexts() {
+    let (alice_keys, bob_keys) = (KeyPair::new(), KeyPair::new());
+    let state = State::new(alice_keys, bob_keys.public()).with_kdf(KdfMode::Spec);
+    let msg = message(&Session::new(state.clone(), ("a", 0), ("b", 0)), "hi");
+
+    let ciphertexts = SUITES
+        .iter()
+        .map(|x| {
+            msg.encrypt(&state.clone().with_suite(*x))
+                .unwrap()
+                .0
+                .encrypted_msg
+        })
+        .collect::<Vec<_>>();
+    assert_ne!(ciphertexts[0], ciphertexts[1]);
+    assert_ne!(ciphertexts[1], ciphertexts[2]);
+}
+
+#[test]
+fn mismatched_suite_is_rejected() {
+    let (mut alice, mut bob) = pair(Suite::Aes256Gcm, Suite::ChaCha20Poly1305);
+
+    let mut m = alice.encrypt(&message(&alice, "hi")).unwrap();
+    assert_eq!(bob.decrypt(&m).unwrap_err(), ERR_SUITE);
+
+    // claiming the receiver's suite does not help either
+    m.suite = Suite::ChaCha20Poly1305;
+    assert_eq!(bob.decrypt(&m).unwrap_err(), ERR_DECRYPT);
+}
+
+#[test]
+fn tampered_ciphertext_is_rejected() {
+    for suite in SUITES {
+        let (mut alice, mut bob) = pair(suite, suite);
+        let mut m = alice.encrypt(&message(&alice, "hi")).unwrap();
+        let last = m.encrypted_msg.len() - 1;
+        m.encrypted_msg[last] ^= 1;
+        assert_eq!(bob.decrypt(&m).unwrap_err(), ERR_DECRYPT);
+    }
+}
+
+#[test]
+fn names() {
+    for suite in SUITES {
+        assert_eq!(suite.to_string().parse::<Suite>().unwrap(), suite);
+    }
+    assert!("rot13".parse::<Suite>().is_err());
+}
@@ -0,0 +1,336 @@
+//! Relays and scripted clients shared by the integration tests.
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::SinkExt;
+use lib_sig::config::ServerOptions;
+use lib_sig::crypto::{KdfMode, KeyPair, SigningKeyPair, State};
+use lib_sig::message::{
+    Challenge, DeviceId, EncryptedMessage, Message, Msg, NackReason, PubKey, RegisterMessage,
+    Request, ServerInfo, UserList, UserQuery,
+};
+use lib_sig::netsim::Network;
+use lib_sig::server::Server;
+use lib_sig::session::Session;
+use lib_sig::storage::MemoryStorage;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+// every wait ends as soon as the awaited message arrives, this only bounds a failing test
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+// starts a relay with in-memory storage on an ephemeral port
+pub async fn start_server(options: ServerOptions) -> SocketAddr {
+    let config = options.validate().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(Box::new(MemoryStorage::new()), &config);
+    tokio::spawn(server.run(listener));
+    addr
+}
+
+// starts a relay with in-memory storage listening as `host` of a simulated network
+pub fn start_simulated_server(net: &Network, host: &str, options: ServerOptions) {
+    let config = options.validate().unwrap();
+    let mut listener = net.listen(host);
+    let server = Server::new(Box::new(MemoryStorage::new()), &config);
+    tokio::spawn(async move {
+        while let Ok((stream, addr)) = listener.accept().await {
+            server.serve(stream, addr).await;
+        }
+    });
+}
+
+// every scripted client of a device registers with the same key, like a client restarted
+// with its data directory
+pub fn signing_key(name: &str, device: DeviceId) -> SigningKeyPair {
+    let mut hash = Sha256::new();
+    hash.update(name.as_bytes());
+    hash.update(device.to_be_bytes());
+    SigningKeyPair::from(<[u8; 32]>::from(hash.finalize()))
+}
+
+// registers a device and announces its key, what the client's `Connection` starts with
+pub fn handshake(
+    name: &str,
+    device: DeviceId,
+    key: &KeyPair,
+) -> impl FnOnce(&Challenge) -> Vec<Msg> {
+    let (name, signing_key, public) = (name.to_owned(), signing_key(name, device), key.public());
+    move |challenge| {
+        vec![
+            Msg::Register(
+                RegisterMessage::new(name.clone(), device).signed(challenge, &signing_key),
+            ),
+            Msg::PubKey(PubKey::new(name, device, public).signed(&signing_key)),
+        ]
+    }
+}
+
+// a scripted client speaking the protocol directly
+pub struct Client<S = TcpStream> {
+    pub name: String,
+    pub device: DeviceId,
+    pub key: KeyPair,
+    pub signing_key: SigningKeyPair,
+    pub lines: Framed<S, LinesCodec>,
+    next_id: u64,
+    pub sessions: HashMap<(String, DeviceId), Session>,
+    // messages read while waiting for another one, in the order they arrived
+    pub inbox: VecDeque<Msg>,
+}
+
+impl Client {
+    pub async fn connect(addr: SocketAddr, name: &str, device: DeviceId, key: KeyPair) -> Self {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Client::new(stream, name, device, key)
+    }
+
+    // registers and announces the key, like the client does after connecting
+    pub async fn join(addr: SocketAddr, name: &str, device: DeviceId) -> Self {
+        Self::join_with(addr, name, device, KeyPair::new()).await
+    }
+
+    pub async fn join_with(addr: SocketAddr, name: &str, device: DeviceId, key: KeyPair) -> Self {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Client::join_over(stream, name, device, key).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    pub fn new(stream: S, name: &str, device: DeviceId, key: KeyPair) -> Self {
+        Client {
+            name: name.to_owned(),
+            device,
+            key,
+            signing_key: signing_key(name, device),
+            lines: Framed::new(stream, LinesCodec::new()),
+            next_id: 0,
+            sessions: HashMap::new(),
+            inbox: VecDeque::new(),
+        }
+    }
+
+    pub async fn join_over(stream: S, name: &str, device: DeviceId, key: KeyPair) -> Self {
+        let mut client = Client::new(stream, name, device, key);
+        client.announce().await;
+        client
+    }
+
+    // connects again over a new stream, keeping the sessions
+    pub async fn rejoin(&mut self, stream: S) {
+        self.lines = Framed::new(stream, LinesCodec::new());
+        self.inbox.clear();
+        self.announce().await;
+    }
+
+    async fn announce(&mut self) {
+        let id = self.register().await;
+        self.expect_ack(id).await;
+        let key = PubKey::new(self.name.clone(), self.device, self.key.public())
+            .signed(&self.signing_key);
+        let id = self.request(Msg::PubKey(key)).await;
+        self.expect_ack(id).await;
+    }
+
+    // answers the challenge the server opened the connection with
+    pub async fn register(&mut self) -> u64 {
+        let challenge = match self.expect(|msg| matches!(msg, Msg::Challenge(_))).await {
+            Msg::Challenge(challenge) => challenge,
+            _ => unreachable!(),
+        };
+        let register = RegisterMessage::new(self.name.clone(), self.device)
+            .signed(&challenge, &self.signing_key);
+        self.request(Msg::Register(register)).await
+    }
+
+    pub async fn request(&mut self, msg: Msg) -> u64 {
+        self.next_id += 1;
+        let request = Msg::Request(Request::new(self.next_id, msg));
+        let line = serde_json::to_string(&request).unwrap();
+        self.lines.send(line).await.unwrap();
+        self.next_id
+    }
+
+    // starts a session with a device whose key did not come from the server
+    pub fn trust(&mut self, user: &str, device: DeviceId, key: x25519_dalek::PublicKey) {
+        let state = State::new(self.key.clone(), key).with_kdf(KdfMode::Spec);
+        let session = Session::new(state, (&self.name, self.device), (user, device));
+        self.sessions.insert((user.to_owned(), device), session);
+    }
+
+    pub async fn send_text(&mut self, user: &str, device: DeviceId, text: &str) -> u64 {
+        let msg = Message::new(
+            text.to_owned(),
+            (self.name.clone(), self.device),
+            (user.to_owned(), device),
+            self.key.public(),
+        );
+        let session = self.sessions.get_mut(&(user.to_owned(), device)).unwrap();
+        let encrypted = session.encrypt(&msg).unwrap();
+        self.request(Msg::EncryptedMessage(encrypted)).await
+    }
+
+    // the next message from the server, sessions are set up with every key it announces
+    pub async fn read(&mut self) -> Option<Msg> {
+        let line = tokio::time::timeout(TIMEOUT, self.lines.next())
+            .await
+            .unwrap_or_else(|_| panic!("{} waited too long for a message", self.name))?
+            .unwrap();
+        Some(self.parse(&line))
+    }
+
+    // the next message unless none arrives for `wait` or the connection is closed
+    pub async fn read_within(&mut self, wait: Duration) -> Option<Msg> {
+        if let Some(msg) = self.inbox.pop_front() {
+            return Some(msg);
+        }
+        let line = tokio::time::timeout(wait, self.lines.next()).await.ok()??;
+        Some(self.parse(&line.unwrap()))
+    }
+
+    // sessions are set up with every key the server announces
+    fn parse(&mut self, line: &str) -> Msg {
+        let msg: Msg = serde_json::from_str(line).unwrap();
+        if let Msg::PubKey(k) = &msg {
+            let id = (k.user.clone(), k.device_id);
+            if id != (self.name.clone(), self.device) && !self.sessions.contains_key(&id) {
+                self.trust(&k.user, k.device_id, k.public_key);
+            }
+        }
+        msg
+    }
+
+    pub fn decrypt(&mut self, msg: &EncryptedMessage) -> Result<String, u32> {
+        let id = (msg.sender_name.clone(), msg.sender_device);
+        self.sessions
+            .get_mut(&id)
+            .unwrap()
+            .decrypt(msg)
+            .map(|x| x.msg)
+    }
+
+    // waits for the first message `f` accepts, the others are kept for later
+    pub async fn expect(&mut self, f: impl Fn(&Msg) -> bool) -> Msg {
+        if let Some(i) = self.inbox.iter().position(&f) {
+            return self.inbox.remove(i).unwrap();
+        }
+        loop {
+            match self.read().await {
+                Some(msg) if f(&msg) => return msg,
+                Some(msg) => self.inbox.push_back(msg),
+                None => panic!("the server closed the connection of {}", self.name),
+            }
+        }
+    }
+
+    pub async fn expect_ack(&mut self, id: u64) {
+        let answer = self
+            .expect(|msg| match msg {
+                Msg::Ack(ack) => ack.id == id,
+                Msg::Nack(nack) => nack.id == Some(id),
+                _ => false,
+            })
+            .await;
+        if let Msg::Nack(nack) = answer {
+            panic!(
+                "request {} of {} was refused: {}",
+                id, self.name, nack.reason
+            );
+        }
+    }
+
+    // the answer to request `id`, the reason if it was refused
+    pub async fn answer(&mut self, id: u64) -> Result<(), NackReason> {
+        let answer = self
+            .expect(|msg| match msg {
+                Msg::Ack(ack) => ack.id == id,
+                Msg::Nack(nack) => nack.id == Some(id),
+                _ => false,
+            })
+            .await;
+        match answer {
+            Msg::Nack(nack) => Err(nack.reason),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn expect_nack(&mut self, id: Option<u64>) -> NackReason {
+        let answer = self
+            .expect(|msg| match msg {
+                Msg::Ack(ack) => Some(ack.id) == id,
+                Msg::Nack(nack) => nack.id == id,
+                _ => false,
+            })
+            .await;
+        match answer {
+            Msg::Nack(nack) => nack.reason,
+            _ => panic!("request {:?} of {} was acknowledged", id, self.name),
+        }
+    }
+
+    pub async fn server_info(&mut self) -> ServerInfo {
+        let id = self.request(Msg::ServerInfoRequest).await;
+        let info = match self.expect(|msg| matches!(msg, Msg::ServerInfo(_))).await {
+            Msg::ServerInfo(info) => info,
+            _ => unreachable!(),
+        };
+        self.expect_ack(id).await;
+        info
+    }
+
+    pub async fn list_users(&mut self, query: UserQuery) -> UserList {
+        let id = self.request(Msg::ListUsers(query)).await;
+        let list = match self.expect(|msg| matches!(msg, Msg::UserList(_))).await {
+            Msg::UserList(list) => list,
+            _ => unreachable!(),
+        };
+        self.expect_ack(id).await;
+        list
+    }
+
+    // asks for the keys of every device of `user`, they arrive before the answer
+    pub async fn lookup(&mut self, user: &str) {
+        let id = self.request(Msg::KeyRequest(user.to_owned())).await;
+        self.expect_ack(id).await;
+    }
+
+    // waits for the key of a device to be announced
+    pub async fn expect_key(&mut self, user: &str, device: DeviceId) {
+        self.expect(|msg| matches!(msg, Msg::PubKey(k) if k.user == user && k.device_id == device))
+            .await;
+    }
+
+    // the sender and the decrypted text of the next message
+    pub async fn expect_text(&mut self) -> (String, String) {
+        let msg = match self
+            .expect(|msg| matches!(msg, Msg::EncryptedMessage(_)))
+            .await
+        {
+            Msg::EncryptedMessage(msg) => msg,
+            _ => unreachable!(),
+        };
+        let text = self.decrypt(&msg).unwrap();
+        (msg.sender_name, text)
+    }
+
+    pub async fn expect_presence(&mut self, user: &str, device: DeviceId, online: bool) {
+        self.expect(|msg| {
+            matches!(msg, Msg::Presence(p) if p.user == user && p.device_id == device && p.online == online)
+        })
+        .await;
+    }
+
+    // waits until the server closes the connection
+    pub async fn expect_closed(&mut self) {
+        while let Some(msg) = self.read().await {
+            self.inbox.push_back(msg);
+        }
+    }
+}
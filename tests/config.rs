@@ -0,0 +1,150 @@
+use std::fs;
+use std::path::PathBuf;
+
+use lib_sig::config::{
+    self, ClientOptions, ConfigError, LogFormat, ServerOptions, DEFAULT_MAX_LINE_LENGTH,
+    DEFAULT_PADDING,
+};
+use lib_sig::crypto::Suite;
+use lib_sig::padding::Padding;
+
+fn write_config(name: &str, text: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("lib-sig-{}-{}.toml", name, std::process::id()));
+    fs::write(&path, text).unwrap();
+    path
+}
+
+fn client(username: &str) -> ClientOptions {
+    ClientOptions {
+        username: Some(username.to_owned()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn defaults() {
+    let server = ServerOptions::default().validate().unwrap();
+    assert_eq!(server.addr.to_string(), config::DEFAULT_ADDR);
+    assert_eq!(server.log_format, LogFormat::Full);
+    assert_eq!(server.rate_limit, config::DEFAULT_RATE_LIMIT);
+    assert!(server.db.is_none());
+    assert!(server.metrics_addr.is_none());
+    assert!(server.admin_socket.is_none());
+    assert_eq!(server.name, config::DEFAULT_NAME);
+    assert_eq!(server.motd, config::DEFAULT_MOTD);
+    assert!(!server.broadcast_keys);
+    assert!(server.list_users);
+
+    let client = client("alice").validate().unwrap();
+    assert_eq!(client.addr, config::DEFAULT_ADDR);
+    assert_eq!(client.device, 0);
+    assert_eq!(client.suite, Suite::default());
+    assert_eq!(client.padding, DEFAULT_PADDING);
+    assert_eq!(client.max_line_length, DEFAULT_MAX_LINE_LENGTH);
+    assert!(client.sealed_sender);
+    assert!(!client.tui);
+    assert!(!client.report_blocks);
+}
+
+#[test]
+fn invalid_options_are_errors() {
+    let invalid = |options: ClientOptions| {
+        assert!(matches!(options.validate(), Err(ConfigError::Invalid(_))))
+    };
+    invalid(ClientOptions::default());
+    invalid(client(""));
+    invalid(client("al ice"));
+    invalid(client("al>ice"));
+    invalid(ClientOptions {
+        addr: Some("localhost".to_owned()),
+        ..client("alice")
+    });
+    invalid(ClientOptions {
+        addr: Some("localhost:70000".to_owned()),
+        ..client("alice")
+    });
+    invalid(ClientOptions {
+        suite: Some("rot13".to_owned()),
+        ..client("alice")
+    });
+    invalid(ClientOptions {
+        padding: Some("lots".to_owned()),
+        ..client("alice")
+    });
+    invalid(ClientOptions {
+        max_line_length: Some(0),
+        ..client("alice")
+    });
+
+    for options in [
+        ServerOptions {
+            addr: Some("localhost:6142".to_owned()),
+            ..Default::default()
+        },
+        ServerOptions {
+            max_connections: Some(0),
+            ..Default::default()
+        },
+        ServerOptions {
+            rate_limit: Some(0),
+            ..Default::default()
+        },
+        ServerOptions {
+            metrics_addr: Some("metrics".to_owned()),
+            ..Default::default()
+        },
+    ] {
+        assert!(matches!(options.validate(), Err(ConfigError::Invalid(_))));
+    }
+}
+
+#[test]
+fn file_is_overridden_by_command_line() {
+    let path = write_config(
+        "client",
+        r#"
+username = "alice"
+addr = "example.org:7000"
+device = 2
+suite = "chacha20-poly1305"
+padding = "buckets"
+sealed_sender = false
+log_format = "json"
+"#,
+    );
+    let file: ClientOptions = config::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let cli = ClientOptions {
+        device: Some(3),
+        padding: Some("none".to_owned()),
+        ..Default::default()
+    };
+    let config = cli.or(file).validate().unwrap();
+    assert_eq!(config.username, "alice");
+    assert_eq!(config.addr, "example.org:7000");
+    assert_eq!(config.device, 3);
+    assert_eq!(config.suite, Suite::ChaCha20Poly1305);
+    assert_eq!(config.padding, Padding::None);
+    assert!(!config.sealed_sender);
+    assert_eq!(config.log_format, LogFormat::Json);
+}
+
+#[test]
+fn bad_files_are_errors() {
+    let path = write_config("unknown", "adress = \"127.0.0.1:1\"\n");
+    let result = config::load::<ServerOptions>(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(ConfigError::Parse(..))));
+
+    let path = write_config("type", "max_connections = \"many\"\n");
+    let result = config::load::<ServerOptions>(&path);
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(ConfigError::Parse(..))));
+
+    let missing = std::env::temp_dir().join("lib-sig-does-not-exist.toml");
+    assert!(matches!(
+        config::load::<ServerOptions>(&missing),
+        Err(ConfigError::Io(..))
+    ));
+}
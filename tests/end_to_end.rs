@@ -0,0 +1,793 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::{signing_key, start_server, Client};
+use futures::{SinkExt, StreamExt};
+use lib_sig::config::ServerOptions;
+use lib_sig::crypto::{KeyPair, SigningKeyPair, Suite};
+use lib_sig::message::{
+    Device, Msg, NackReason, PreKeyBundle, PubKey, RegisterMessage, SessionReset, UserList,
+    UserQuery, FEATURES, MAX_USER_LIST,
+};
+use lib_sig::server::Server;
+use lib_sig::storage::MemoryStorage;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_util::codec::{Framed, LinesCodec};
+
+#[tokio::test]
+async fn registration_conflicts() {
+    let addr = start_server(ServerOptions::default()).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+
+    // the same device can only be connected once
+    let mut again = Client::connect(addr, "alice", 0, KeyPair::new()).await;
+    let id = again.register().await;
+    assert_eq!(
+        again.expect_nack(Some(id)).await,
+        NackReason::AlreadyConnected
+    );
+    again.expect_closed().await;
+
+    // other devices have to be linked first
+    let mut phone = Client::connect(addr, "alice", 1, KeyPair::new()).await;
+    let id = phone.register().await;
+    assert_eq!(phone.expect_nack(Some(id)).await, NackReason::NotRegistered);
+    phone.expect_closed().await;
+
+    // and name the key the new device registers with
+    let id = alice
+        .request(Msg::LinkDevice(Device::new("alice".to_owned(), 1)))
+        .await;
+    assert_eq!(alice.expect_nack(Some(id)).await, NackReason::Malformed);
+    let phone =
+        Device::new("alice".to_owned(), 1).with_signing_key(signing_key("alice", 1).public());
+    let id = alice.request(Msg::LinkDevice(phone)).await;
+    alice.expect_ack(id).await;
+    Client::join(addr, "alice", 1).await;
+
+    // nothing is served before registering
+    let mut stranger = Client::connect(addr, "stranger", 0, KeyPair::new()).await;
+    let id = stranger
+        .request(Msg::PreKeyRequest(Device::new("alice".to_owned(), 0)))
+        .await;
+    assert_eq!(
+        stranger.expect_nack(Some(id)).await,
+        NackReason::NotRegistered
+    );
+    stranger.expect_closed().await;
+
+    // an unlinked device only comes back with the key it had
+    let id = alice
+        .request(Msg::UnlinkDevice(Device::new("alice".to_owned(), 1)))
+        .await;
+    alice.expect_ack(id).await;
+    let other = Device::new("alice".to_owned(), 1).with_signing_key(SigningKeyPair::new().public());
+    let id = alice.request(Msg::LinkDevice(other)).await;
+    assert_eq!(alice.expect_nack(Some(id)).await, NackReason::NotPermitted);
+    let mut phone = Client::connect(addr, "alice", 1, KeyPair::new()).await;
+    let id = phone.register().await;
+    assert_eq!(phone.expect_nack(Some(id)).await, NackReason::NotRegistered);
+    phone.expect_closed().await;
+    let phone =
+        Device::new("alice".to_owned(), 1).with_signing_key(signing_key("alice", 1).public());
+    let id = alice.request(Msg::LinkDevice(phone)).await;
+    alice.expect_ack(id).await;
+    Client::join(addr, "alice", 1).await;
+
+    // nor can anyone else link devices of alice
+    let mut mallory = Client::join(addr, "mallory", 0).await;
+    let id = mallory
+        .request(Msg::LinkDevice(Device::new("alice".to_owned(), 2)))
+        .await;
+    assert_eq!(
+        mallory.expect_nack(Some(id)).await,
+        NackReason::NotPermitted
+    );
+}
+
+#[tokio::test]
+async fn presence() {
+    let options = ServerOptions {
+        broadcast_keys: Some(true),
+        ..ServerOptions::default()
+    };
+    let addr = start_server(options).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+
+    let bob = Client::join(addr, "bob", 0).await;
+    alice.expect_presence("bob", 0, true).await;
+
+    drop(bob);
+    alice.expect_presence("bob", 0, false).await;
+
+    // a device that connects later learns who is online already
+    let mut carol = Client::join(addr, "carol", 0).await;
+    carol.expect_presence("alice", 0, true).await;
+    carol.expect_key("bob", 0).await;
+}
+
+#[tokio::test]
+async fn keys_only_go_to_introduced_users() {
+    let addr = start_server(ServerOptions::default()).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+    let mut bob = Client::join(addr, "bob", 0).await;
+    let mut carol = Client::join(addr, "carol", 0).await;
+
+    // looking bob up introduces alice to him as well
+    alice.lookup("bob").await;
+    alice.expect_key("bob", 0).await;
+    alice.expect_presence("bob", 0, true).await;
+    bob.expect_key("alice", 0).await;
+    bob.expect_presence("alice", 0, true).await;
+
+    let id = alice.request(Msg::KeyRequest("dave".to_owned())).await;
+    assert_eq!(
+        alice.expect_nack(Some(id)).await,
+        NackReason::UnknownRecipient
+    );
+
+    // introductions outlive the connection
+    drop(bob);
+    alice.expect_presence("bob", 0, false).await;
+    let mut bob = Client::join(addr, "bob", 0).await;
+    bob.expect_key("alice", 0).await;
+    alice.expect_key("bob", 0).await;
+
+    // carol never heard of either of them
+    while let Some(msg) = carol.read_within(Duration::from_millis(200)).await {
+        assert!(
+            !matches!(msg, Msg::PubKey(_) | Msg::Presence(_)),
+            "carol learned {:?}",
+            msg
+        );
+    }
+
+    // asking for prekeys is an introduction too
+    let id = carol
+        .request(Msg::PreKeyRequest(Device::new("dave".to_owned(), 0)))
+        .await;
+    assert_eq!(
+        carol.expect_nack(Some(id)).await,
+        NackReason::UnknownRecipient
+    );
+    let id = carol
+        .request(Msg::PreKeyRequest(Device::new("bob".to_owned(), 0)))
+        .await;
+    assert_eq!(carol.expect_nack(Some(id)).await, NackReason::NoPreKeys);
+    drop(bob);
+    carol.expect_presence("bob", 0, false).await;
+}
+
+// registers `name` with `signing_key` and announces a key signed with it, returning the answer
+async fn announce_signed(
+    addr: SocketAddr,
+    name: &str,
+    signing_key: &SigningKeyPair,
+) -> (Client, Option<NackReason>) {
+    let key = KeyPair::new();
+    let mut client = Client::connect(addr, name, 0, key.clone()).await;
+    client.signing_key = signing_key.clone();
+    let id = client.register().await;
+    if let Err(reason) = client.answer(id).await {
+        return (client, Some(reason));
+    }
+    let pubkey = PubKey::new(name.to_owned(), 0, key.public()).signed(signing_key);
+    let id = client.request(Msg::PubKey(pubkey)).await;
+    let refused = client.answer(id).await.err();
+    (client, refused)
+}
+
+#[tokio::test]
+async fn signing_keys_are_pinned() {
+    let addr = start_server(ServerOptions::default()).await;
+    let signing_key = SigningKeyPair::new();
+    let (mut alice, refused) = announce_signed(addr, "alice", &signing_key).await;
+    assert_eq!(refused, None);
+
+    // peers learn the signing key to check prekeys with
+    let mut bob = Client::join(addr, "bob", 0).await;
+    bob.lookup("alice").await;
+    match bob
+        .expect(|msg| matches!(msg, Msg::PubKey(k) if k.user == "alice"))
+        .await
+    {
+        Msg::PubKey(k) => {
+            assert_eq!(k.signing_key, Some(signing_key.public()));
+            assert!(k.verify());
+        }
+        _ => unreachable!(),
+    }
+
+    let bundle = |signing_key: &SigningKeyPair| {
+        let (identity, prekey) = (KeyPair::new(), KeyPair::new());
+        PreKeyBundle::new("alice".to_owned(), 0, identity.public(), prekey.public(), 1)
+            .signed(signing_key)
+    };
+    let rotated = bundle(&signing_key);
+    let key = rotated.identity_key;
+    let id = alice.request(Msg::RotateKey(rotated)).await;
+    alice.expect_ack(id).await;
+    // the new key reaches peers with a signature they can check themselves
+    match bob
+        .expect(|msg| matches!(msg, Msg::PubKey(k) if k.public_key == key))
+        .await
+    {
+        Msg::PubKey(k) => assert!(k.verify()),
+        _ => unreachable!(),
+    }
+    let mut unsigned = bundle(&signing_key);
+    unsigned.key_signature = Vec::new();
+    let id = alice.request(Msg::RotateKey(unsigned)).await;
+    assert_eq!(
+        alice.expect_nack(Some(id)).await,
+        NackReason::InvalidSignature
+    );
+    let id = alice
+        .request(Msg::PreKeyBundle(bundle(&SigningKeyPair::new())))
+        .await;
+    assert_eq!(
+        alice.expect_nack(Some(id)).await,
+        NackReason::InvalidSignature
+    );
+
+    drop(alice);
+    bob.expect_presence("alice", 0, false).await;
+
+    // the device cannot switch to another key either
+    let mut alice = Client::connect(addr, "alice", 0, KeyPair::new()).await;
+    alice.signing_key = signing_key.clone();
+    let id = alice.register().await;
+    alice.expect_ack(id).await;
+    let other = SigningKeyPair::new();
+    let id = alice
+        .request(Msg::PubKey(
+            PubKey::new("alice".to_owned(), 0, KeyPair::new().public()).signed(&other),
+        ))
+        .await;
+    assert_eq!(
+        alice.expect_nack(Some(id)).await,
+        NackReason::InvalidSignature
+    );
+    let mut forged = PubKey::new("alice".to_owned(), 0, KeyPair::new().public()).signed(&other);
+    forged.signing_key = Some(signing_key.public());
+    let id = alice.request(Msg::PubKey(forged)).await;
+    assert_eq!(
+        alice.expect_nack(Some(id)).await,
+        NackReason::InvalidSignature
+    );
+    let unsigned = PubKey::new("alice".to_owned(), 0, KeyPair::new().public());
+    let id = alice.request(Msg::PubKey(unsigned)).await;
+    assert_eq!(
+        alice.expect_nack(Some(id)).await,
+        NackReason::InvalidSignature
+    );
+    drop(alice);
+    bob.expect_presence("alice", 0, false).await;
+
+    // whoever registers as the device next has to sign with the same key
+    let (mut mallory, refused) = announce_signed(addr, "alice", &other).await;
+    assert_eq!(refused, Some(NackReason::InvalidSignature));
+    mallory.expect_closed().await;
+
+    let (_, refused) = announce_signed(addr, "alice", &signing_key).await;
+    assert_eq!(refused, None);
+}
+
+#[tokio::test]
+async fn offline_devices_cannot_be_taken_over() {
+    let addr = start_server(ServerOptions::default()).await;
+    let alice = Client::join(addr, "alice", 0).await;
+    let mut bob = Client::join(addr, "bob", 0).await;
+    bob.lookup("alice").await;
+    bob.expect_key("alice", 0).await;
+    let key = alice.key.clone();
+    drop(alice);
+    bob.expect_presence("alice", 0, false).await;
+    let id = bob.send_text("alice", 0, "queued").await;
+    bob.expect_ack(id).await;
+
+    // knowing the name and device is not enough to log in
+    let mut mallory = Client::connect(addr, "alice", 0, KeyPair::new()).await;
+    mallory.signing_key = SigningKeyPair::new();
+    let id = mallory.register().await;
+    assert_eq!(
+        mallory.expect_nack(Some(id)).await,
+        NackReason::InvalidSignature
+    );
+    mallory.expect_closed().await;
+
+    let mut mallory = Client::connect(addr, "alice", 0, KeyPair::new()).await;
+    mallory.expect(|msg| matches!(msg, Msg::Challenge(_))).await;
+    let unsigned = RegisterMessage::new("alice".to_owned(), 0);
+    let id = mallory.request(Msg::Register(unsigned)).await;
+    assert_eq!(
+        mallory.expect_nack(Some(id)).await,
+        NackReason::InvalidSignature
+    );
+    mallory.expect_closed().await;
+
+    // nor is a registration recorded on another connection
+    let mut first = Client::connect(addr, "alice", 0, KeyPair::new()).await;
+    let challenge = match first.expect(|msg| matches!(msg, Msg::Challenge(_))).await {
+        Msg::Challenge(challenge) => challenge,
+        _ => unreachable!(),
+    };
+    let recorded =
+        RegisterMessage::new("alice".to_owned(), 0).signed(&challenge, &signing_key("alice", 0));
+    let mut replayed = Client::connect(addr, "alice", 0, KeyPair::new()).await;
+    replayed
+        .expect(|msg| matches!(msg, Msg::Challenge(_)))
+        .await;
+    let id = replayed.request(Msg::Register(recorded)).await;
+    assert_eq!(
+        replayed.expect_nack(Some(id)).await,
+        NackReason::InvalidSignature
+    );
+    drop(first);
+
+    // the queue is still there for alice
+    let mut alice = Client::join_with(addr, "alice", 0, key).await;
+    assert_eq!(
+        alice.expect_text().await,
+        ("bob".to_owned(), "queued".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn blocked_senders_are_dropped() {
+    let addr = start_server(ServerOptions::default()).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+    let mut bob = Client::join(addr, "bob", 0).await;
+    bob.lookup("alice").await;
+    bob.expect_key("alice", 0).await;
+
+    let id = alice.request(Msg::Block("alice".to_owned())).await;
+    assert_eq!(alice.expect_nack(Some(id)).await, NackReason::NotPermitted);
+    let id = alice.request(Msg::Block("bob".to_owned())).await;
+    alice.expect_ack(id).await;
+
+    // bob cannot tell he was blocked
+    let id = bob.send_text("alice", 0, "hello").await;
+    bob.expect_ack(id).await;
+    let id = bob.request(Msg::KeyRequest("alice".to_owned())).await;
+    assert_eq!(
+        bob.expect_nack(Some(id)).await,
+        NackReason::UnknownRecipient
+    );
+    let id = bob
+        .request(Msg::PreKeyRequest(Device::new("alice".to_owned(), 0)))
+        .await;
+    assert_eq!(
+        bob.expect_nack(Some(id)).await,
+        NackReason::UnknownRecipient
+    );
+    while let Some(msg) = alice.read_within(Duration::from_millis(200)).await {
+        assert!(!matches!(msg, Msg::EncryptedMessage(_)), "got {:?}", msg);
+    }
+
+    let id = alice.request(Msg::Unblock("bob".to_owned())).await;
+    alice.expect_ack(id).await;
+    bob.send_text("alice", 0, "again").await;
+    assert_eq!(
+        alice.expect_text().await,
+        ("bob".to_owned(), "again".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn listing_users() {
+    let addr = start_server(ServerOptions::default()).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+    for name in ["bob", "bobby", "carol"] {
+        Client::join(addr, name, 0).await;
+    }
+
+    let list = alice.list_users(UserQuery::default()).await;
+    assert_eq!(list.users, ["alice", "bob", "bobby", "carol"]);
+    let list = alice
+        .list_users(UserQuery::new(Some("bob".to_owned()), 1, Some(1)))
+        .await;
+    assert_eq!(list, UserList::new(vec!["bobby".to_owned()], 1, 2));
+    let list = alice
+        .list_users(UserQuery::new(None, 0, Some(MAX_USER_LIST + 1)))
+        .await;
+    assert_eq!(list.total, 4);
+
+    // without the directory only full names are found
+    let options = ServerOptions {
+        list_users: Some(false),
+        ..ServerOptions::default()
+    };
+    let addr = start_server(options).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+    Client::join(addr, "bob", 0).await;
+    assert!(alice
+        .list_users(UserQuery::default())
+        .await
+        .users
+        .is_empty());
+    assert!(alice
+        .list_users(UserQuery::new(Some("bo".to_owned()), 0, None))
+        .await
+        .users
+        .is_empty());
+    let list = alice
+        .list_users(UserQuery::new(Some("bob".to_owned()), 0, None))
+        .await;
+    assert_eq!(list, UserList::new(vec!["bob".to_owned()], 0, 1));
+}
+
+#[tokio::test]
+async fn lookups_are_rate_limited() {
+    let options = ServerOptions {
+        list_users: Some(false),
+        rate_limit: Some(2),
+        ..ServerOptions::default()
+    };
+    let addr = start_server(options).await;
+    let mut mallory = Client::join(addr, "mallory", 0).await;
+
+    // guessing names runs into the same limit as sending messages
+    let mut refused = Vec::new();
+    for (i, name) in ["alice", "bob", "carol", "dave", "erin", "frank"]
+        .iter()
+        .enumerate()
+    {
+        let msg = match i % 3 {
+            0 => Msg::KeyRequest(name.to_string()),
+            1 => Msg::PreKeyRequest(Device::new(name.to_string(), 0)),
+            _ => Msg::ListUsers(UserQuery::new(Some(name.to_string()), 0, None)),
+        };
+        let id = mallory.request(msg).await;
+        let answer = mallory
+            .expect(|msg| {
+                matches!(msg, Msg::Nack(x) if x.id == Some(id))
+                    || matches!(msg, Msg::Ack(x) if x.id == id)
+            })
+            .await;
+        if let Msg::Nack(nack) = answer {
+            refused.push(nack.reason);
+        }
+    }
+    assert!(refused
+        .iter()
+        .any(|x| matches!(x, NackReason::RateLimited { .. })));
+}
+
+#[tokio::test]
+async fn messages_between_online_devices() {
+    let addr = start_server(ServerOptions::default()).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+    let mut bob = Client::join(addr, "bob", 0).await;
+    alice.lookup("bob").await;
+
+    let id = alice.send_text("bob", 0, "hi bob").await;
+    alice.expect_ack(id).await;
+    assert_eq!(
+        bob.expect_text().await,
+        ("alice".to_owned(), "hi bob".to_owned())
+    );
+
+    let id = bob.send_text("alice", 0, "hi alice").await;
+    bob.expect_ack(id).await;
+    assert_eq!(
+        alice.expect_text().await,
+        ("bob".to_owned(), "hi alice".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn messages_to_offline_devices_are_queued() {
+    let addr = start_server(ServerOptions::default()).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+
+    let bob_key = KeyPair::new();
+    let bob = Client::join_with(addr, "bob", 0, bob_key.clone()).await;
+    alice.lookup("bob").await;
+    drop(bob);
+    alice.expect_presence("bob", 0, false).await;
+
+    for text in ["one", "two"] {
+        let id = alice.send_text("bob", 0, text).await;
+        alice.expect_ack(id).await;
+    }
+
+    // bob comes back with the same key, the session carries on
+    let mut bob = Client::join_with(addr, "bob", 0, bob_key).await;
+    assert_eq!(bob.expect_text().await.1, "one");
+    assert_eq!(bob.expect_text().await.1, "two");
+}
+
+#[tokio::test]
+async fn refused_requests() {
+    let options = ServerOptions {
+        max_line_length: Some(4096),
+        ..ServerOptions::default()
+    };
+    let addr = start_server(options).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+
+    alice.trust("carol", 0, KeyPair::new().public());
+    let id = alice.send_text("carol", 0, "hi").await;
+    assert_eq!(
+        alice.expect_nack(Some(id)).await,
+        NackReason::UnknownRecipient
+    );
+
+    let id = alice
+        .request(Msg::PreKeyRequest(Device::new("alice".to_owned(), 0)))
+        .await;
+    assert_eq!(alice.expect_nack(Some(id)).await, NackReason::NoPreKeys);
+
+    // nobody gets to restart the sessions of someone else
+    let reset = SessionReset {
+        sender_name: "bob".to_owned(),
+        sender_device: 0,
+        recv_name: "alice".to_owned(),
+        recv_device: 0,
+        identity_key: KeyPair::new().public(),
+        ephemeral_key: KeyPair::new().public(),
+        prekey_id: 0,
+        pq_ciphertext: None,
+        suite: Suite::default(),
+    };
+    let id = alice.request(Msg::SessionReset(reset)).await;
+    assert_eq!(alice.expect_nack(Some(id)).await, NackReason::NotPermitted);
+
+    // lines that are too long or not a message are answered without an id, the connection
+    // stays up
+    alice.trust("bob", 0, KeyPair::new().public());
+    alice.send_text("bob", 0, &"x".repeat(8192)).await;
+    assert_eq!(alice.expect_nack(None).await, NackReason::TooLarge);
+    alice.lines.send("{\"Request\":".to_owned()).await.unwrap();
+    assert_eq!(alice.expect_nack(None).await, NackReason::Malformed);
+    let id = alice
+        .request(Msg::UnlinkDevice(Device::new("alice".to_owned(), 5)))
+        .await;
+    assert_eq!(
+        alice.expect_nack(Some(id)).await,
+        NackReason::UnknownRecipient
+    );
+}
+
+#[tokio::test]
+async fn concurrent_messaging() {
+    const CLIENTS: usize = 4;
+    const MESSAGES: usize = 10;
+
+    let options = ServerOptions {
+        rate_limit: Some(1000),
+        ..ServerOptions::default()
+    };
+    let addr = start_server(options).await;
+
+    let names = (0..CLIENTS)
+        .map(|i| format!("user{}", i))
+        .collect::<Vec<_>>();
+    let mut clients = Vec::new();
+    for name in &names {
+        clients.push(Client::join(addr, name, 0).await);
+    }
+    // every client looks up those that joined after it, and is introduced to those before it
+    for (i, client) in clients.iter_mut().enumerate() {
+        for name in &names[i + 1..] {
+            client.lookup(name).await;
+        }
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        for name in &names[..i] {
+            client.expect_key(name, 0).await;
+        }
+    }
+
+    let tasks = clients
+        .into_iter()
+        .map(|mut client| {
+            let names = names.clone();
+            tokio::spawn(async move {
+                let peers = names
+                    .iter()
+                    .filter(|x| **x != client.name)
+                    .collect::<Vec<_>>();
+                let mut sent = Vec::new();
+                for i in 0..MESSAGES {
+                    for peer in &peers {
+                        let text = format!("{} from {}", i, client.name);
+                        sent.push(client.send_text(peer, 0, &text).await);
+                    }
+                }
+                for id in sent {
+                    client.expect_ack(id).await;
+                }
+
+                // every peer's messages arrive, in the order they were sent
+                let mut received: HashMap<String, Vec<String>> = HashMap::new();
+                for _ in 0..MESSAGES * peers.len() {
+                    let (from, text) = client.expect_text().await;
+                    received.entry(from).or_default().push(text);
+                }
+                for peer in peers {
+                    let expected = (0..MESSAGES)
+                        .map(|i| format!("{} from {}", i, peer))
+                        .collect::<Vec<_>>();
+                    assert_eq!(received[peer.as_str()], expected);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+// a plain HTTP/1.0 request, the endpoint closes the connection after answering
+async fn get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.0\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn metrics() {
+    let config = ServerOptions::default().validate().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (addr, metrics_addr) = (
+        listener.local_addr().unwrap(),
+        metrics.local_addr().unwrap(),
+    );
+    let server = Server::new(Box::new(MemoryStorage::new()), &config).with_metrics(metrics);
+    tokio::spawn(server.run(listener));
+
+    let mut alice = Client::join(addr, "alice", 0).await;
+    let bob = Client::join(addr, "bob", 0).await;
+    alice.lookup("bob").await;
+    let id = alice.send_text("bob", 0, "hi").await;
+    alice.expect_ack(id).await;
+
+    alice.trust("carol", 0, KeyPair::new().public());
+    let id = alice.send_text("carol", 0, "hi").await;
+    alice.expect_nack(Some(id)).await;
+    alice.lines.send("{".to_owned()).await.unwrap();
+    alice.expect_nack(None).await;
+
+    let mut again = Client::connect(addr, "alice", 0, KeyPair::new()).await;
+    let id = again.register().await;
+    again.expect_nack(Some(id)).await;
+    again.expect_closed().await;
+
+    // the message to bob waits in his queue
+    drop(bob);
+    alice.expect_presence("bob", 0, false).await;
+    let id = alice.send_text("bob", 0, "later").await;
+    alice.expect_ack(id).await;
+
+    let response = get(metrics_addr, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    for line in [
+        "lib_sig_connected_peers 1",
+        "lib_sig_queued_messages 1",
+        "lib_sig_relayed_messages_total{kind=\"encrypted\"} 2",
+        "lib_sig_dropped_messages_total{reason=\"unknown_recipient\"} 1",
+        "lib_sig_registration_failures_total{reason=\"already_connected\"} 1",
+        "lib_sig_decode_errors_total{reason=\"malformed\"} 1",
+    ] {
+        assert!(response.lines().any(|x| x == line), "missing {}", line);
+    }
+    assert!(response.contains("lib_sig_received_bytes_total"));
+    assert!(get(metrics_addr, "/").await.starts_with("HTTP/1.1 404"));
+}
+
+// sends an admin command and collects the reply up to its last line
+async fn admin(socket: &mut Framed<UnixStream, LinesCodec>, command: &str) -> Vec<String> {
+    socket.send(command).await.unwrap();
+    let mut reply = Vec::new();
+    loop {
+        let line = socket.next().await.unwrap().unwrap();
+        let done = line == "ok" || line.starts_with("error: ");
+        reply.push(line);
+        if done {
+            return reply;
+        }
+    }
+}
+
+#[tokio::test]
+async fn admin_commands() {
+    let config = ServerOptions::default().validate().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let path = std::env::temp_dir().join(format!("lib-sig-admin-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = Server::new(Box::new(MemoryStorage::new()), &config)
+        .with_admin(UnixListener::bind(&path).unwrap());
+    tokio::spawn(server.run(listener));
+    let mut socket = Framed::new(UnixStream::connect(&path).await.unwrap(), LinesCodec::new());
+
+    let mut alice = Client::join(addr, "alice", 0).await;
+    let mut bob = Client::join(addr, "bob", 0).await;
+    alice.lookup("bob").await;
+    let list = admin(&mut socket, "list").await;
+    assert_eq!(list.len(), 3);
+    assert!(list[0].starts_with("alice 0 127.0.0.1:"));
+    assert!(list[1].starts_with("bob 0 127.0.0.1:"));
+    assert_eq!(
+        admin(&mut socket, "reboot").await,
+        ["error: unknown command: reboot"]
+    );
+
+    admin(&mut socket, "broadcast maintenance at noon").await;
+    let info = |msg: &Msg| matches!(msg, Msg::Info(x) if x.info == "maintenance at noon");
+    alice.expect(info).await;
+    bob.expect(info).await;
+
+    // kicked devices are told why and may come back
+    admin(&mut socket, "kick bob").await;
+    bob.expect(|msg| matches!(msg, Msg::Info(x) if x.info.contains("administrator")))
+        .await;
+    bob.expect_closed().await;
+    alice.expect_presence("bob", 0, false).await;
+    assert_eq!(
+        admin(&mut socket, "kick bob").await,
+        ["error: bob is not connected"]
+    );
+
+    admin(&mut socket, "motd Hello from the admin.").await;
+    assert_eq!(
+        admin(&mut socket, "motd").await,
+        ["Hello from the admin.", "ok"]
+    );
+    let mut bob = Client::join(addr, "bob", 0).await;
+    assert_eq!(bob.server_info().await.motd, "Hello from the admin.");
+
+    // banned users are refused when they register again
+    admin(&mut socket, "ban bob").await;
+    bob.expect_closed().await;
+    let mut again = Client::connect(addr, "bob", 0, KeyPair::new()).await;
+    let id = again.register().await;
+    assert_eq!(again.expect_nack(Some(id)).await, NackReason::Banned);
+    admin(&mut socket, "unban bob").await;
+    assert_eq!(
+        admin(&mut socket, "unban bob").await,
+        ["error: bob is not banned"]
+    );
+
+    // banned addresses cannot connect at all
+    admin(&mut socket, "ban 127.0.0.1").await;
+    alice.expect_closed().await;
+    assert_eq!(admin(&mut socket, "bans").await, ["127.0.0.1", "ok"]);
+    let mut refused = Client::connect(addr, "carol", 0, KeyPair::new()).await;
+    refused.expect(|msg| matches!(msg, Msg::Err(_))).await;
+    refused.expect_closed().await;
+    assert_eq!(admin(&mut socket, "list").await, ["ok"]);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn server_info() {
+    let options = ServerOptions {
+        name: Some("test relay".to_owned()),
+        motd: Some("Be nice.".to_owned()),
+        max_line_length: Some(4096),
+        rate_limit: Some(5),
+        ..ServerOptions::default()
+    };
+    let addr = start_server(options).await;
+    let mut alice = Client::join(addr, "alice", 0).await;
+
+    let info = alice.server_info().await;
+    assert_eq!(info.name, "test relay");
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.motd, "Be nice.");
+    assert_eq!((info.max_line_length, info.rate_limit), (4096, 5));
+    assert_eq!(info.features, FEATURES);
+}
@@ -0,0 +1,81 @@
+//! Known-answer tests for `KdfMode::Spec` against `tests/vectors/double_ratchet.json`.
+//! The vectors were generated independently of this crate so other implementations can
+//! check themselves against the same file.
+
+use lib_sig::crypto::{
+    kdf_ck, kdf_rk, Aes128SivSha256, ChainKey, KdfMode, KeyPair, RootKey, ROOT_KDF_INFO,
+};
+use serde_json::Value;
+use x25519_dalek::PublicKey;
+
+const VECTORS: &str = include_str!("vectors/double_ratchet.json");
+
+fn vectors() -> Value {
+    serde_json::from_str(VECTORS).unwrap()
+}
+
+fn bytes(v: &Value) -> [u8; 32] {
+    let s = v.as_str().unwrap();
+    assert_eq!(s.len(), 64);
+    let mut out = [0u8; 32];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    out
+}
+
+#[test]
+fn root_kdf_info() {
+    assert_eq!(
+        vectors()["root_kdf_info"].as_str().unwrap().as_bytes(),
+        ROOT_KDF_INFO
+    );
+}
+
+#[test]
+fn chain_steps() {
+    for v in vectors()["kdf_ck"].as_array().unwrap() {
+        let mut ck = ChainKey::from(bytes(&v["chain_key"]));
+        for (i, step) in v["steps"].as_array().unwrap().iter().enumerate() {
+            let (next, mk) = kdf_ck(&ck);
+            assert_eq!(mk, bytes(&step["message_key"]));
+            assert_eq!(next.key(), bytes(&step["next_chain_key"]));
+            assert_eq!(next.count(), i as u32 + 1);
+            ck = next;
+        }
+    }
+}
+
+#[test]
+fn root_steps() {
+    for v in vectors()["kdf_rk"].as_array().unwrap() {
+        let (rk, ck) = kdf_rk(&RootKey::from(bytes(&v["root_key"])), &bytes(&v["dh_out"]));
+        assert_eq!(rk.key(), bytes(&v["next_root_key"]));
+        assert_eq!(ck.key(), bytes(&v["chain_key"]));
+    }
+}
+
+#[test]
+fn root_step_with_dh() {
+    for v in vectors()["root_step"].as_array().unwrap() {
+        let keys = KeyPair::from((bytes(&v["private_key"]), bytes(&v["public_key"])));
+        let their_key = PublicKey::from(bytes(&v["their_public_key"]));
+        assert_eq!(
+            keys.private().diffie_hellman(&their_key).to_bytes(),
+            bytes(&v["dh_out"])
+        );
+
+        let (rk, ck) = KdfMode::Spec.root_key(
+            &Aes128SivSha256,
+            &RootKey::from(bytes(&v["root_key"])),
+            keys.private(),
+            &their_key,
+        );
+        assert_eq!(rk.key(), bytes(&v["next_root_key"]));
+        assert_eq!(ck.key(), bytes(&v["chain_key"]));
+
+        let (next, mk) = KdfMode::Spec.chain_key(&Aes128SivSha256, &ck);
+        assert_eq!(mk, bytes(&v["message_key"]));
+        assert_eq!(next.key(), bytes(&v["next_chain_key"]));
+    }
+}
@@ -0,0 +1,107 @@
+//! Known-answer tests for the ML-KEM-768 wrapper in `lib_sig::crypto` against
+//! `tests/vectors/ml_kem_768.json`.
+
+use lib_sig::crypto::{
+    mlkem_encapsulate, mlkem_encapsulate_deterministic, MlKemKeyPair, MLKEM_CIPHERTEXT_LEN,
+    MLKEM_PUBLIC_KEY_LEN,
+};
+use serde_json::Value;
+
+const VECTORS: &str = include_str!("vectors/ml_kem_768.json");
+
+fn vectors() -> Vec<Value> {
+    group("ml_kem_768")
+}
+
+fn group(name: &str) -> Vec<Value> {
+    let v: Value = serde_json::from_str(VECTORS).unwrap();
+    v[name].as_array().unwrap().clone()
+}
+
+fn bytes(v: &Value) -> Vec<u8> {
+    let s = v.as_str().unwrap();
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn key_pair(v: &Value) -> MlKemKeyPair {
+    MlKemKeyPair::from_seed(&bytes(&v["seed"]).try_into().unwrap())
+}
+
+#[test]
+fn key_generation() {
+    for v in vectors() {
+        let public = key_pair(&v).public();
+        assert_eq!(public.len(), MLKEM_PUBLIC_KEY_LEN);
+        assert_eq!(public, bytes(&v["encapsulation_key"]));
+    }
+}
+
+#[test]
+fn decapsulation() {
+    for v in vectors() {
+        let ciphertext = bytes(&v["ciphertext"]);
+        assert_eq!(ciphertext.len(), MLKEM_CIPHERTEXT_LEN);
+        assert_eq!(
+            key_pair(&v).decapsulate(&ciphertext).unwrap().to_vec(),
+            bytes(&v["shared_secret"])
+        );
+    }
+}
+
+#[test]
+fn encapsulation() {
+    for v in group("encapsulation") {
+        let ek = bytes(&v["encapsulation_key"]);
+        let m = bytes(&v["m"]).try_into().unwrap();
+        let (secret, ciphertext) = mlkem_encapsulate_deterministic(&ek, &m).unwrap();
+        assert_eq!(ciphertext, bytes(&v["ciphertext"]));
+        assert_eq!(secret.to_vec(), bytes(&v["shared_secret"]));
+
+        let keys = key_pair(&v);
+        assert_eq!(keys.public(), ek);
+        assert_eq!(keys.decapsulate(&ciphertext), Some(secret));
+    }
+}
+
+#[test]
+fn implicit_rejection() {
+    for v in vectors() {
+        assert_eq!(
+            key_pair(&v)
+                .decapsulate(&bytes(&v["tampered_ciphertext"]))
+                .unwrap()
+                .to_vec(),
+            bytes(&v["rejected_secret"])
+        );
+    }
+}
+
+#[test]
+fn round_trip() {
+    for v in vectors() {
+        let keys = key_pair(&v);
+        let (secret, ciphertext) = mlkem_encapsulate(&keys.public()).unwrap();
+        assert_eq!(keys.decapsulate(&ciphertext), Some(secret));
+    }
+
+    let keys = MlKemKeyPair::new();
+    let (secret, ciphertext) = mlkem_encapsulate(&keys.public()).unwrap();
+    assert_eq!(keys.decapsulate(&ciphertext), Some(secret));
+}
+
+#[test]
+fn malformed_inputs() {
+    let keys = MlKemKeyPair::new();
+    let mut public = keys.public();
+    assert!(mlkem_encapsulate(&public[1..]).is_none());
+
+    // a coefficient of 4095 is not reduced modulo q
+    public[0] = 0xff;
+    public[1] |= 0x0f;
+    assert!(mlkem_encapsulate(&public).is_none());
+
+    assert!(keys.decapsulate(&[0u8; MLKEM_CIPHERTEXT_LEN - 1]).is_none());
+}
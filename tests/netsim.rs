@@ -0,0 +1,247 @@
+//! The relay, scripted clients and the client's connection over simulated links, with time
+//! paused so that every run sees the same faults at the same moments.
+
+mod common;
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use common::{handshake, start_simulated_server, Client};
+use lib_sig::config::ServerOptions;
+use lib_sig::connection::{Connection, Dial, MAX_RECONNECT_DELAY, MIN_RECONNECT_DELAY};
+use lib_sig::crypto::{KdfMode, KeyPair, State};
+use lib_sig::message::{Message, Msg};
+use lib_sig::netsim::{Link, Network, Stream};
+use lib_sig::outbox::{Entry, Outbox, Status};
+use lib_sig::padding::Padding;
+use lib_sig::session::Session;
+use tokio::time::Instant;
+
+// long enough for everything on its way to arrive
+const QUIET: Duration = Duration::from_secs(5);
+
+async fn join(net: &Network, name: &str) -> Client<Stream> {
+    let stream = net.connect(name, "server").await.unwrap();
+    Client::join_over(stream, name, 0, KeyPair::new()).await
+}
+
+// alice and bob joined over clean links and know each other's keys
+async fn start(seed: u64) -> (Network, Client<Stream>, Client<Stream>) {
+    let net = Network::new(seed);
+    let options = ServerOptions {
+        rate_limit: Some(1000),
+        ..ServerOptions::default()
+    };
+    start_simulated_server(&net, "server", options);
+    let mut alice = join(&net, "alice").await;
+    let mut bob = join(&net, "bob").await;
+    alice.lookup("bob").await;
+    bob.expect_key("alice", 0).await;
+    (net, alice, bob)
+}
+
+// the client's connection of `name` to the server
+fn connection(net: &Network, name: &str) -> Connection<impl Dial<Stream = Stream>> {
+    let (net, name) = (net.clone(), name.to_owned());
+    let dial = move || {
+        let (net, name) = (net.clone(), name.clone());
+        async move { net.connect(&name, "server").await }
+    };
+    Connection::new(dial, 64 * 1024)
+}
+
+// reads the server's answers until it goes quiet, acknowledged entries leave the outbox
+async fn acknowledged(conn: &mut Connection<impl Dial>, outbox: &mut Outbox) {
+    while let Ok(Some(Ok(line))) = tokio::time::timeout(QUIET, conn.next()).await {
+        if let Msg::Ack(ack) = serde_json::from_str(&line).unwrap() {
+            conn.answered(Some(ack.id));
+            outbox.remove(ack.id);
+        }
+    }
+}
+
+// every message that arrives decrypts once, copies of it are refused
+async fn receive(client: &mut Client<Stream>) -> Vec<String> {
+    let mut texts = Vec::new();
+    let mut seen = HashSet::new();
+    while let Some(msg) = client.read_within(QUIET).await {
+        if let Msg::EncryptedMessage(msg) = msg {
+            let line = serde_json::to_string(&msg).unwrap();
+            match client.decrypt(&msg) {
+                Ok(text) => {
+                    assert!(seen.insert(line), "{} decrypted a copy", client.name);
+                    texts.push(text);
+                }
+                Err(_) => assert!(seen.contains(&line), "{} refused a message", client.name),
+            }
+        }
+    }
+    texts
+}
+
+#[tokio::test(start_paused = true)]
+async fn sessions_survive_a_lossy_link() {
+    for seed in 0..4 {
+        let (net, mut alice, mut bob) = start(seed).await;
+        let link = Link {
+            latency: Duration::from_millis(30),
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+            reorder_rate: 0.3,
+            reorder_delay: Duration::from_millis(100),
+        };
+        net.set_link("alice", "server", link);
+        net.set_link("bob", "server", link);
+
+        for round in 0..3 {
+            let sent = (0..30)
+                .map(|i| format!("round {} message {}", round, i))
+                .collect::<Vec<_>>();
+            for text in &sent {
+                alice.send_text("bob", 0, text).await;
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let received = receive(&mut bob).await;
+            assert!(received.iter().all(|x| sent.contains(x)));
+            assert!(received.len() > sent.len() / 2, "seed {}", seed);
+
+            // the replies take the ratchet a step further
+            bob.send_text("alice", 0, "reply").await;
+            while receive(&mut alice).await.is_empty() {
+                bob.send_text("alice", 0, "reply").await;
+            }
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn messages_after_a_partition_decrypt() {
+    let (net, mut alice, mut bob) = start(0).await;
+    alice.send_text("bob", 0, "before").await;
+    assert_eq!(bob.expect_text().await.1, "before");
+
+    net.partition("bob", "server");
+    for _ in 0..3 {
+        alice.send_text("bob", 0, "lost").await;
+    }
+    assert!(receive(&mut bob).await.is_empty());
+    net.heal("bob", "server");
+
+    alice.send_text("bob", 0, "after").await;
+    assert_eq!(receive(&mut bob).await, ["after"]);
+    bob.send_text("alice", 0, "reply").await;
+    assert_eq!(alice.expect_text().await.1, "reply");
+}
+
+#[tokio::test(start_paused = true)]
+async fn reconnecting_after_a_disconnect() {
+    let (net, mut alice, mut bob) = start(0).await;
+
+    net.disconnect("bob", "server");
+    bob.expect_closed().await;
+    alice.expect_presence("bob", 0, false).await;
+
+    // the relay queues what is sent meanwhile, bob cannot reach it until the partition heals
+    net.partition("bob", "server");
+    assert!(net.connect("bob", "server").await.is_err());
+    let id = alice.send_text("bob", 0, "queued").await;
+    alice.expect_ack(id).await;
+    net.heal("bob", "server");
+
+    bob.rejoin(net.connect("bob", "server").await.unwrap())
+        .await;
+    alice.expect_presence("bob", 0, true).await;
+    assert_eq!(
+        bob.expect_text().await,
+        ("alice".to_owned(), "queued".to_owned())
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn reconnecting_backs_off_until_registered() {
+    let net = Network::new(0);
+    start_simulated_server(&net, "server", ServerOptions::default());
+    let key = KeyPair::new();
+    let mut conn = connection(&net, "alice");
+    conn.connect(handshake("alice", 0, &key)).await.unwrap();
+
+    // every attempt that fails waits twice as long as the one before, up to the bound
+    net.partition("alice", "server");
+    net.disconnect("alice", "server");
+    assert!(conn.next().await.is_none());
+    conn.disconnected();
+    let mut delays = vec![conn.retry_at() - Instant::now()];
+    for _ in 0..7 {
+        tokio::time::sleep_until(conn.retry_at()).await;
+        assert!(conn.connect(handshake("alice", 0, &key)).await.is_err());
+        conn.disconnected();
+        delays.push(conn.retry_at() - Instant::now());
+    }
+    let secs = delays.iter().map(|x| x.as_secs()).collect::<Vec<_>>();
+    assert_eq!(secs, [1, 2, 4, 8, 16, 32, 60, 60]);
+    assert_eq!(delays[7], MAX_RECONNECT_DELAY);
+
+    // getting through is not enough, the server has to take the registration
+    net.heal("alice", "server");
+    tokio::time::sleep_until(conn.retry_at()).await;
+    conn.connect(handshake("alice", 0, &key)).await.unwrap();
+    net.disconnect("alice", "server");
+    conn.disconnected();
+    assert_eq!(conn.retry_at() - Instant::now(), MAX_RECONNECT_DELAY);
+
+    tokio::time::sleep_until(conn.retry_at()).await;
+    conn.connect(handshake("alice", 0, &key)).await.unwrap();
+    conn.registered();
+    net.disconnect("alice", "server");
+    conn.disconnected();
+    assert_eq!(conn.retry_at() - Instant::now(), MIN_RECONNECT_DELAY);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_outbox_is_sent_again_after_reconnecting() {
+    let net = Network::new(0);
+    start_simulated_server(&net, "server", ServerOptions::default());
+    let mut bob = join(&net, "bob").await;
+    let key = KeyPair::new();
+    bob.trust("alice", 0, key.public());
+    let state = State::new(key.clone(), bob.key.public()).with_kdf(KdfMode::Spec);
+    let mut session = Session::new(state, ("alice", 0), ("bob", 0));
+
+    let mut conn = connection(&net, "alice");
+    conn.connect(handshake("alice", 0, &key)).await.unwrap();
+    let mut outbox = Outbox::new();
+    acknowledged(&mut conn, &mut outbox).await;
+
+    // written while the link drops everything, the connection does not notice
+    net.partition("alice", "server");
+    for text in ["first", "second"] {
+        let msg = Message::new(
+            text.to_owned(),
+            ("alice".to_owned(), 0),
+            ("bob".to_owned(), 0),
+            key.public(),
+        );
+        let request = conn.request(Msg::EncryptedMessage(session.encrypt(&msg).unwrap()));
+        outbox.push(Entry::new(
+            request.id,
+            msg,
+            Padding::None,
+            request.line.clone(),
+        ));
+        conn.send_tracked(&mut outbox, request).await;
+    }
+    assert!(outbox.entries().iter().all(|x| x.status == Status::Sent));
+    assert!(receive(&mut bob).await.is_empty());
+
+    net.disconnect("alice", "server");
+    net.heal("alice", "server");
+    assert!(conn.next().await.is_none());
+    conn.disconnected();
+    tokio::time::sleep_until(conn.retry_at()).await;
+    conn.connect(handshake("alice", 0, &key)).await.unwrap();
+    conn.replay(&mut outbox).await;
+
+    assert_eq!(receive(&mut bob).await, ["first", "second"]);
+    acknowledged(&mut conn, &mut outbox).await;
+    assert!(outbox.is_empty());
+}
@@ -0,0 +1,183 @@
+use lib_sig::crypto::{
+    pqxdh_initiate, pqxdh_respond, x3dh_initiate, KdfMode, KeyPair, MlKemKeyPair, RootKey,
+    SigningKeyPair, State, Suite,
+};
+use lib_sig::message::{Message, PreKeyBundle, ERR_DECRYPT};
+use lib_sig::session::Session;
+
+struct Responder {
+    identity: KeyPair,
+    prekey: KeyPair,
+    pq_prekey: MlKemKeyPair,
+}
+
+impl Responder {
+    fn new() -> Self {
+        Responder {
+            identity: KeyPair::new(),
+            prekey: KeyPair::new(),
+            pq_prekey: MlKemKeyPair::new(),
+        }
+    }
+}
+
+fn session(keys: &KeyPair, other: &KeyPair, root: RootKey, local: &str, remote: &str) -> Session {
+    let state = State::from_root(keys.clone(), other.public(), root)
+        .with_kdf(KdfMode::Spec)
+        .with_suite(Suite::MlKem768Aes256Gcm);
+    Session::new(state, (local, 0), (remote, 0))
+}
+
+fn send(from: &mut Session, text: &str) -> lib_sig::message::EncryptedMessage {
+    let msg = Message::new(
+        text.to_owned(),
+        ("from".to_owned(), 0),
+        ("to".to_owned(), 0),
+        from.state().key_pair().public(),
+    );
+    from.encrypt(&msg).unwrap()
+}
+
+#[test]
+fn both_sides_agree() {
+    let (alice, ephemeral, bob) = (KeyPair::new(), KeyPair::new(), Responder::new());
+
+    let (root, ciphertext) = pqxdh_initiate(
+        &alice,
+        &ephemeral,
+        &bob.identity.public(),
+        &bob.prekey.public(),
+        &bob.pq_prekey.public(),
+    )
+    .unwrap();
+    let bob_root = pqxdh_respond(
+        &bob.identity,
+        &bob.prekey,
+        &bob.pq_prekey,
+        &alice.public(),
+        &ephemeral.public(),
+        &ciphertext,
+    )
+    .unwrap();
+    assert_eq!(root.key(), bob_root.key());
+
+    // the KEM secret changes the result
+    let classical = x3dh_initiate(
+        &alice,
+        &ephemeral,
+        &bob.identity.public(),
+        &bob.prekey.public(),
+    );
+    assert_ne!(root.key(), classical.key());
+}
+
+#[test]
+fn session_round_trip() {
+    let (alice, ephemeral, bob) = (KeyPair::new(), KeyPair::new(), Responder::new());
+
+    let (root, ciphertext) = pqxdh_initiate(
+        &alice,
+        &ephemeral,
+        &bob.identity.public(),
+        &bob.prekey.public(),
+        &bob.pq_prekey.public(),
+    )
+    .unwrap();
+    let bob_root = pqxdh_respond(
+        &bob.identity,
+        &bob.prekey,
+        &bob.pq_prekey,
+        &alice.public(),
+        &ephemeral.public(),
+        &ciphertext,
+    )
+    .unwrap();
+
+    let mut alice_session = session(&alice, &bob.identity, root, "alice", "bob");
+    let mut bob_session = session(&bob.identity, &alice, bob_root, "bob", "alice");
+    for i in 0..3 {
+        let m = send(&mut alice_session, &format!("a{}", i));
+        assert_eq!(m.suite, Suite::MlKem768Aes256Gcm);
+        assert_eq!(bob_session.decrypt(&m).unwrap().msg, format!("a{}", i));
+        let m = send(&mut bob_session, &format!("b{}", i));
+        assert_eq!(alice_session.decrypt(&m).unwrap().msg, format!("b{}", i));
+    }
+}
+
+#[test]
+fn tampered_ciphertext_gives_another_session() {
+    let (alice, ephemeral, bob) = (KeyPair::new(), KeyPair::new(), Responder::new());
+
+    let (root, mut ciphertext) = pqxdh_initiate(
+        &alice,
+        &ephemeral,
+        &bob.identity.public(),
+        &bob.prekey.public(),
+        &bob.pq_prekey.public(),
+    )
+    .unwrap();
+    ciphertext[0] ^= 1;
+    let bob_root = pqxdh_respond(
+        &bob.identity,
+        &bob.prekey,
+        &bob.pq_prekey,
+        &alice.public(),
+        &ephemeral.public(),
+        &ciphertext,
+    )
+    .unwrap();
+    assert_ne!(root.key(), bob_root.key());
+
+    let mut alice_session = session(&alice, &bob.identity, root, "alice", "bob");
+    let mut bob_session = session(&bob.identity, &alice, bob_root, "bob", "alice");
+    let m = send(&mut alice_session, "hi");
+    assert_eq!(bob_session.decrypt(&m).unwrap_err(), ERR_DECRYPT);
+}
+
+#[test]
+fn malformed_keys_are_rejected() {
+    let (alice, ephemeral, bob) = (KeyPair::new(), KeyPair::new(), Responder::new());
+
+    let pq_prekey = bob.pq_prekey.public();
+    assert!(pqxdh_initiate(
+        &alice,
+        &ephemeral,
+        &bob.identity.public(),
+        &bob.prekey.public(),
+        &pq_prekey[..100],
+    )
+    .is_none());
+    assert!(pqxdh_respond(
+        &bob.identity,
+        &bob.prekey,
+        &bob.pq_prekey,
+        &alice.public(),
+        &ephemeral.public(),
+        &[0u8; 100],
+    )
+    .is_none());
+}
+
+#[test]
+fn pq_prekey_is_signed() {
+    let (signing_key, bob) = (SigningKeyPair::new(), Responder::new());
+    let bundle = PreKeyBundle::new(
+        "bob".to_owned(),
+        0,
+        bob.identity.public(),
+        bob.prekey.public(),
+        1,
+    )
+    .with_pq_prekey(bob.pq_prekey.public())
+    .with_suite(Suite::MlKem768Aes256Gcm)
+    .signed(&signing_key);
+    assert!(bundle.verify(&signing_key.public()));
+
+    let mut swapped = bundle.clone();
+    swapped.pq_prekey = Some(MlKemKeyPair::new().public());
+    assert!(!swapped.verify(&signing_key.public()));
+
+    // so is the suite, nobody gets to talk the initiator out of a post-quantum session
+    let downgraded = bundle.with_suite(Suite::Aes256Gcm);
+    assert!(!downgraded.verify(&signing_key.public()));
+}
@@ -0,0 +1,198 @@
+//! Models of the ratchet under random schedules: messages are sent while others are still on
+//! their way, delivered out of order, twice or never, and every one that arrives has to decrypt.
+
+use lib_sig::crypto::{KdfMode, KeyPair, State};
+use lib_sig::message::{EncryptedMessage, Message, ERR_DECRYPT, ERR_DUPLICATE, ERR_SUITE};
+use lib_sig::session::Session;
+use proptest::prelude::*;
+
+// side 0 is alice, side 1 is bob
+#[derive(Clone, Debug)]
+enum Op {
+    Send(usize),
+    // the message at the index, modulo how many are on their way, reaches the other side
+    Deliver(usize, usize),
+    Drop(usize, usize),
+    // a message that was delivered before arrives again
+    Replay(usize),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        3 => (0..2usize).prop_map(Op::Send),
+        3 => (0..2usize, any::<usize>()).prop_map(|(from, i)| Op::Deliver(from, i)),
+        1 => (0..2usize, any::<usize>()).prop_map(|(from, i)| Op::Drop(from, i)),
+        1 => any::<usize>().prop_map(Op::Replay),
+    ]
+}
+
+fn message(text: &str, key: &KeyPair) -> Message {
+    Message::new(
+        text.to_owned(),
+        ("alice".to_owned(), 0),
+        ("bob".to_owned(), 0),
+        key.public(),
+    )
+}
+
+// messages travel as they do on the wire
+fn encrypt(state: &mut State, text: &str) -> String {
+    let (encrypted, next) = message(text, state.key_pair()).encrypt(state).unwrap();
+    *state = next;
+    serde_json::to_string(&encrypted).unwrap()
+}
+
+fn decrypt(state: &mut State, line: &str) -> Result<String, u32> {
+    let encrypted: EncryptedMessage = serde_json::from_str(line).unwrap();
+    let (msg, next) = encrypted.decrypt(state)?;
+    *state = next;
+    Ok(msg.msg)
+}
+
+fn states() -> [State; 2] {
+    let (alice, bob) = (KeyPair::new(), KeyPair::new());
+    [
+        State::new(alice.clone(), bob.public()).with_kdf(KdfMode::Spec),
+        State::new(bob, alice.public()).with_kdf(KdfMode::Spec),
+    ]
+}
+
+fn sessions() -> [Session; 2] {
+    let [alice, bob] = states();
+    [
+        Session::new(alice, ("alice", 0), ("bob", 0)),
+        Session::new(bob, ("bob", 0), ("alice", 0)),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn delivered_messages_decrypt(ops in prop::collection::vec(op(), 0..80)) {
+        let mut states = states();
+        // bare states cannot cope with both sides starting at once, `Session` takes care of that
+        let first = encrypt(&mut states[0], "first");
+        prop_assert_eq!(decrypt(&mut states[1], &first), Ok("first".to_owned()));
+
+        let mut in_flight: [Vec<(String, String)>; 2] = Default::default();
+        let mut delivered = vec![(1, first)];
+        for (n, op) in ops.into_iter().enumerate() {
+            match op {
+                Op::Send(from) => {
+                    let text = format!("message {}", n);
+                    let line = encrypt(&mut states[from], &text);
+                    in_flight[from].push((text, line));
+                }
+                Op::Deliver(from, i) if !in_flight[from].is_empty() => {
+                    let (text, line) = in_flight[from].remove(i % in_flight[from].len());
+                    prop_assert_eq!(decrypt(&mut states[1 - from], &line), Ok(text));
+                    delivered.push((1 - from, line));
+                }
+                Op::Drop(from, i) if !in_flight[from].is_empty() => {
+                    in_flight[from].remove(i % in_flight[from].len());
+                }
+                Op::Replay(i) => {
+                    let (to, line) = &delivered[i % delivered.len()];
+                    prop_assert_eq!(decrypt(&mut states[*to], line), Err(ERR_DUPLICATE));
+                }
+                _ => (),
+            }
+        }
+
+        // whatever is still on its way arrives in the end
+        for from in 0..2 {
+            for (text, line) in std::mem::take(&mut in_flight[from]) {
+                prop_assert_eq!(decrypt(&mut states[1 - from], &line), Ok(text));
+            }
+        }
+    }
+
+    // the relay keeps the order of each direction, but both sides may start at once
+    #[test]
+    fn sessions_started_from_both_sides(ops in prop::collection::vec((0..2usize, any::<bool>()), 0..60)) {
+        let mut sessions = sessions();
+        let mut in_flight: [Vec<(String, EncryptedMessage)>; 2] = Default::default();
+        for (n, (from, send)) in ops.into_iter().enumerate() {
+            if send {
+                let text = format!("message {}", n);
+                let msg = message(&text, sessions[from].state().key_pair());
+                in_flight[from].push((text, sessions[from].encrypt(&msg).unwrap()));
+            } else if !in_flight[from].is_empty() {
+                let (text, msg) = in_flight[from].remove(0);
+                prop_assert_eq!(sessions[1 - from].decrypt(&msg).map(|x| x.msg), Ok(text));
+            }
+        }
+        for from in 0..2 {
+            for (text, msg) in std::mem::take(&mut in_flight[from]) {
+                prop_assert_eq!(sessions[1 - from].decrypt(&msg).map(|x| x.msg), Ok(text));
+            }
+        }
+    }
+
+    // forged headers and ciphertexts are refused and leave the state as it was
+    #[test]
+    fn forged_messages_are_refused(
+        count in any::<u32>(),
+        previous_count in any::<u32>(),
+        new_key in any::<bool>(),
+        encrypted_msg in prop::collection::vec(any::<u8>(), 0..64),
+    ) {
+        let mut states = states();
+        let line = encrypt(&mut states[0], "hi");
+        let mut forged: EncryptedMessage = serde_json::from_str(&line).unwrap();
+        forged.count = count;
+        forged.previous_count = previous_count;
+        forged.encrypted_msg = encrypted_msg;
+        if new_key {
+            forged.public_key = KeyPair::new().public();
+        }
+
+        let result = forged.decrypt(&states[1]).map(|(x, _)| x.msg);
+        prop_assert!(result.is_err());
+        prop_assert_ne!(result, Err(ERR_SUITE));
+        prop_assert_eq!(decrypt(&mut states[1], &line), Ok("hi".to_owned()));
+    }
+}
+
+// the ciphertext is bound to the header it was sent with
+#[test]
+fn headers_are_authenticated() {
+    let mut states = states();
+    let line = encrypt(&mut states[0], "hi");
+    let forgeries: [fn(&mut EncryptedMessage); 6] = [
+        |x| x.sender_name = "mallory".to_owned(),
+        |x| x.sender_device = 1,
+        |x| x.recv_name = "carol".to_owned(),
+        |x| x.recv_device = 1,
+        |x| x.sent_to = Some("carol".to_owned()),
+        |x| x.previous_count = 1,
+    ];
+    for forge in forgeries {
+        let mut forged: EncryptedMessage = serde_json::from_str(&line).unwrap();
+        forge(&mut forged);
+        assert_eq!(
+            forged.decrypt(&states[1]).map(|(x, _)| x.msg),
+            Err(ERR_DECRYPT)
+        );
+    }
+    assert_eq!(decrypt(&mut states[1], &line), Ok("hi".to_owned()));
+}
+
+// a message encrypted again takes a new chain position but keeps its id
+#[test]
+fn ids_survive_encrypting_again() {
+    let mut states = states();
+    let msg = message("hi", states[0].key_pair());
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let (encrypted, next) = msg.encrypt(&states[0]).unwrap();
+        states[0] = next;
+        let (decrypted, next) = encrypted.decrypt(&states[1]).unwrap();
+        states[1] = next;
+        ids.push(decrypted.id);
+    }
+    assert!(ids[0].is_some());
+    assert_eq!(ids[0], ids[1]);
+    assert_ne!(message("hi", states[0].key_pair()).id, ids[0]);
+}
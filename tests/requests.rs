@@ -0,0 +1,52 @@
+use lib_sig::message::{Ack, Device, Msg, Nack, NackReason, Request};
+
+fn round_trip(msg: &Msg) -> Msg {
+    serde_json::from_str(&serde_json::to_string(msg).unwrap()).unwrap()
+}
+
+#[test]
+fn requests_are_unwrapped() {
+    let msg = Msg::Request(Request::new(
+        7,
+        Msg::PreKeyRequest(Device::new("bob".to_owned(), 1)),
+    ));
+    match round_trip(&msg).into_request() {
+        (Some(7), Msg::PreKeyRequest(d)) => assert_eq!((d.user.as_str(), d.device_id), ("bob", 1)),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // messages sent without a request have no id
+    let msg = Msg::LinkDevice(Device::new("bob".to_owned(), 2));
+    assert!(matches!(msg.into_request(), (None, Msg::LinkDevice(_))));
+}
+
+#[test]
+fn responses_keep_their_reason() {
+    match round_trip(&Msg::Ack(Ack::new(3))) {
+        Msg::Ack(ack) => assert_eq!(ack.id, 3),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    for reason in [
+        NackReason::UnknownRecipient,
+        NackReason::RateLimited { retry_after: 2 },
+        NackReason::TooLarge,
+        NackReason::NotRegistered,
+    ] {
+        match round_trip(&Msg::Nack(Nack::new(Some(4), reason))) {
+            Msg::Nack(nack) => {
+                assert_eq!(nack.id, Some(4));
+                assert_eq!(nack.reason, reason);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    // lines the server could not read are refused without an id
+    let json = serde_json::to_string(&Msg::Nack(Nack::new(None, NackReason::TooLarge))).unwrap();
+    assert_eq!(json, r#"{"Nack":{"id":null,"reason":"TooLarge"}}"#);
+    assert_eq!(
+        NackReason::RateLimited { retry_after: 2 }.to_string(),
+        "rate limited, retry after 2s"
+    );
+}
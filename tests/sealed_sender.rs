@@ -0,0 +1,133 @@
+use lib_sig::crypto::{access_key_hash, KdfMode, KeyPair, SigningKeyPair, State};
+use lib_sig::message::{
+    unix_time, EncryptedMessage, Message, SenderCertificate, ERR_CERTIFICATE, ERR_DECRYPT,
+    ERR_MALFORMED,
+};
+use lib_sig::session::Session;
+
+struct Setup {
+    server: SigningKeyPair,
+    alice_keys: KeyPair,
+    bob_keys: KeyPair,
+    alice: Session,
+    bob: Session,
+}
+
+fn setup() -> Setup {
+    let (alice_keys, bob_keys) = (KeyPair::new(), KeyPair::new());
+    let alice = State::new(alice_keys.clone(), bob_keys.public()).with_kdf(KdfMode::Spec);
+    let bob = State::new(bob_keys.clone(), alice_keys.public()).with_kdf(KdfMode::Spec);
+    Setup {
+        server: SigningKeyPair::new(),
+        alice_keys,
+        bob_keys,
+        alice: Session::new(alice, ("alice", 0), ("bob", 0)),
+        bob: Session::new(bob, ("bob", 0), ("alice", 0)),
+    }
+}
+
+fn certificate(s: &Setup, user: &str, expires: u64) -> SenderCertificate {
+    SenderCertificate::new(user.to_owned(), 0, s.alice_keys.public(), expires).signed(&s.server)
+}
+
+fn send(s: &mut Setup, text: &str) -> EncryptedMessage {
+    let mut msg = Message::new(
+        text.to_owned(),
+        ("alice".to_owned(), 0),
+        ("bob".to_owned(), 0),
+        s.alice.state().key_pair().public(),
+    );
+    msg.access_key = Some([7; 32]);
+    s.alice.encrypt(&msg).unwrap()
+}
+
+#[test]
+fn round_trip() {
+    let mut s = setup();
+    let cert = certificate(&s, "alice", unix_time() + 60);
+    let sealed = send(&mut s, "hi").seal(&cert, &s.bob_keys.public(), [7; 32]);
+    assert_eq!(sealed.recv_name, "bob");
+    assert_eq!(sealed.delivery_token, [7; 32]);
+
+    // the sender only shows up once the message is unsealed
+    let json = serde_json::to_string(&sealed).unwrap();
+    assert!(!json.contains("alice"));
+
+    let (cert, msg) = sealed.unseal(&s.bob_keys, &s.server.public()).unwrap();
+    assert_eq!(cert.identity_key, s.alice_keys.public());
+    assert_eq!(msg.sender_name, "alice");
+    let msg = s.bob.decrypt(&msg).unwrap();
+    assert_eq!(msg.msg, "hi");
+    assert_eq!(msg.access_key, Some([7; 32]));
+}
+
+#[test]
+fn only_the_recipient_can_unseal() {
+    let mut s = setup();
+    let cert = certificate(&s, "alice", unix_time() + 60);
+    let sealed = send(&mut s, "hi").seal(&cert, &s.bob_keys.public(), [7; 32]);
+    assert_eq!(
+        sealed
+            .unseal(&KeyPair::new(), &s.server.public())
+            .unwrap_err(),
+        ERR_DECRYPT
+    );
+
+    let mut tampered = sealed;
+    tampered.sealed[0] ^= 1;
+    assert_eq!(
+        tampered
+            .unseal(&s.bob_keys, &s.server.public())
+            .unwrap_err(),
+        ERR_DECRYPT
+    );
+}
+
+#[test]
+fn invalid_certificates_are_rejected() {
+    let mut s = setup();
+
+    let expired = certificate(&s, "alice", unix_time() - 1);
+    let sealed = send(&mut s, "hi").seal(&expired, &s.bob_keys.public(), [7; 32]);
+    assert_eq!(
+        sealed.unseal(&s.bob_keys, &s.server.public()).unwrap_err(),
+        ERR_CERTIFICATE
+    );
+
+    let other_server = SigningKeyPair::new();
+    let forged = SenderCertificate::new("alice".to_owned(), 0, s.alice_keys.public(), u64::MAX)
+        .signed(&other_server);
+    let sealed = send(&mut s, "hi").seal(&forged, &s.bob_keys.public(), [7; 32]);
+    assert_eq!(
+        sealed.unseal(&s.bob_keys, &s.server.public()).unwrap_err(),
+        ERR_CERTIFICATE
+    );
+
+    // a certificate of someone else does not vouch for alice
+    let mallory = certificate(&s, "mallory", unix_time() + 60);
+    let sealed = send(&mut s, "hi").seal(&mallory, &s.bob_keys.public(), [7; 32]);
+    assert_eq!(
+        sealed.unseal(&s.bob_keys, &s.server.public()).unwrap_err(),
+        ERR_CERTIFICATE
+    );
+}
+
+#[test]
+fn redirected_message_is_rejected() {
+    let mut s = setup();
+    let cert = certificate(&s, "alice", unix_time() + 60);
+    let mut sealed = send(&mut s, "hi").seal(&cert, &s.bob_keys.public(), [7; 32]);
+    sealed.recv_name = "carol".to_owned();
+    assert_eq!(
+        sealed.unseal(&s.bob_keys, &s.server.public()).unwrap_err(),
+        ERR_MALFORMED
+    );
+}
+
+#[test]
+fn access_key_hash_hides_the_key() {
+    let hash = access_key_hash(&[7; 32]);
+    assert_ne!(hash, [7; 32]);
+    assert_eq!(hash, access_key_hash(&[7; 32]));
+    assert_ne!(hash, access_key_hash(&[8; 32]));
+}